@@ -0,0 +1,42 @@
+//! Host build of the parts of the driver that do not touch the hardware, so that their unit
+//! tests run without a board. From this directory:
+//!
+//! ```text
+//! cargo test
+//! ```
+//!
+//! The modules are the sources of the driver, included as they are. This crate stands in for
+//! the few items of the driver crate root they use.
+
+#![no_std]
+// Only the tests use the modules
+#![allow(dead_code)]
+// The sources follow the idioms of the driver and its older esp toolchain
+#![allow(clippy::manual_is_multiple_of, clippy::redundant_static_lifetimes)]
+
+#[path = "../../src/sdmmc_sd/device.rs"]
+mod device;
+#[path = "../../src/error.rs"]
+mod error;
+#[path = "../../src/sdmmc/idmac.rs"]
+mod idmac;
+#[path = "../../src/sdmmc_sd/block/layout.rs"]
+mod layout;
+#[path = "../../src/sdmmc_sd/partition.rs"]
+mod partition;
+
+pub use error::Error;
+
+/// Driver module paths the sources import from
+mod sdmmc_sd {
+    pub(crate) mod block {
+        pub(crate) use crate::layout::*;
+    }
+}
+
+#[macro_export]
+macro_rules! bit {
+    ($offset: expr) => {
+        1 << $offset
+    };
+}
@@ -1,12 +1,59 @@
+use embedded_sdmmc::Block;
+
 use crate::common::*;
 use crate::{hw_cmd::SdmmcHwCmd, Error};
 
+/// Caller buffers of a scatter-gather transfer, filled or sent in order as one data phase
+#[derive(Debug)]
+pub enum SgList<'a> {
+    Read(&'a mut [&'a mut [Block]]),
+    Write(&'a [&'a [Block]]),
+}
+
+impl SgList<'_> {
+    /// Bytes of all buffers
+    pub fn len(&self) -> usize {
+        match self {
+            SgList::Read(bufs) => bufs.iter().map(|b| b.len() * Block::LEN).sum(),
+            SgList::Write(bufs) => bufs.iter().map(|b| b.len() * Block::LEN).sum(),
+        }
+    }
+
+    pub const fn is_write(&self) -> bool {
+        matches!(self, SgList::Write(_))
+    }
+}
+
+/// DMA buffer of the card a transfer uses as it is: a write sends what was copied into it before,
+/// a read leaves the data in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Staging {
+    Rx,
+    Tx,
+}
+
+impl Staging {
+    /// The other buffer, to fill one while the DMA works on the other
+    pub const fn other(self) -> Self {
+        match self {
+            Staging::Rx => Staging::Tx,
+            Staging::Tx => Staging::Rx,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SdmmcCmd<'a> {
     pub opcode: u8,
     pub arg: u32,
     pub responce: [u32; 4],
     pub data: Option<&'a mut [u8]>,
+    /// Source of write transfers, staged into the DMA TX buffer
+    pub tx_data: Option<&'a [u8]>,
+    /// Several buffers instead of `data` or `tx_data`
+    pub sg: Option<SgList<'a>>,
+    /// A DMA buffer instead of any caller buffer
+    pub staged: Option<Staging>,
     pub datalen: u32,
     pub buflen: u32,
     pub blklen: u32,
@@ -23,6 +70,9 @@ impl<'a> Default for SdmmcCmd<'a> {
             arg: 0,
             responce: [0u32; 4],
             data: None,
+            tx_data: None,
+            sg: None,
+            staged: None,
             datalen: 0,
             buflen: 0,
             blklen: 0,
@@ -43,28 +93,48 @@ impl<'a> SdmmcCmd<'a> {
         self.flags & flag != 0
     }
 
+    pub const fn has_data(&self) -> bool {
+        self.data.is_some() || self.tx_data.is_some() || self.sg.is_some() || self.staged.is_some()
+    }
+
     pub fn make_hw_cmd(&self) -> SdmmcHwCmd {
-        if self.data.is_some() {
+        if self.has_data() {
             assert!(self.datalen % self.blklen == 0)
         }
+        // HPI is sent while the card is still busy, so it must not wait for the data line
+        let is_hpi = self.opcode == MMC_SEND_STATUS && self.arg & MMC_HPI_ARG != 0;
+        // SDIO abort and suspend requests are function 0 accesses sent while a CMD53 is in flight,
+        // the bus status is polled the same way until the card releases the data lines
+        let io_reg = (self.arg >> SD_ARG_CMD52_REG_SHIFT) & SD_ARG_CMD52_REG_MASK;
+        let is_io_ctl = self.opcode == SD_IO_RW_DIRECT
+            && (self.arg >> SD_ARG_CMD52_FUNC_SHIFT) & SD_ARG_CMD52_FUNC_MASK == 0;
+        let is_io_abort = is_io_ctl
+            && self.arg & SD_ARG_CMD52_WRITE != 0
+            && io_reg == SD_IO_CCCR_CTL
+            && self.arg & CCCR_CTL_AS_MASK as u32 != 0;
+        let is_io_suspend = is_io_ctl && io_reg == SD_IO_CCCR_BUS_SUSPEND;
         SdmmcHwCmd::default()
             .with_cmd_index(self.opcode)
-            .with_stop_abort_cmd(self.opcode == MMC_STOP_TRANSMISSION)
+            .with_stop_abort_cmd(self.opcode == MMC_STOP_TRANSMISSION || is_io_abort)
             .with_send_init(self.opcode == MMC_GO_IDLE_STATE)
             .with_volt_switch(self.opcode == SD_SWITCH_VOLTAGE)
             .with_wait_complete(
                 self.opcode != MMC_STOP_TRANSMISSION
                     && self.opcode != MMC_GO_IDLE_STATE
-                    && self.opcode != SD_SWITCH_VOLTAGE,
+                    && self.opcode != SD_SWITCH_VOLTAGE
+                    && !is_hpi
+                    && !is_io_abort
+                    && !is_io_suspend,
             )
             .with_response_expect(self.has_flag(SCF_RSP_PRESENT))
             .with_response_long(self.has_flag(SCF_RSP_PRESENT) && self.has_flag(SCF_RSP_136))
             .with_check_response_crc(self.has_flag(SCF_RSP_CRC))
-            .with_data_expected(self.data.is_some())
-            .with_rw(self.data.is_some() && self.has_flag(SCF_CMD_READ))
+            .with_data_expected(self.has_data())
+            .with_rw(self.has_data() && !self.has_flag(SCF_CMD_READ))
             .with_send_auto_stop(
-                self.data.is_some()
+                self.has_data()
                     && self.datalen > 0
+                    && !self.has_flag(SCF_PREDEF_COUNT)
                     && (self.opcode == MMC_WRITE_BLOCK_MULTIPLE
                         || self.opcode == MMC_READ_BLOCK_MULTIPLE
                         || self.opcode == MMC_WRITE_DAT_UNTIL_STOP
@@ -45,6 +45,7 @@ pub const MMC_GO_IDLE_STATE: u8 = 0; /* R0 */
 pub const MMC_SEND_OP_COND: u8 = 1; /* R3 */
 pub const MMC_ALL_SEND_CID: u8 = 2; /* R2 */
 pub const MMC_SET_RELATIVE_ADDR: u8 = 3; /* R1 */
+pub const MMC_SLEEP_AWAKE: u8 = 5; /* R1B */
 pub const MMC_SWITCH: u8 = 6; /* R1B */
 pub const MMC_SELECT_CARD: u8 = 7; /* R1 */
 pub const MMC_SEND_EXT_CSD: u8 = 8; /* R1 */
@@ -61,9 +62,18 @@ pub const MMC_WRITE_DAT_UNTIL_STOP: u8 = 20; /* R1 */
 pub const MMC_SET_BLOCK_COUNT: u8 = 23; /* R1 */
 pub const MMC_WRITE_BLOCK_SINGLE: u8 = 24; /* R1 */
 pub const MMC_WRITE_BLOCK_MULTIPLE: u8 = 25; /* R1 */
+pub const MMC_SET_WRITE_PROT: u8 = 28; /* R1B */
+pub const MMC_CLR_WRITE_PROT: u8 = 29; /* R1B */
+pub const MMC_SEND_WRITE_PROT: u8 = 30; /* R1 */
+pub const MMC_SEND_WRITE_PROT_TYPE: u8 = 31; /* R1 */
 pub const MMC_ERASE_GROUP_START: u8 = 35; /* R1 */
 pub const MMC_ERASE_GROUP_END: u8 = 36; /* R1 */
 pub const MMC_ERASE: u8 = 38; /* R1B */
+pub const MMC_QUE_TASK_PARAMS: u8 = 44; /* R1 */
+pub const MMC_QUE_TASK_ADDR: u8 = 45; /* R1 */
+pub const MMC_EXECUTE_READ_TASK: u8 = 46; /* R1 */
+pub const MMC_EXECUTE_WRITE_TASK: u8 = 47; /* R1 */
+pub const MMC_CMDQ_TASK_MGMT: u8 = 48; /* R1B */
 pub const MMC_APP_CMD: u8 = 55; /* R1 */
 
 /* SD commands */
@@ -114,13 +124,144 @@ pub const SCF_RSP_R5B: u32 = SCF_RSP_PRESENT | SCF_RSP_CRC | SCF_RSP_IDX | SCF_R
 pub const SCF_RSP_R6: u32 = SCF_RSP_PRESENT | SCF_RSP_CRC | SCF_RSP_IDX;
 pub const SCF_RSP_R7: u32 = SCF_RSP_PRESENT | SCF_RSP_CRC | SCF_RSP_IDX;
 pub const SCF_WAIT_BUSY: u32 = 0x2000;
+pub const SCF_PREDEF_COUNT: u32 = 0x4000; /*< block count set with CMD23, no auto stop */
 
+pub const MMC_R1_OUT_OF_RANGE: u32 = 1 << 31; /* argument out of range */
+pub const MMC_R1_ADDRESS_ERROR: u32 = 1 << 30; /* misaligned address */
+pub const MMC_R1_BLOCK_LEN_ERROR: u32 = 1 << 29; /* invalid transfer length */
+pub const MMC_R1_WP_VIOLATION: u32 = 1 << 26; /* write to protected block */
+pub const MMC_R1_CARD_ECC_FAILED: u32 = 1 << 21; /* internal ECC failed */
+pub const MMC_R1_ERROR: u32 = 1 << 19; /* generic error */
+pub const MMC_R1_WRITE_ERRORS: u32 = MMC_R1_OUT_OF_RANGE
+    | MMC_R1_ADDRESS_ERROR
+    | MMC_R1_BLOCK_LEN_ERROR
+    | MMC_R1_WP_VIOLATION
+    | MMC_R1_CARD_ECC_FAILED
+    | MMC_R1_ERROR;
 pub const MMC_R1_READY_FOR_DATA: u32 = 1 << 8; /* ready for next transfer */
 pub const MMC_R1_APP_CMD: u32 = 1 << 5; /* app. commands supported */
+pub const MMC_R1_EXCEPTION_EVENT: u32 = 1 << 6; /* eMMC exception event pending */
 pub const MMC_R1_SWITCH_ERROR: u32 = 1 << 7; /* switch command did not succeed */
 pub const MMC_R1_CURRENT_STATE_POS: u32 = 9;
 pub const MMC_R1_CURRENT_STATE_MASK: u32 = 0x1E00; /* card current state */
 pub const MMC_R1_CURRENT_STATE_TRAN: u32 = 4;
+pub const MMC_R1_CURRENT_STATE_PRG: u32 = 7;
+
+pub const fn mmc_r1_current_state(status: u32) -> u32 {
+    (status & MMC_R1_CURRENT_STATE_MASK) >> MMC_R1_CURRENT_STATE_POS
+}
+
+/* SD Status register, ACMD13 */
+pub const SD_SSR_SIZE: usize = 64;
+pub const SD_SSR_AU_SIZE_BYTE: usize = 10; /* AU_SIZE in the upper nibble */
+/// AU_SIZE codes in 512 byte sectors, 0 is undefined
+pub const SD_SSR_AU_SECTORS: [u32; 16] = [
+    0, 32, 64, 128, 256, 512, 1024, 2048, 4096, 8192, 16384, 24576, 32768, 49152, 65536, 131072,
+];
+
+/* CMD23 argument bits */
+pub const MMC_SET_BLOCK_COUNT_MASK: u32 = 0xFFFF;
+pub const MMC_SET_BLOCK_COUNT_RELIABLE: u32 = 1 << 31;
+
+/* CMD5 argument bit selecting sleep rather than awake */
+pub const MMC_SLEEP_AWAKE_SLEEP: u32 = 1 << 15;
+
+/* CMD44 argument bits */
+pub const MMC_CMDQ_RELIABLE_WRITE: u32 = 1 << 31;
+pub const MMC_CMDQ_DATA_DIR_READ: u32 = 1 << 30;
+pub const MMC_CMDQ_PRIORITY: u32 = 1 << 23;
+pub const MMC_CMDQ_TASK_ID_POS: u32 = 16;
+pub const MMC_CMDQ_BLOCK_COUNT_MASK: u32 = 0xFFFF;
+
+/* CMD48 task management op codes */
+pub const MMC_CMDQ_TM_DISCARD_QUEUE: u32 = 1;
+pub const MMC_CMDQ_TM_DISCARD_TASK: u32 = 2;
+
+/* CMD13 argument bit requesting the queue status register instead of the card status */
+pub const MMC_SEND_STATUS_SQS: u32 = 1 << 15;
+
+/* CMD12/CMD13 argument bit requesting a High Priority Interrupt */
+pub const MMC_HPI_ARG: u32 = 1 << 0;
+
+/* MMC_SWITCH access modes */
+pub const MMC_SWITCH_MODE_CMD_SET: u32 = 0x00; /* Change the command set */
+pub const MMC_SWITCH_MODE_SET_BITS: u32 = 0x01; /* Set bits in value */
+pub const MMC_SWITCH_MODE_CLEAR_BITS: u32 = 0x02; /* Clear bits in value */
+pub const MMC_SWITCH_MODE_WRITE_BYTE: u32 = 0x03; /* Set target to value */
+
+pub const EXT_CSD_CMD_SET_NORMAL: u8 = 1 << 0;
+pub const EXT_CSD_CMD_SET_SECURE: u8 = 1 << 1;
+pub const EXT_CSD_CMD_SET_CPSECURE: u8 = 1 << 2;
+
+/* EXT_CSD fields */
+pub const EXT_CSD_MMC_SIZE: usize = 512;
+pub const EXT_CSD_CMDQ_MODE_EN: usize = 15; /* R/W */
+pub const EXT_CSD_POWER_OFF_NOTIFICATION: usize = 34; /* R/W */
+pub const EXT_CSD_ENH_START_ADDR: usize = 136; /* R/W, 4 bytes */
+pub const EXT_CSD_ENH_SIZE_MULT: usize = 140; /* R/W, 3 bytes */
+pub const EXT_CSD_GP_SIZE_MULT: usize = 143; /* R/W, 3 bytes per GP partition */
+pub const EXT_CSD_PARTITION_SETTING_COMPLETED: usize = 155; /* R/W */
+pub const EXT_CSD_PARTITIONS_ATTRIBUTE: usize = 156; /* R/W */
+pub const EXT_CSD_MAX_ENH_SIZE_MULT: usize = 157; /* RO, 3 bytes */
+pub const EXT_CSD_PARTITIONING_SUPPORT: usize = 160; /* RO */
+pub const EXT_CSD_HPI_MGMT: usize = 161; /* R/W */
+pub const EXT_CSD_BKOPS_EN: usize = 163; /* R/W */
+pub const EXT_CSD_BKOPS_START: usize = 164; /* W/E_P */
+pub const EXT_CSD_WR_REL_PARAM: usize = 166; /* RO */
+pub const EXT_CSD_WR_REL_SET: usize = 167; /* R/W */
+pub const EXT_CSD_USER_WP: usize = 171; /* R/W */
+pub const EXT_CSD_BOOT_WP: usize = 173; /* R/W */
+pub const EXT_CSD_ERASE_GROUP_DEF: usize = 175; /* R/W */
+pub const EXT_CSD_REV: usize = 192; /* RO */
+pub const EXT_CSD_OUT_OF_INTERRUPT_TIME: usize = 198; /* RO */
+pub const EXT_CSD_SEC_CNT: usize = 212; /* RO, 4 bytes */
+pub const EXT_CSD_SLEEP_NOTIFICATION_TIME: usize = 216; /* RO */
+pub const EXT_CSD_S_A_TIMEOUT: usize = 217; /* RO */
+pub const EXT_CSD_HC_WP_GRP_SIZE: usize = 221; /* RO */
+pub const EXT_CSD_REL_WR_SEC_C: usize = 222; /* RO */
+pub const EXT_CSD_HC_ERASE_GRP_SIZE: usize = 224; /* RO */
+pub const EXT_CSD_BKOPS_STATUS: usize = 246; /* RO */
+pub const EXT_CSD_POWER_OFF_LONG_TIME: usize = 247; /* RO */
+pub const EXT_CSD_CMDQ_DEPTH: usize = 307; /* RO */
+pub const EXT_CSD_CMDQ_SUPPORT: usize = 308; /* RO */
+pub const EXT_CSD_GENERIC_CMD6_TIME: usize = 248; /* RO */
+pub const EXT_CSD_BKOPS_SUPPORT: usize = 502; /* RO */
+pub const EXT_CSD_HPI_FEATURES: usize = 503; /* RO */
+
+/* EXT_CSD field definitions */
+pub const EXT_CSD_HPI_MGMT_EN: u8 = 1 << 0;
+pub const EXT_CSD_HPI_SUPPORTED: u8 = 1 << 0;
+pub const EXT_CSD_HPI_IMPL_CMD12: u8 = 1 << 1; /* HPI is issued with CMD12 instead of CMD13 */
+pub const EXT_CSD_BKOPS_SUPPORTED: u8 = 1 << 0;
+pub const EXT_CSD_BKOPS_MANUAL_EN: u8 = 1 << 0;
+pub const EXT_CSD_BKOPS_AUTO_EN: u8 = 1 << 1;
+pub const EXT_CSD_BKOPS_START_MANUAL: u8 = 1;
+pub const EXT_CSD_BKOPS_STATUS_MASK: u8 = 0x03;
+pub const EXT_CSD_REV_4_5: u8 = 6;
+pub const EXT_CSD_REV_5_0: u8 = 7;
+pub const EXT_CSD_REV_5_1: u8 = 8;
+pub const EXT_CSD_NO_POWER_NOTIFICATION: u8 = 0;
+pub const EXT_CSD_POWER_ON: u8 = 1;
+pub const EXT_CSD_POWER_OFF_SHORT: u8 = 2;
+pub const EXT_CSD_POWER_OFF_LONG: u8 = 3;
+pub const EXT_CSD_SLEEP_NOTIFICATION: u8 = 4;
+pub const EXT_CSD_CMDQ_SUPPORTED: u8 = 1 << 0;
+pub const EXT_CSD_CMDQ_DEPTH_MASK: u8 = 0x1F;
+pub const EXT_CSD_CMDQ_MODE_ENABLED: u8 = 1 << 0;
+pub const EXT_CSD_WR_REL_PARAM_HS_CTRL_REL: u8 = 1 << 0;
+pub const EXT_CSD_WR_REL_PARAM_EN_REL_WR: u8 = 1 << 2;
+pub const EXT_CSD_USER_WP_US_PWR_WP_EN: u8 = 1 << 0;
+pub const EXT_CSD_USER_WP_US_PERM_WP_EN: u8 = 1 << 2;
+pub const EXT_CSD_USER_WP_US_PWR_WP_DIS: u8 = 1 << 3;
+pub const EXT_CSD_USER_WP_US_PERM_WP_DIS: u8 = 1 << 4;
+pub const EXT_CSD_BOOT_WP_B_PWR_WP_EN: u8 = 1 << 0;
+pub const EXT_CSD_BOOT_WP_B_PERM_WP_EN: u8 = 1 << 2;
+pub const EXT_CSD_ERASE_GROUP_DEF_EN: u8 = 1 << 0;
+pub const EXT_CSD_PARTITION_SETTING_DONE: u8 = 1 << 0;
+pub const EXT_CSD_PARTITIONS_ATTRIBUTE_ENH_USR: u8 = 1 << 0;
+pub const EXT_CSD_PARTITIONS_ATTRIBUTE_ENH_GP1: u8 = 1 << 1; /* ENH_GP2..4 follow */
+pub const EXT_CSD_PARTITIONING_EN: u8 = 1 << 0;
+pub const EXT_CSD_ENH_ATTRIBUTE_EN: u8 = 1 << 1;
 
 pub const MMC_OCR_MEM_READY: u32 = 1 << 31; /* memory power-up status bit */
 pub const MMC_OCR_ACCESS_MODE_MASK: u32 = 0x60000000; /* bits 30:29 */
@@ -148,3 +289,178 @@ pub const SD_OCR_VOL_MASK: u32 = 0xFF8000;
 pub const SD_OCR_SDHC_CAP: u32 = 1 << 30;
 pub const SD_OCR_XPC: u32 = 1 << 28;
 pub const SD_OCR_S18_RA: u32 = 1 << 24;
+
+/// Field `(start, len)` of a response, bit 0 being the LSB of `resp[0]`
+pub const fn mmc_rsp_bits(resp: &[u32; 4], (start, len): (u32, u32)) -> u32 {
+    let raw = (resp[3] as u128) << 96
+        | (resp[2] as u128) << 64
+        | (resp[1] as u128) << 32
+        | resp[0] as u128;
+    ((raw >> start) as u32) & (u32::MAX >> (32 - len))
+}
+
+/* CSD fields, start and length in the R2 response */
+pub const MMC_CSD_CSDVER: (u32, u32) = (126, 2);
+pub const MMC_CSD_READ_BL_LEN: (u32, u32) = (80, 4);
+pub const MMC_CSD_C_SIZE: (u32, u32) = (62, 12);
+pub const MMC_CSD_C_SIZE_MULT: (u32, u32) = (47, 3);
+pub const MMC_CSD_ERASE_GRP_SIZE: (u32, u32) = (42, 5);
+pub const MMC_CSD_ERASE_GRP_MULT: (u32, u32) = (37, 5);
+pub const MMC_CSD_WRITE_BL_LEN: (u32, u32) = (22, 4);
+pub const SD_CSD_V2_C_SIZE: (u32, u32) = (48, 22);
+pub const SD_CSD_CSDVER_1_0: u32 = 0;
+pub const SD_CSD_CSDVER_2_0: u32 = 1;
+pub const SD_CSD_V2_BL_LEN: u32 = 9; /* 512 byte blocks */
+pub const SD_CSD_SECTOR_SIZE: (u32, u32) = (39, 7);
+pub const SD_CSD_WP_GRP_SIZE: (u32, u32) = (32, 7);
+pub const SD_CSD_WP_GRP_ENABLE: (u32, u32) = (31, 1);
+pub const SD_CSD_WRITE_BL_LEN: (u32, u32) = (22, 4);
+
+/* SD IO OCR (R4) */
+pub const SD_IO_OCR_MEM_READY: u32 = 1 << 31; /* all IO functions ready */
+pub const SD_IO_OCR_NUM_FUNCTIONS_POS: u32 = 28;
+pub const SD_IO_OCR_NUM_FUNCTIONS_MASK: u32 = 0x7;
+pub const SD_IO_OCR_MEM_PRESENT: u32 = 1 << 27;
+pub const SD_IO_OCR_MASK: u32 = 0x00FFFFF0;
+
+pub const fn sd_io_ocr_num_functions(ocr: u32) -> u8 {
+    ((ocr >> SD_IO_OCR_NUM_FUNCTIONS_POS) & SD_IO_OCR_NUM_FUNCTIONS_MASK) as u8
+}
+
+/* CMD52 arguments */
+pub const SD_ARG_CMD52_READ: u32 = 0 << 31;
+pub const SD_ARG_CMD52_WRITE: u32 = 1 << 31;
+pub const SD_ARG_CMD52_FUNC_SHIFT: u32 = 28;
+pub const SD_ARG_CMD52_FUNC_MASK: u32 = 0x7;
+pub const SD_ARG_CMD52_EXCHANGE: u32 = 1 << 27; /* read after write */
+pub const SD_ARG_CMD52_REG_SHIFT: u32 = 9;
+pub const SD_ARG_CMD52_REG_MASK: u32 = 0x1FFFF;
+pub const SD_ARG_CMD52_DATA_SHIFT: u32 = 0;
+pub const SD_ARG_CMD52_DATA_MASK: u32 = 0xFF;
+
+/* CMD53 arguments */
+pub const SD_ARG_CMD53_READ: u32 = 0 << 31;
+pub const SD_ARG_CMD53_WRITE: u32 = 1 << 31;
+pub const SD_ARG_CMD53_FUNC_SHIFT: u32 = 28;
+pub const SD_ARG_CMD53_FUNC_MASK: u32 = 0x7;
+pub const SD_ARG_CMD53_BLOCK_MODE: u32 = 1 << 27;
+pub const SD_ARG_CMD53_INCREMENT: u32 = 1 << 26;
+pub const SD_ARG_CMD53_REG_SHIFT: u32 = 9;
+pub const SD_ARG_CMD53_REG_MASK: u32 = 0x1FFFF;
+pub const SD_ARG_CMD53_LENGTH_SHIFT: u32 = 0;
+pub const SD_ARG_CMD53_LENGTH_MASK: u32 = 0x1FF;
+pub const SD_ARG_CMD53_LENGTH_MAX: u32 = 512;
+
+/* R5 response flags */
+pub const SD_R5_COM_CRC_ERROR: u32 = 1 << 15;
+pub const SD_R5_ILLEGAL_COMMAND: u32 = 1 << 14;
+pub const SD_R5_ERROR: u32 = 1 << 11;
+pub const SD_R5_FUNCTION_NUMBER: u32 = 1 << 9;
+pub const SD_R5_OUT_OF_RANGE: u32 = 1 << 8;
+pub const SD_R5_ERROR_MASK: u32 = SD_R5_COM_CRC_ERROR
+    | SD_R5_ILLEGAL_COMMAND
+    | SD_R5_ERROR
+    | SD_R5_FUNCTION_NUMBER
+    | SD_R5_OUT_OF_RANGE;
+
+pub const fn sd_r5_data(resp: u32) -> u8 {
+    (resp & 0xFF) as u8
+}
+
+/* Card Common Control Registers (CCCR) */
+pub const SD_IO_CCCR_START: u32 = 0x00000;
+pub const SD_IO_CCCR_SIZE: u32 = 0x100;
+pub const SD_IO_CCCR_REVISION: u32 = 0x00;
+pub const SD_IO_CCCR_SD_REVISION: u32 = 0x01;
+pub const SD_IO_CCCR_FN_ENABLE: u32 = 0x02;
+pub const SD_IO_CCCR_FN_READY: u32 = 0x03;
+pub const SD_IO_CCCR_INT_ENABLE: u32 = 0x04;
+pub const SD_IO_CCCR_INT_PENDING: u32 = 0x05;
+pub const SD_IO_CCCR_CTL: u32 = 0x06;
+pub const SD_IO_CCCR_BUS_WIDTH: u32 = 0x07;
+pub const SD_IO_CCCR_CARD_CAP: u32 = 0x08;
+pub const SD_IO_CCCR_CISPTR: u32 = 0x09; /* 3 bytes */
+pub const SD_IO_CCCR_BUS_SUSPEND: u32 = 0x0C;
+pub const SD_IO_CCCR_FUNC_SELECT: u32 = 0x0D;
+pub const SD_IO_CCCR_EXEC_FLAGS: u32 = 0x0E;
+pub const SD_IO_CCCR_READY_FLAGS: u32 = 0x0F;
+pub const SD_IO_CCCR_BLKSIZEL: u32 = 0x10;
+pub const SD_IO_CCCR_BLKSIZEH: u32 = 0x11;
+pub const SD_IO_CCCR_POWER_CONTROL: u32 = 0x12;
+pub const SD_IO_CCCR_HIGHSPEED: u32 = 0x13;
+
+pub const CCCR_INT_ENABLE_IENM: u8 = 1 << 0; /* interrupt enable master */
+pub const CCCR_CTL_AS_MASK: u8 = 0x07; /* abort select */
+pub const CCCR_CTL_RES: u8 = 1 << 3; /* IO reset */
+pub const CCCR_CARD_CAP_SDC: u8 = 1 << 0; /* direct command (CMD52) during data transfer */
+pub const CCCR_CARD_CAP_SMB: u8 = 1 << 1; /* multi-block */
+pub const CCCR_CARD_CAP_SRW: u8 = 1 << 2; /* read wait */
+pub const CCCR_CARD_CAP_SBS: u8 = 1 << 3; /* suspend/resume */
+pub const CCCR_CARD_CAP_S4MI: u8 = 1 << 4; /* interrupt between blocks in 4-bit mode */
+pub const CCCR_CARD_CAP_E4MI: u8 = 1 << 5;
+pub const CCCR_CARD_CAP_LSC: u8 = 1 << 6; /* low-speed card */
+pub const CCCR_CARD_CAP_4BLS: u8 = 1 << 7; /* 4-bit support for low-speed card */
+pub const CCCR_HIGHSPEED_SUPPORT: u8 = 1 << 0; /* SHS */
+pub const CCCR_HIGHSPEED_ENABLE: u8 = 1 << 1; /* EHS */
+pub const CCCR_BUS_SUSPEND_BS: u8 = 1 << 0; /* bus status, transfer still in progress */
+pub const CCCR_BUS_SUSPEND_BR: u8 = 1 << 1; /* bus release request */
+pub const CCCR_FUNC_SELECT_FS_MASK: u8 = 0x0F;
+pub const CCCR_FUNC_SELECT_DF: u8 = 1 << 7; /* resumed function has data to transfer */
+pub const CCCR_BUS_WIDTH_MASK: u8 = 0x03;
+pub const CCCR_BUS_WIDTH_1: u8 = 0x00;
+pub const CCCR_BUS_WIDTH_4: u8 = 0x02;
+pub const CCCR_BUS_WIDTH_CD_DISABLE: u8 = 1 << 7; /* disconnect the DAT3 pull-up */
+
+/* Card clock frequencies, kHz */
+pub const SDMMC_FREQ_DEFAULT: u32 = 20000;
+pub const SDMMC_FREQ_HIGHSPEED: u32 = 40000;
+pub const SDMMC_FREQ_PROBING: u32 = 400;
+
+/* Function Basic Registers (FBR) */
+pub const SD_IO_FBR_START: u32 = 0x00100;
+pub const SD_IO_FBR_SIZE: u32 = 0x100;
+pub const SD_IO_FBR_INTERFACE: u32 = 0x00;
+pub const SD_IO_FBR_EXT_INTERFACE: u32 = 0x01;
+pub const SD_IO_FBR_CISPTR: u32 = 0x09; /* 3 bytes */
+pub const SD_IO_FBR_CSAPTR: u32 = 0x0C; /* 3 bytes */
+pub const SD_IO_FBR_BLKSIZEL: u32 = 0x10;
+pub const SD_IO_FBR_BLKSIZEH: u32 = 0x11;
+
+pub const FBR_INTERFACE_CODE_MASK: u8 = 0x0F;
+pub const FBR_INTERFACE_CODE_EXT: u8 = 0x0F; /* code is in the extended register */
+pub const FBR_INTERFACE_CSA_SUPPORT: u8 = 1 << 6;
+
+pub const fn sd_io_fbr(func: u8) -> u32 {
+    func as u32 * SD_IO_FBR_SIZE
+}
+
+pub const SD_IO_CIS_START: u32 = 0x01000;
+pub const SD_IO_CIS_SIZE: u32 = 0x17000 - 0x01000;
+
+/* CIS tuple codes */
+pub const CISTPL_CODE_NULL: u8 = 0x00;
+pub const CISTPL_CODE_DEVICE: u8 = 0x01;
+pub const CISTPL_CODE_CHKSUM: u8 = 0x10;
+pub const CISTPL_CODE_VERS1: u8 = 0x15;
+pub const CISTPL_CODE_ALTSTR: u8 = 0x16;
+pub const CISTPL_CODE_CONFIG: u8 = 0x1A;
+pub const CISTPL_CODE_CFTABLE_ENTRY: u8 = 0x1B;
+pub const CISTPL_CODE_MANFID: u8 = 0x20;
+pub const CISTPL_CODE_FUNCID: u8 = 0x21;
+pub const CISTPL_CODE_FUNCE: u8 = 0x22;
+pub const CISTPL_CODE_VENDER_BEGIN: u8 = 0x80;
+pub const CISTPL_CODE_VENDER_END: u8 = 0x8F;
+pub const CISTPL_CODE_SDIO_STD: u8 = 0x91;
+pub const CISTPL_CODE_SDIO_EXT: u8 = 0x92;
+pub const CISTPL_CODE_END: u8 = 0xFF;
+
+pub const CISTPL_LINK_END: u8 = 0xFF; /* end of the tuple chain */
+
+pub const CISTPL_FUNCID_SDIO: u8 = 0x0C;
+
+/* CISTPL_FUNCE */
+pub const CISTPL_FUNCE_TYPE_FUNC0: u8 = 0x00;
+pub const CISTPL_FUNCE_TYPE_FUNC: u8 = 0x01;
+pub const CISTPL_FUNCE_FUNC0_BLK_SIZE: usize = 1; /* 2 bytes */
+pub const CISTPL_FUNCE_FUNC0_MAX_TRAN_SPEED: usize = 3;
+pub const CISTPL_FUNCE_FUNC_MAX_BLK_SIZE: usize = 12; /* 2 bytes */
@@ -0,0 +1,13 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    InvalidArg,
+    Timeout,
+    NotFound,
+    InvalidCRC,
+    InvalidResponce,
+    InvalidSize,
+    Fail,
+    NotSupported,
+    InvalidState,
+    Interrupted,
+}
@@ -2,29 +2,22 @@
 
 mod cmd;
 mod common;
+mod error;
 mod hw_cmd;
 mod sdmmc;
 pub mod sdmmc_sd;
 
 use embassy_sync::{
-    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, semaphore::FairSemaphore,
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    channel::Channel,
+    semaphore::{FairSemaphore, Semaphore},
 };
-use esp_hal::peripherals::IO_MUX;
+use esp_hal::peripherals::{IO_MUX, SDHOST};
+use log::warn;
 
 use crate::inter::Event;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Error {
-    InvalidArg,
-    Timeout,
-    NotFound,
-    InvalidCRC,
-    InvalidResponce,
-    InvalidSize,
-    Fail,
-    NotSupported,
-    InvalidState,
-}
+pub use error::Error;
 
 //configure pins
 
@@ -59,7 +52,39 @@ macro_rules! configure_pin_iomux {
 
 static EVENT_QUEUE: Channel<CriticalSectionRawMutex, Event, 32> = Channel::new();
 
-static INTR_EVENT: FairSemaphore<CriticalSectionRawMutex, 1> = FairSemaphore::new(0);
+/// SDIO interrupt events, one per slot
+static INTR_EVENT: [FairSemaphore<CriticalSectionRawMutex, 1>; 2] =
+    [FairSemaphore::new(0), FairSemaphore::new(0)];
+
+/// Wait for the card in `slot` to raise an SDIO interrupt.
+///
+/// The ISR masks the slot interrupt when it fires. It is re-armed by the next call, once the
+/// function interrupts have been serviced, since the card keeps DAT1 low until then.
+///
+/// One task waits per slot. A second waiter fails with [`Error::InvalidState`] rather than
+/// returning as if the card had raised an interrupt.
+pub async fn wait_for_sdio_interrupt(slot: Slot) -> Result<(), Error> {
+    let sdio_event = &INTR_EVENT[slot.num() as usize];
+    let mask = common::SDMMC_INTMASK_IO_SLOT0 << slot.num();
+    let sdmmc = unsafe { SDHOST::steal() };
+    critical_section::with(|_| {
+        sdio_event.set(0);
+        sdmmc
+            .register_block()
+            .rintsts()
+            .write(|w| unsafe { w.bits(mask) });
+        sdmmc
+            .register_block()
+            .intmask()
+            .modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+    });
+    let releaser = sdio_event.acquire(1).await.map_err(|_| {
+        warn!("{TAG} another task already waits for the interrupt of {slot:?}");
+        Error::InvalidState
+    })?;
+    releaser.disarm();
+    Ok(())
+}
 
 const APB_CLK_FREQ: u32 = 80 * 1000000;
 // const APB_CLK_FREQ: u32 = 80 * 10000;
@@ -70,7 +95,7 @@ mod inter {
     use esp_hal::{handler, interrupt::InterruptHandler, system::Cpu};
     use log::{info, trace};
 
-    use crate::{EVENT_QUEUE, TAG};
+    use crate::{Slot, EVENT_QUEUE, TAG};
 
     // extern crate instability;
     // #[instability::unstable]
@@ -143,7 +168,11 @@ mod inter {
                 w.sdio_int_mask()
                     .bits(r.sdio_int_mask().bits() & !sdio_pending)
             });
-            super::INTR_EVENT.release(1); // Sephamore release one
+            for slot in [Slot::Slot0, Slot::Slot1] {
+                if sdio_pending & slot.bit() != 0 {
+                    super::INTR_EVENT[slot.num() as usize].release(1);
+                }
+            }
         }
 
         // if task woken yield
@@ -7,16 +7,14 @@ use embassy_sync::{
     semaphore::{FairSemaphore, Semaphore},
 };
 use embassy_time::{Duration, Instant, Timer};
-use esp_hal::{
-    dma::DmaDescriptor,
-    peripherals::{self, IO_MUX, SDHOST},
-};
+use esp_hal::peripherals::{self, IO_MUX, SDHOST};
 use esp_hal::{
     gpio::{Output, OutputConfig},
     peripherals::DPORT,
 };
 use log::{debug, error, info, warn};
 
+pub(crate) mod idmac;
 mod ll;
 
 const TAG: &'static str = "[SDMMC]";
@@ -26,7 +24,7 @@ use crate::{
     hw_cmd::SdmmcHwCmd,
     inter::{self, Event},
     pullup_en_internal,
-    sdmmc::ll::SDMMC_LL_EVENT_DEFAULT,
+    sdmmc::{idmac::IdmacDesc, ll::SDMMC_LL_EVENT_DEFAULT},
     Error, Slot, Width, APB_CLK_FREQ, EVENT_QUEUE, INTR_EVENT,
 };
 
@@ -303,7 +301,9 @@ impl Sdmmc {
         EVENT_QUEUE.clear();
 
         // Reset Semaphore
-        INTR_EVENT.set(0);
+        for sdio_event in &INTR_EVENT {
+            sdio_event.set(0);
+        }
 
         // Attack interrupt handler
         unsafe { inter::bind() };
@@ -379,6 +379,17 @@ impl Sdmmc {
         Ok(())
     }
 
+    /// Hold an SDIO multi-block read between blocks by asserting read wait on DAT2
+    pub fn set_read_wait(&self, en: bool) {
+        self.ll_set_read_wait(en);
+    }
+
+    /// Stop or restart the card clock, e.g. around eMMC sleep
+    pub async fn gate_card_clk(&mut self, slot: Slot, gated: bool) -> Result<(), Error> {
+        self.ll_enable_card_clk(slot, !gated);
+        self.clk_update_cmd(slot, false).await
+    }
+
     // look here
     pub async fn set_clk_always_on(&mut self, slot: Slot, en: bool) {
         self.ll_enable_card_clk_low_power(slot, en);
@@ -420,32 +431,17 @@ impl Sdmmc {
         });
     }
 
-    pub fn dma_prepare(&self, desc: *mut DmaDescriptor, block_size: u16, data_size: u32) {
-        let block = self.host.register_block();
-
-        block
-            .bytcnt()
-            .write(|w| unsafe { w.byte_count().bits(data_size) });
-        block
-            .blksiz()
-            .write(|w| unsafe { w.block_size().bits(block_size) });
-        block
-            .dbaddr()
-            .write(|w| unsafe { w.dbaddr().bits(desc.addr() as u32) });
-
-        // Other
-        block.bmod().write(|w| {
-            w.de().set_bit(); // Double check bit
-            w.fb().set_bit()
-        });
+    /// Start the IDMAC on the descriptor ring at `desc` of a `data_size` byte transfer
+    pub fn dma_prepare(&self, desc: *mut IdmacDesc, block_size: u16, data_size: u32) {
+        self.ll_set_data_transfer_len(data_size);
+        self.ll_set_block_size(block_size as u32);
+        self.ll_set_desc_addr(desc);
+        self.enable_dma(true);
         self.dma_resume();
     }
 
     pub fn dma_resume(&self) {
-        self.host
-            .register_block()
-            .pldmnd()
-            .write(|w| unsafe { w.pd().bits(1) });
+        self.ll_poll_demand();
     }
 
     pub fn cmd_taken(&self) -> bool {
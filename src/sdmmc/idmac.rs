@@ -0,0 +1,431 @@
+//! Descriptors of the SDHOST internal DMA controller (IDMAC).
+//!
+//! The IDMAC is the DesignWare one and does not understand the GDMA descriptors of esp-hal. It
+//! walks a chain of its own 4 word descriptors, each moving up to [`IDMAC_MAX_BUF_LEN`] bytes of
+//! one buffer. [`IdmacRing`] links `N` such descriptors into a ring over one or more buffers and
+//! refills them as the IDMAC hands them back, the way `fill_dma_descriptors` of esp-idf does, so
+//! a transfer is not limited to `N` descriptors worth of data.
+
+use core::{
+    ops::Range,
+    ptr,
+    sync::atomic::{compiler_fence, Ordering},
+};
+
+use crate::{bit, Error};
+
+/// Largest buffer one descriptor moves, `SDMMC_DMA_MAX_BUF_LEN` of esp-idf
+pub(crate) const IDMAC_MAX_BUF_LEN: usize = 4096;
+
+/// Disable interrupt on completion, no RI/TI once this descriptor is done
+const DES0_DIC: u32 = bit!(1);
+/// Last descriptor of the transfer
+const DES0_LD: u32 = bit!(2);
+/// First descriptor of the transfer
+const DES0_FS: u32 = bit!(3);
+/// `next` holds the address of the next descriptor rather than a second buffer
+const DES0_CH: u32 = bit!(4);
+/// Card error summary, set by the IDMAC when the transfer of this descriptor failed
+const DES0_CES: u32 = bit!(30);
+/// The descriptor belongs to the IDMAC, cleared by it once the buffer is done
+const DES0_OWN: u32 = bit!(31);
+
+const DES1_BS1_MASK: u32 = 0x1fff;
+
+/// One IDMAC descriptor, `sdmmc_desc_t` of esp-idf
+#[repr(C, align(4))]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct IdmacDesc {
+    des0: u32,
+    /// Buffer 1 size in the low 13 bits, buffer 2 is unused in chained mode
+    des1: u32,
+    buffer: *mut u8,
+    next: *mut IdmacDesc,
+}
+
+impl IdmacDesc {
+    pub(crate) const EMPTY: Self = Self {
+        des0: 0,
+        des1: 0,
+        buffer: ptr::null_mut(),
+        next: ptr::null_mut(),
+    };
+
+    /// `des0` as last written, by the IDMAC possibly
+    fn status(&self) -> u32 {
+        // SAFETY: reference to an initialized field
+        unsafe { ptr::read_volatile(&self.des0) }
+    }
+
+    pub(crate) fn owned_by_dma(&self) -> bool {
+        self.status() & DES0_OWN != 0
+    }
+
+    pub(crate) fn has_error(&self) -> bool {
+        self.status() & DES0_CES != 0
+    }
+}
+
+/// `(offset, size)` of the descriptors moving `len` bytes of one buffer
+pub(crate) fn split(len: usize) -> impl Iterator<Item = (usize, usize)> {
+    (0..len)
+        .step_by(IDMAC_MAX_BUF_LEN)
+        .map(move |offset| (offset, (len - offset).min(IDMAC_MAX_BUF_LEN)))
+}
+
+/// `des0` handing a descriptor to the IDMAC, raising RI/TI on completion if `interrupt`
+pub(crate) const fn des0(first: bool, last: bool, interrupt: bool) -> u32 {
+    let mut des0 = DES0_OWN | DES0_CH;
+    if first {
+        des0 |= DES0_FS;
+    }
+    if last {
+        des0 |= DES0_LD;
+    }
+    if !interrupt {
+        des0 |= DES0_DIC;
+    }
+    des0
+}
+
+/// `des1` of a descriptor moving `size` bytes, the IDMAC moves whole words
+pub(crate) const fn des1(size: usize) -> u32 {
+    (size.next_multiple_of(4) as u32) & DES1_BS1_MASK
+}
+
+/// Point each descriptor at the following one and the last one back at the first
+pub(crate) fn link_ring(descs: &mut [IdmacDesc]) {
+    let head = descs.as_mut_ptr();
+    let len = descs.len();
+    for (i, desc) in descs.iter_mut().enumerate() {
+        desc.next = head.wrapping_add((i + 1) % len);
+    }
+}
+
+/// Ring of `N` descriptors over up to `N` buffers, see the module documentation.
+///
+/// The descriptors point at each other, so the ring must stay in place between
+/// [`Self::start`] and the end of the transfer.
+pub(crate) struct IdmacRing<const N: usize> {
+    descs: [IdmacDesc; N],
+    /// `(buf, len)` of the buffers of the transfer, in order
+    segments: [(*mut u8, usize); N],
+    num_segments: usize,
+    /// Segment and offset in it of the first byte not handed to the IDMAC yet
+    cursor: (usize, usize),
+    /// Next descriptor to fill
+    head: usize,
+    /// Oldest descriptor handed to the IDMAC and not taken back
+    tail: usize,
+    in_flight: usize,
+    /// Descriptors filled since [`Self::start`], the first one is marked FS
+    filled: usize,
+    /// The last buffer ended off a word boundary, nothing can follow it
+    sealed: bool,
+}
+
+impl<const N: usize> IdmacRing<N> {
+    pub(crate) const fn new() -> Self {
+        const { assert!(N > 0, "IDMAC ring needs at least one descriptor") };
+        Self {
+            descs: [IdmacDesc::EMPTY; N],
+            segments: [(ptr::null_mut(), 0); N],
+            num_segments: 0,
+            cursor: (0, 0),
+            head: 0,
+            tail: 0,
+            in_flight: 0,
+            filled: 0,
+            sealed: false,
+        }
+    }
+
+    /// Forget the buffers and descriptors of the previous transfer
+    pub(crate) fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.num_segments == 0
+    }
+
+    pub(crate) fn descriptors(&self) -> &[IdmacDesc] {
+        &self.descs
+    }
+
+    /// Address range of the descriptors, which the IDMAC must be able to reach as well
+    pub(crate) fn as_ptr_range(&self) -> Range<*const IdmacDesc> {
+        self.descs.as_ptr_range()
+    }
+
+    /// Append `len` bytes at `buf` to the transfer.
+    ///
+    /// `buf` must be word aligned. Only the last buffer of a transfer may have a length that is
+    /// not a multiple of 4, the IDMAC then moves the whole last word.
+    pub(crate) fn push(&mut self, buf: *mut u8, len: usize) -> Result<(), Error> {
+        if buf as usize % 4 != 0 || self.sealed {
+            Err(Error::InvalidArg)?;
+        }
+        if len == 0 || self.num_segments == N {
+            Err(Error::InvalidSize)?;
+        }
+        self.segments[self.num_segments] = (buf, len);
+        self.num_segments += 1;
+        self.sealed = len % 4 != 0;
+        Ok(())
+    }
+
+    /// Link the ring, hand the first descriptors to the IDMAC and return the head for `DBADDR`,
+    /// `None` without buffers
+    pub(crate) fn start(&mut self) -> Option<*mut IdmacDesc> {
+        if self.is_empty() {
+            return None;
+        }
+        link_ring(&mut self.descs);
+        self.fill();
+        Some(self.descs.as_mut_ptr())
+    }
+
+    /// Take back the descriptors the IDMAC is done with and hand them out again with the rest
+    /// of the transfer. Returns whether the whole transfer is done.
+    ///
+    /// The IDMAC may have suspended on a descriptor it did not own, it must be resumed after.
+    pub(crate) fn refill(&mut self) -> bool {
+        while self.in_flight > 0 && !self.descs[self.tail].owned_by_dma() {
+            self.tail = (self.tail + 1) % N;
+            self.in_flight -= 1;
+        }
+        self.fill();
+        self.pending_descs() == 0 && self.in_flight == 0
+    }
+
+    /// Descriptors the data not handed to the IDMAC yet needs
+    fn pending_descs(&self) -> usize {
+        let (segment, offset) = self.cursor;
+        self.segments[..self.num_segments]
+            .iter()
+            .skip(segment)
+            .enumerate()
+            .map(|(i, &(_, len))| {
+                let done = if i == 0 { offset } else { 0 };
+                split(len - done).count()
+            })
+            .sum()
+    }
+
+    fn fill(&mut self) {
+        // while data waits for a free descriptor, each completion must come back for a refill
+        let streaming = self.pending_descs() > N - self.in_flight;
+        while self.in_flight < N && self.cursor.0 < self.num_segments {
+            let (segment, offset) = self.cursor;
+            let (buf, len) = self.segments[segment];
+            let size = (len - offset).min(IDMAC_MAX_BUF_LEN);
+            self.cursor = if offset + size == len {
+                (segment + 1, 0)
+            } else {
+                (segment, offset + size)
+            };
+            let last = self.cursor.0 == self.num_segments;
+
+            let desc = &mut self.descs[self.head];
+            desc.des1 = des1(size);
+            desc.buffer = buf.wrapping_add(offset);
+            // the IDMAC may be reading the ring, it must see the buffer before the OWN bit
+            compiler_fence(Ordering::Release);
+            // SAFETY: reference to an initialized field
+            unsafe {
+                ptr::write_volatile(
+                    &mut desc.des0,
+                    des0(self.filled == 0, last, last || streaming),
+                )
+            };
+            self.head = (self.head + 1) % N;
+            self.in_flight += 1;
+            self.filled += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    const N: usize = 4;
+
+    /// Word aligned address, never dereferenced
+    const BUF: *mut u8 = 0x3FFB_0000 as *mut u8;
+
+    fn sizes(len: usize) -> Vec<usize> {
+        split(len).map(|(_, size)| size).collect()
+    }
+
+    /// Start `ring` where it is, the descriptors point at each other
+    fn start(ring: &mut IdmacRing<N>, segments: &[(*mut u8, usize)]) {
+        for &(buf, len) in segments {
+            ring.push(buf, len).unwrap();
+        }
+        assert_eq!(ring.start(), Some(ring.descs.as_mut_ptr()));
+    }
+
+    /// The IDMAC is done with the `count` oldest descriptors
+    fn complete(ring: &mut IdmacRing<N>, count: usize) {
+        for i in 0..count {
+            ring.descs[(ring.tail + i) % N].des0 &= !DES0_OWN;
+        }
+    }
+
+    fn flags(desc: &IdmacDesc) -> u32 {
+        desc.des0 & (DES0_OWN | DES0_CH | DES0_FS | DES0_LD | DES0_DIC)
+    }
+
+    #[test]
+    fn split_boundaries() {
+        assert!(sizes(0).is_empty());
+        assert_eq!(sizes(1), [1]);
+        assert_eq!(sizes(4096), [4096]);
+        assert_eq!(sizes(4097), [4096, 1]);
+        assert_eq!(sizes(N * 4096), [4096; N]);
+        assert_eq!(sizes(N * 4096 + 1), [4096, 4096, 4096, 4096, 1]);
+        let offsets: Vec<_> = split(3 * 4096 + 8).map(|(offset, _)| offset).collect();
+        assert_eq!(offsets, [0, 4096, 8192, 12288]);
+    }
+
+    #[test]
+    fn des1_rounds_up_to_words() {
+        assert_eq!(des1(1), 4);
+        assert_eq!(des1(4), 4);
+        assert_eq!(des1(510), 512);
+        assert_eq!(des1(4096), 4096);
+    }
+
+    #[test]
+    fn des0_flags() {
+        assert_eq!(
+            des0(true, true, true),
+            DES0_OWN | DES0_CH | DES0_FS | DES0_LD
+        );
+        assert_eq!(des0(false, false, false), DES0_OWN | DES0_CH | DES0_DIC);
+        assert_eq!(des0(true, false, true), DES0_OWN | DES0_CH | DES0_FS);
+    }
+
+    #[test]
+    fn ring_is_linked() {
+        let mut descs = [IdmacDesc::EMPTY; N];
+        link_ring(&mut descs);
+        let head = descs.as_mut_ptr();
+        for (i, desc) in descs.iter().enumerate() {
+            assert_eq!(desc.next, head.wrapping_add((i + 1) % N));
+        }
+    }
+
+    #[test]
+    fn single_byte() {
+        let ring = &mut IdmacRing::new();
+        start(ring, &[(BUF, 1)]);
+        assert_eq!(ring.in_flight, 1);
+        let desc = &ring.descs[0];
+        assert_eq!(flags(desc), DES0_OWN | DES0_CH | DES0_FS | DES0_LD);
+        assert_eq!((desc.buffer, desc.des1), (BUF, 4));
+    }
+
+    #[test]
+    fn one_full_descriptor() {
+        let ring = &mut IdmacRing::new();
+        start(ring, &[(BUF, 4096)]);
+        assert_eq!(ring.in_flight, 1);
+        assert_eq!(ring.descs[0].des1, 4096);
+        assert_eq!(
+            flags(&ring.descs[0]),
+            DES0_OWN | DES0_CH | DES0_FS | DES0_LD
+        );
+    }
+
+    #[test]
+    fn just_over_one_descriptor() {
+        let ring = &mut IdmacRing::new();
+        start(ring, &[(BUF, 4097)]);
+        assert_eq!(ring.in_flight, 2);
+        assert_eq!(
+            flags(&ring.descs[0]),
+            DES0_OWN | DES0_CH | DES0_FS | DES0_DIC
+        );
+        assert_eq!(flags(&ring.descs[1]), DES0_OWN | DES0_CH | DES0_LD);
+        assert_eq!(ring.descs[1].buffer, BUF.wrapping_add(4096));
+        assert_eq!(ring.descs[1].des1, 4);
+        assert_eq!(ring.descs[0].next, &ring.descs[1] as *const _ as *mut _);
+    }
+
+    #[test]
+    fn ring_full() {
+        let ring = &mut IdmacRing::new();
+        start(ring, &[(BUF, N * 4096)]);
+        assert_eq!(ring.in_flight, N);
+        for (i, desc) in ring.descs.iter().enumerate() {
+            assert_eq!(desc.buffer, BUF.wrapping_add(i * 4096));
+            assert_eq!(desc.des0 & DES0_FS != 0, i == 0);
+            assert_eq!(desc.des0 & DES0_LD != 0, i == N - 1);
+            assert_eq!(desc.des0 & DES0_DIC != 0, i != N - 1);
+        }
+        assert!(!ring.refill());
+        complete(ring, N);
+        assert!(ring.refill());
+    }
+
+    #[test]
+    fn ring_wraps_around() {
+        let ring = &mut IdmacRing::new();
+        start(ring, &[(BUF, N * 4096 + 1)]);
+        // more data than descriptors, every completion comes back for a refill
+        assert_eq!(ring.in_flight, N);
+        assert!(ring
+            .descs
+            .iter()
+            .all(|d| d.des0 & (DES0_LD | DES0_DIC) == 0));
+        assert!(!ring.refill());
+        assert_eq!(ring.in_flight, N);
+
+        complete(ring, 1);
+        assert!(!ring.refill());
+        assert_eq!(ring.in_flight, N);
+        let desc = &ring.descs[0];
+        assert_eq!(flags(desc), DES0_OWN | DES0_CH | DES0_LD);
+        assert_eq!((desc.buffer, desc.des1), (BUF.wrapping_add(N * 4096), 4));
+
+        complete(ring, N);
+        assert!(ring.refill());
+    }
+
+    #[test]
+    fn several_buffers() {
+        let second = BUF.wrapping_add(0x10000);
+        let ring = &mut IdmacRing::new();
+        start(ring, &[(BUF, 8), (second, 4097)]);
+        assert_eq!(ring.in_flight, 3);
+        assert_eq!((ring.descs[0].buffer, ring.descs[0].des1), (BUF, 8));
+        assert_eq!((ring.descs[1].buffer, ring.descs[1].des1), (second, 4096));
+        assert_eq!(ring.descs[2].buffer, second.wrapping_add(4096));
+        assert_eq!(flags(&ring.descs[0]) & DES0_FS, DES0_FS);
+        assert_eq!(flags(&ring.descs[2]) & DES0_LD, DES0_LD);
+    }
+
+    #[test]
+    fn capacity_errors() {
+        let mut ring = IdmacRing::<N>::new();
+        assert_eq!(ring.start(), None);
+        assert_eq!(ring.push(BUF.wrapping_add(2), 4), Err(Error::InvalidArg));
+        assert_eq!(ring.push(BUF, 0), Err(Error::InvalidSize));
+        for i in 0..N {
+            ring.push(BUF.wrapping_add(i * 4096), 4096).unwrap();
+        }
+        assert_eq!(ring.push(BUF, 4), Err(Error::InvalidSize));
+
+        ring.clear();
+        assert!(ring.is_empty());
+        ring.push(BUF, 6).unwrap();
+        assert_eq!(ring.push(BUF.wrapping_add(8), 4), Err(Error::InvalidArg));
+    }
+}
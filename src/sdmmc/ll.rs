@@ -1,6 +1,11 @@
-use esp_hal::{dma::DmaDescriptor, peripherals::DPORT};
+use esp_hal::peripherals::DPORT;
 
-use crate::{bit, hw_cmd::SdmmcHwCmd, sdmmc::Sdmmc, Slot, Width};
+use crate::{
+    bit,
+    hw_cmd::SdmmcHwCmd,
+    sdmmc::{idmac::IdmacDesc, Sdmmc},
+    Slot, Width,
+};
 
 pub(crate) const SDMMC_LL_EVENT_IO_SLOT1: u32 = 1 << 17;
 pub(crate) const SDMMC_LL_EVENT_IO_SLOT0: u32 = 1 << 16;
@@ -22,6 +27,8 @@ pub(crate) const SDMMC_LL_EVENT_CMD_DONE: u32 = 1 << 2;
 pub(crate) const SDMMC_LL_EVENT_RESP_ERR: u32 = 1 << 1;
 pub(crate) const SDMMC_LL_EVENT_CD: u32 = 1 << 0;
 
+pub(crate) const SDMMC_LL_CTRL_READ_WAIT: u32 = 1 << 6;
+
 // Default enabled interrupts (sdio is enabled only when use):
 pub(crate) const SDMMC_LL_EVENT_DEFAULT: u32 = SDMMC_LL_EVENT_CD
     | SDMMC_LL_EVENT_RESP_ERR
@@ -224,6 +231,17 @@ impl Sdmmc {
             .bit_is_clear()
     }
 
+    /// Assert read wait on DAT2 (SDIO)
+    pub(crate) fn ll_set_read_wait(&self, en: bool) {
+        self.host.register_block().ctrl().modify(|r, w| unsafe {
+            w.bits(if en {
+                r.bits() | SDMMC_LL_CTRL_READ_WAIT
+            } else {
+                r.bits() & !SDMMC_LL_CTRL_READ_WAIT
+            })
+        });
+    }
+
     pub(crate) fn ll_set_data_timeout(&self, timeout_cycles: u32) {
         self.host
             .register_block()
@@ -269,19 +287,33 @@ impl Sdmmc {
     }
 
     pub(crate) fn ll_set_data_transfer_len(&self, len: u32) {
-        todo!()
+        self.host
+            .register_block()
+            .bytcnt()
+            .write(|w| unsafe { w.byte_count().bits(len) });
     }
 
     pub(crate) fn ll_set_block_size(&self, block_size: u32) {
-        todo!()
+        self.host
+            .register_block()
+            .blksiz()
+            .write(|w| unsafe { w.block_size().bits(block_size as u16) });
     }
 
-    pub(crate) fn ll_set_desc_addr(&self, desc: *mut DmaDescriptor) {
-        todo!()
+    /// Head of the IDMAC descriptor chain of the next transfer
+    pub(crate) fn ll_set_desc_addr(&self, desc: *mut IdmacDesc) {
+        self.host
+            .register_block()
+            .dbaddr()
+            .write(|w| unsafe { w.dbaddr().bits(desc as u32) });
     }
 
+    /// Make a suspended IDMAC fetch the descriptor at `DBADDR` again
     pub(crate) fn ll_poll_demand(&self) {
-        todo!()
+        self.host
+            .register_block()
+            .pldmnd()
+            .write(|w| unsafe { w.pd().bits(1) });
     }
 
     pub(crate) fn ll_set_cmd(&self, cmd: SdmmcHwCmd) {
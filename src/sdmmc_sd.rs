@@ -1,25 +1,61 @@
-use embassy_futures::yield_now;
-use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
-use embassy_time::{block_for, Duration, WithTimeout};
-use embedded_sdmmc::BlockDevice;
+use core::{cell::RefCell, iter};
+use embassy_futures::{
+    select::{select, Either},
+    yield_now,
+};
+
+use embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex};
+use embassy_time::{block_for, Duration, Instant, WithTimeout};
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
 use esp_hal::{
-    dma::{DmaDescriptor, DmaRxBuf, DmaRxBuffer, DmaTxBuf},
+    dma::{DmaRxBuf, DmaTxBuf},
     peripherals::SDHOST,
 };
 use log::{debug, info, warn};
-use sdio_host::{common_cmd::Resp, sd::SD, Cmd};
+use sdio_host::{common_cmd::Resp, Cmd};
 
+pub mod block;
+pub mod cache;
 pub mod cmd;
+pub mod cmdq;
+pub mod combo;
 pub mod common;
+mod device;
 pub mod init;
 pub mod io;
+pub mod mmc;
+pub mod partition;
+pub mod readahead;
+pub mod sdio_driver;
+pub mod storage;
 
 use crate::{
-    bit, cmd::SdmmcCmd, common::*, common::*, inter::Event, sdmmc::Sdmmc, Error, Slot, Width,
-    EVENT_QUEUE,
+    bit,
+    cmd::{SdmmcCmd, SgList, Staging},
+    common::*,
+    inter::Event,
+    sdmmc::{
+        idmac::{IdmacDesc, IdmacRing},
+        Sdmmc,
+    },
+    Error, Slot, Width, EVENT_QUEUE,
 };
 const TAG: &'static str = "[SDMMC_CARD]";
 
+pub(crate) use device::{blocks_as_bytes, blocks_as_bytes_mut};
+
+/// IDMAC descriptors of one transfer, enough for 64KB
+const DMA_DESCRIPTORS: usize = 16;
+/// Internal RAM the SDHOST DMA can reach, as `SOC_DMA_LOW` and `SOC_DMA_HIGH` of esp-idf
+const SOC_DMA_LOW: usize = 0x3FFA_E000;
+const SOC_DMA_HIGH: usize = 0x4000_0000;
+
+fn is_dma_capable(ptr: *const u8, len: usize) -> bool {
+    let start = ptr as usize;
+    let end = start.saturating_add(len);
+    start >= SOC_DMA_LOW && end <= SOC_DMA_HIGH
+}
+
 pub struct TransState {
     ptr: *mut u8,
     size_remaining: usize,
@@ -30,6 +66,33 @@ pub struct TransState {
 struct CSD {
     pub(crate) sector_size: u32,
     pub(crate) capacity: u32,
+    /// SD write protection group in sectors, 0 if the card has none
+    pub(crate) wp_grp_sectors: u32,
+    /// eMMC erase group in sectors while high capacity erase groups are off, 0 for SD cards
+    pub(crate) erase_grp_sectors: u32,
+}
+
+#[derive(Default)]
+pub(crate) struct ExtCsd {
+    pub(crate) rev: u8,
+    pub(crate) hpi_features: u8,
+    pub(crate) hpi_enabled: bool,
+    pub(crate) bkops_support: bool,
+    pub(crate) bkops_en: u8,
+    pub(crate) out_of_interrupt_time_ms: u32,
+    pub(crate) generic_cmd6_time_ms: u32,
+    pub(crate) wr_rel_param: u8,
+    pub(crate) rel_wr_sec_c: u8,
+    pub(crate) erase_group_def: u8,
+    pub(crate) hc_wp_grp_size: u8,
+    pub(crate) hc_erase_grp_size: u8,
+    pub(crate) power_off_notification: u8,
+    pub(crate) power_off_long_time_ms: u32,
+    pub(crate) sleep_notification_time_ms: u32,
+    pub(crate) s_a_timeout_ms: u32,
+    pub(crate) cmdq_support: bool,
+    pub(crate) cmdq_depth: u8,
+    pub(crate) cmdq_enabled: bool,
 }
 
 pub struct SdmmcCard {
@@ -40,45 +103,70 @@ pub struct SdmmcCard {
     freq_khz: u32, // default is 400
     dma_rx_buf: DmaRxBuf,
     dma_tx_buf: DmaTxBuf,
-    rsa: u32,
+    /// Descriptors of the running transfer, over the caller buffers or the DMA buffers
+    idmac: IdmacRing<DMA_DESCRIPTORS>,
+    /// The running transfer uses the caller buffer rather than the DMA buffers
+    zero_copy: bool,
+    /// The running CMD53 was interrupted by [`io::request_io_suspend`] rather than an abort
+    io_suspending: bool,
+    /// A read was started with [`Self::start_read_sectors`] and not finished yet
+    read_in_flight: bool,
     pub(crate) is_mmc: bool,
+    pub(crate) is_mem: bool,
+    pub(crate) is_sdio: bool,
     ocr: u32,
     pub(crate) raw_cid: [u32; 4],
     pub(crate) rca: u16,
     pub(crate) csd: CSD, // look at later
+    pub(crate) ext_csd: ExtCsd,
+    pub(crate) reliable_write: bool,
+    pub(crate) power_state: mmc::PowerState,
+    pub(crate) sdio: io::SdioCardInfo,
+    pub(crate) cis_handlers: [Option<io::CisTup>; io::SDIO_MAX_CIS_HANDLERS],
 }
 
-pub struct SdmmcDevice(Mutex<CriticalSectionRawMutex, SdmmcCard>);
+/// Blocking [`BlockDevice`] over an initialized card, for use with
+/// [`embedded_sdmmc::VolumeManager`].
+///
+/// Each call drives the transfer to completion with [`embassy_futures::block_on`]. The card is
+/// not behind a critical section since the transfer itself waits for the SDHOST interrupt.
+pub struct SdmmcDevice(Mutex<NoopRawMutex, RefCell<SdmmcCard>>);
+
+impl SdmmcDevice {
+    pub fn new(card: SdmmcCard) -> Self {
+        Self(Mutex::new(RefCell::new(card)))
+    }
+
+    pub fn into_inner(self) -> SdmmcCard {
+        self.0.into_inner().into_inner()
+    }
+
+    fn with_card<R>(&self, f: impl FnOnce(&mut SdmmcCard) -> Result<R, Error>) -> Result<R, Error> {
+        device::with_card(&self.0, f)
+    }
+}
+
+impl device::DeviceCard for SdmmcCard {
+    fn sector_size(&self) -> u32 {
+        SdmmcCard::sector_size(self)
+    }
+    fn capacity(&self) -> u32 {
+        SdmmcCard::capacity(self)
+    }
+}
 
 impl BlockDevice for SdmmcDevice {
     type Error = Error;
-    fn read(
-        &self,
-        blocks: &mut [embedded_sdmmc::Block],
-        start_block_idx: embedded_sdmmc::BlockIdx,
-    ) -> Result<(), Self::Error> {
-        unsafe {
-            self.0.lock_mut(|card| {
-                embassy_futures::block_on(card.read_sectors(blocks, start_block_idx))
-            })
-        } // :3
-    }
-    fn write(
-        &self,
-        blocks: &[embedded_sdmmc::Block],
-        start_block_idx: embedded_sdmmc::BlockIdx,
-    ) -> Result<(), Self::Error> {
-        unsafe {
-            self.0
-                .lock_mut(|card| embassy_futures::block_on(card.write_sectors()))
-        } // :3
-    }
-    fn num_blocks(&self) -> Result<embedded_sdmmc::BlockCount, Self::Error> {
-        // unsafe {
-        //     self.0
-        //         .lock_mut(|card| embassy_futures::block_on())
-        // } // :3
-        todo!()
+    fn read(&self, blocks: &mut [Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        self.with_card(|card| embassy_futures::block_on(card.read_sectors(blocks, start_block_idx)))
+    }
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        self.with_card(|card| {
+            embassy_futures::block_on(card.write_sectors(blocks, start_block_idx))
+        })
+    }
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        device::num_blocks(&self.0)
     }
 }
 
@@ -96,20 +184,57 @@ impl SdmmcCard {
             freq_khz: 20000,
             dma_rx_buf,
             dma_tx_buf,
-            rsa: 0,
+            idmac: IdmacRing::new(),
+            zero_copy: false,
+            io_suspending: false,
+            read_in_flight: false,
             ocr: 0,
             raw_cid: [0u32; 4],
             rca: 0,
             csd: CSD {
                 sector_size: 0,
                 capacity: 0,
+                wp_grp_sectors: 0,
+                erase_grp_sectors: 0,
             },
+            ext_csd: Default::default(),
+            reliable_write: false,
+            power_state: mmc::PowerState::Active,
+            sdio: Default::default(),
+            cis_handlers: [None; io::SDIO_MAX_CIS_HANDLERS],
             is_mmc: false,
+            is_mem: true,
+            is_sdio: false,
         };
         card.sdmmc.init().await.unwrap();
         card
     }
 
+    /// Card has a memory part, true for SD memory, eMMC and combo cards
+    pub fn is_mem(&self) -> bool {
+        self.is_mem
+    }
+
+    /// Card has IO functions, true for SDIO and combo cards
+    pub fn is_sdio(&self) -> bool {
+        self.is_sdio
+    }
+
+    /// Slot the card is attached to, see [`crate::wait_for_sdio_interrupt`]
+    pub fn slot(&self) -> Slot {
+        self.slot
+    }
+
+    /// Sector size of the memory part in bytes, 512 on all current cards
+    pub fn sector_size(&self) -> u32 {
+        self.csd.sector_size
+    }
+
+    /// Capacity of the memory part in sectors
+    pub fn capacity(&self) -> u32 {
+        self.csd.capacity
+    }
+
     pub async fn init_sd_if_cond(&mut self) -> Result<(), Error> {
         let mut host_ocr = SD_OCR_VOL_MASK;
         match self.cmd_send_if_cond(host_ocr).await {
@@ -144,13 +269,72 @@ impl SdmmcCard {
         todo!()
     }
 
-    fn decode_csd(&self, cmd: &SdmmcCmd) -> sdio_host::sd::CSD<SD> {
-        sdio_host::sd::CSD::<SD>::from(cmd.responce)
+    fn decode_csd(&self, cmd: &SdmmcCmd) -> Result<CSD, Error> {
+        let resp = &cmd.responce;
+        let csd_ver = mmc_rsp_bits(resp, MMC_CSD_CSDVER);
+        let (mut capacity, read_bl_len) = if !self.is_mmc && csd_ver == SD_CSD_CSDVER_2_0 {
+            (
+                (mmc_rsp_bits(resp, SD_CSD_V2_C_SIZE) + 1) << 10,
+                SD_CSD_V2_BL_LEN,
+            )
+        } else if self.is_mmc || csd_ver == SD_CSD_CSDVER_1_0 {
+            // all eMMC CSD structure versions share the SD 1.0 capacity fields,
+            // cards above 2GB report their real size in EXT_CSD
+            (
+                (mmc_rsp_bits(resp, MMC_CSD_C_SIZE) + 1)
+                    << (mmc_rsp_bits(resp, MMC_CSD_C_SIZE_MULT) + 2),
+                mmc_rsp_bits(resp, MMC_CSD_READ_BL_LEN),
+            )
+        } else {
+            warn!("{TAG} unknown SD CSD structure version {csd_ver}");
+            Err(Error::NotSupported)?
+        };
+        let read_bl_size = 1 << read_bl_len;
+        let sector_size = read_bl_size.min(512);
+        if sector_size < read_bl_size {
+            capacity *= read_bl_size / sector_size;
+        }
+        // only standard capacity SD cards have write protection groups
+        let wp_grp_sectors = if !self.is_mmc
+            && csd_ver == SD_CSD_CSDVER_1_0
+            && mmc_rsp_bits(resp, SD_CSD_WP_GRP_ENABLE) != 0
+        {
+            let sectors = (mmc_rsp_bits(resp, SD_CSD_WP_GRP_SIZE) + 1)
+                * (mmc_rsp_bits(resp, SD_CSD_SECTOR_SIZE) + 1);
+            (sectors << mmc_rsp_bits(resp, SD_CSD_WRITE_BL_LEN)) / 512
+        } else {
+            0
+        };
+        let erase_grp_sectors = if self.is_mmc {
+            let blocks = (mmc_rsp_bits(resp, MMC_CSD_ERASE_GRP_SIZE) + 1)
+                * (mmc_rsp_bits(resp, MMC_CSD_ERASE_GRP_MULT) + 1);
+            (blocks << mmc_rsp_bits(resp, MMC_CSD_WRITE_BL_LEN)) / 512
+        } else {
+            0
+        };
+        Ok(CSD {
+            sector_size,
+            capacity,
+            wp_grp_sectors,
+            erase_grp_sectors,
+        })
     }
 }
 
 impl SdmmcCard {
     async fn do_transaction(&mut self, cmd_info: &mut SdmmcCmd<'_>) -> Result<(), Error> {
+        self.start_transaction(cmd_info).await?;
+        self.finish_transaction(cmd_info).await
+    }
+
+    /// Issue the command and start its data transfer without waiting for either.
+    ///
+    /// [`Self::finish_transaction`] must follow with an equivalent command before anything else
+    /// is sent to the card.
+    pub(crate) async fn start_transaction(
+        &mut self,
+        cmd_info: &mut SdmmcCmd<'_>,
+    ) -> Result<(), Error> {
         // NOTE critical section is not needed due to ownership
         // let block = self.sdmmc.host.register_block();
 
@@ -162,8 +346,13 @@ impl SdmmcCard {
             self.handle_voltage_switch_stage1(self.slot, cmd_info).await;
         }
 
+        if cmd_info.has_flag(SCF_WAIT_BUSY) {
+            // an HPI request only applies to the busy operation it was made during
+            mmc::reset_hpi_request(self.slot);
+        }
+
         let hw_cmd = cmd_info.make_hw_cmd();
-        if cmd_info.data.is_some() {
+        if cmd_info.has_data() {
             if cmd_info.datalen >= 4 && cmd_info.datalen % 4 != 0 {
                 warn!(
                     "{TAG} do_transaction: invalid size: total={}",
@@ -174,16 +363,87 @@ impl SdmmcCard {
                 Ok(())
             }?;
 
-            // May need to add alignment check for sanity purposes later here
-
-            self.dma_prepare(cmd_info.datalen, cmd_info.blklen);
-            // self.dma_rx_buf.
+            let len = cmd_info.datalen as usize;
+            let write = cmd_info.tx_data.is_some()
+                || cmd_info.sg.as_ref().is_some_and(SgList::is_write)
+                || (cmd_info.staged.is_some() && !cmd_info.has_flag(SCF_CMD_READ));
+            if cmd_info.sg.as_ref().is_some_and(|sg| sg.len() != len) {
+                warn!("{TAG} do_transaction: scatter-gather buffers do not match total={len}");
+                Err(Error::InvalidSize)?;
+            }
+            self.zero_copy = match (
+                cmd_info.tx_data,
+                cmd_info.data.as_deref_mut(),
+                cmd_info.sg.as_mut(),
+            ) {
+                (Some(src), ..) if src.len() >= len => {
+                    self.zero_copy_chain(iter::once((src.as_ptr().cast_mut(), len)))
+                }
+                (None, Some(dst), _) if dst.len() >= len => {
+                    self.zero_copy_chain(iter::once((dst.as_mut_ptr(), len)))
+                }
+                (None, None, Some(SgList::Write(bufs))) => {
+                    let segments = bufs
+                        .iter()
+                        .map(|b| (b.as_ptr().cast::<u8>().cast_mut(), b.len() * Block::LEN));
+                    self.zero_copy_chain(segments)
+                }
+                (None, None, Some(SgList::Read(bufs))) => {
+                    let segments = bufs
+                        .iter_mut()
+                        .map(|b| (b.as_mut_ptr().cast::<u8>(), b.len() * Block::LEN));
+                    self.zero_copy_chain(segments)
+                }
+                _ => false,
+            };
+
+            if !self.zero_copy {
+                let staging = match (cmd_info.staged, write) {
+                    (Some(Staging::Rx), _) | (None, false) => self.dma_rx_buf.as_mut_slice(),
+                    (Some(Staging::Tx), _) | (None, true) => self.dma_tx_buf.as_mut_slice(),
+                };
+                if len > staging.len() {
+                    warn!("{TAG} do_transaction: DMA buffer too small: total={len}");
+                    Err(Error::InvalidSize)?;
+                }
+                if write && cmd_info.staged.is_none() {
+                    match (cmd_info.tx_data, &cmd_info.sg) {
+                        (Some(src), _) if src.len() >= len => {
+                            staging[..len].copy_from_slice(&src[..len])
+                        }
+                        (None, Some(SgList::Write(bufs))) => {
+                            // gather the buffers into the DMA buffer
+                            let blocks = bufs.iter().flat_map(|b| b.iter());
+                            for (dst, src) in staging[..len].chunks_mut(Block::LEN).zip(blocks) {
+                                dst.copy_from_slice(&src.contents);
+                            }
+                        }
+                        _ => {
+                            warn!("{TAG} do_transaction: invalid write size: total={len}");
+                            Err(Error::InvalidSize)?;
+                        }
+                    }
+                }
+                self.idmac.clear();
+                self.idmac
+                    .push(staging.as_mut_ptr(), len)
+                    .inspect_err(|err| warn!("{TAG} do_transaction: total={len}: {err:?}"))?;
+            }
+            let desc = self.idmac.start().ok_or(Error::InvalidSize)?;
+            self.sdmmc
+                .dma_prepare(desc, cmd_info.blklen as u16, cmd_info.datalen);
         }
 
         self.sdmmc
             .start_cmd(crate::Slot::Slot1, hw_cmd, cmd_info.arg)
-            .await?;
+            .await
+    }
 
+    /// Wait for the command started by [`Self::start_transaction`] and copy out received data
+    pub(crate) async fn finish_transaction(
+        &mut self,
+        cmd_info: &mut SdmmcCmd<'_>,
+    ) -> Result<(), Error> {
         // process events until transfer is complete
         let mut ret = Ok(());
         let mut unhandled = Event {
@@ -204,16 +464,37 @@ impl SdmmcCard {
         }
 
         if ret.is_ok() && cmd_info.has_flag(SCF_WAIT_BUSY) {
-            if !self.wait_for_busy_cleared(cmd_info.timeout_ms).await {
-                info!("{TAG} wait_for_busy_cleared returned false");
-                ret = Err(Error::Timeout);
+            ret = self
+                .wait_for_busy_cleared(cmd_info.timeout_ms)
+                .await
+                .inspect_err(|err| info!("{TAG} wait_for_busy_cleared returned {err:?}"));
+        }
+
+        if ret.is_err() {
+            let descs = self.idmac.descriptors();
+            if let Some(i) = descs.iter().position(IdmacDesc::has_error) {
+                warn!(
+                    "{TAG} IDMAC descriptor {i} of {} reported a card error",
+                    descs.len()
+                );
             }
+            let pending = descs.iter().filter(|d| d.owned_by_dma()).count();
+            debug!("{TAG} {pending} IDMAC descriptors not processed");
         }
 
-        if let Some(buf) = cmd_info.data.as_mut() {
-            let bytes = self.dma_rx_buf.read_received_data(buf);
-            debug!("{TAG} received data with {bytes} bytes left");
+        if self.zero_copy || cmd_info.staged.is_some() {
+            // the data is already in place
+        } else if let Some(buf) = cmd_info.data.as_mut() {
+            let len = buf.len().min(cmd_info.datalen as usize);
+            buf[..len].copy_from_slice(&self.dma_rx_buf.as_slice()[..len]);
+        } else if let Some(SgList::Read(bufs)) = cmd_info.sg.as_mut() {
+            // scatter the DMA buffer over the buffers
+            let received = self.dma_rx_buf.as_slice().chunks(Block::LEN);
+            for (dst, src) in bufs.iter_mut().flat_map(|b| b.iter_mut()).zip(received) {
+                dst.contents.copy_from_slice(src);
+            }
         }
+        self.zero_copy = false;
 
         ret
     }
@@ -233,10 +514,40 @@ impl SdmmcCard {
         state: &mut State,
         unhandled: &mut Event,
     ) -> Result<(), Error> {
-        match self
-            .wait_for_event(Duration::from_millis(cmd.timeout_ms).as_ticks())
-            .await
-        {
+        let ticks = Duration::from_millis(cmd.timeout_ms).as_ticks();
+        let event = if cmd.opcode == SD_IO_RW_EXTENDED && *state == State::SendingData {
+            // a read wait hold counts towards the timeout, the requests must not restart it
+            let deadline = Instant::now() + Duration::from_ticks(ticks);
+            loop {
+                let ticks = deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_ticks();
+                match select(self.wait_for_event(ticks), io::wait_io_request()).await {
+                    Either::First(event) => break event,
+                    Either::Second(io::IoRequest::ReadWait(en)) => {
+                        if cmd.has_flag(SCF_CMD_READ) && self.sdio.read_wait() {
+                            self.sdmmc.set_read_wait(en);
+                        } else {
+                            warn!("{TAG} handle_event: read wait not available, ignored");
+                        }
+                    }
+                    Either::Second(io::IoRequest::Suspend) if self.sdio.suspend_resume() => {
+                        // the data keeps flowing until the card releases the bus
+                        warn!("{TAG} handle_event: CMD53 suspend requested");
+                        self.io_suspending = true;
+                        return Err(Error::Interrupted);
+                    }
+                    Either::Second(_) => {
+                        warn!("{TAG} handle_event: CMD53 abort requested");
+                        self.sdmmc.dma_stop();
+                        return Err(Error::Interrupted);
+                    }
+                }
+            }
+        } else {
+            self.wait_for_event(ticks).await
+        };
+        match event {
             Ok(mut event) => {
                 info!(
                     "{} handle_event: slot {:?} event {:?} unhandled {:?}",
@@ -287,7 +598,7 @@ impl SdmmcCard {
 
                         next_state = if cmd.err.is_some() {
                             State::Idle
-                        } else if cmd.data.is_none() {
+                        } else if !cmd.has_data() {
                             State::Idle
                         } else {
                             State::SendingData
@@ -300,7 +611,12 @@ impl SdmmcCard {
                         self.sdmmc.dma_stop();
                     }
                     if mask_check_and_clear(&mut event.dma_status, SD_DMA_DONE_MASK) {
-                        next_state = State::Busy;
+                        // descriptors handed back go out again with the rest of the data
+                        if self.idmac.refill() {
+                            next_state = State::Busy;
+                        } else {
+                            self.sdmmc.dma_resume();
+                        }
                     }
                     if orig_evt.sdmmc_status & (SDMMC_INTMASK_SBE | SDMMC_INTMASK_DATA_OVER) != 0 {
                         next_state = State::Idle;
@@ -368,7 +684,7 @@ impl SdmmcCard {
             None
         } {
             cmd.err = Some(err);
-            if cmd.data.is_some() {
+            if cmd.has_data() {
                 self.sdmmc.dma_stop();
             }
             warn!("{TAG} process_command_responce: error {err:?} status={status:b}");
@@ -394,7 +710,7 @@ impl SdmmcCard {
                 .write(|w| w.fifo_reset().set_bit());
         }
         if cmd.err.is_some() {
-            if cmd.data.is_some() {
+            if cmd.has_data() {
                 self.dma_stop();
             }
             warn!("{TAG} process data status error {:?}", cmd.err);
@@ -475,34 +791,64 @@ impl SdmmcCard {
     //     self.sdmmc.calc_freq(host_div, card_div)
     // }
 
-    fn dma_prepare(&mut self, data_size: u32, block_size: u32) {
-        let prep = self.dma_rx_buf.prepare();
+    /// Set the descriptor ring up over the `(buf, len)` segments if the DMA can use them in place,
+    /// see [`Self::dma_can_use`], and there are at most [`DMA_DESCRIPTORS`] of them. `false` means
+    /// going through the DMA buffers.
+    fn zero_copy_chain(&mut self, segments: impl Iterator<Item = (*mut u8, usize)>) -> bool {
+        self.idmac.clear();
+        for (buf, len) in segments {
+            if !self.dma_can_use(buf, len) || self.idmac.push(buf, len).is_err() {
+                self.idmac.clear();
+                return false;
+            }
+        }
+        !self.idmac.is_empty()
+    }
 
-        let block = self.sdmmc.host.register_block();
-        block.bytcnt().write(|w| unsafe { w.bits(data_size) });
-        block.blksiz().write(|w| unsafe { w.bits(block_size) });
-        block
-            .dbaddr()
-            .write(|w| unsafe { w.dbaddr().bits(prep.start.addr() as u32) });
-        self.sdmmc.enable_dma(true);
-        self.dma_resume();
+    /// Whether the DMA can move `len` bytes at `buf` in place: word aligned and in internal RAM,
+    /// like the descriptors themselves
+    pub(crate) fn dma_can_use(&self, buf: *const u8, len: usize) -> bool {
+        let descs = self.idmac.as_ptr_range();
+        let descs_len = descs.end as usize - descs.start as usize;
+        is_dma_capable(descs.start.cast(), descs_len)
+            && buf as usize % 4 == 0
+            && len % 4 == 0
+            && is_dma_capable(buf, len)
     }
 
-    fn dma_resume(&self) {
-        self.sdmmc.dma_resume();
+    /// Contents of a DMA buffer, e.g. after a read with [`SdmmcCmd::staged`]
+    pub(crate) fn dma_buf(&self, staging: Staging) -> &[u8] {
+        match staging {
+            Staging::Rx => self.dma_rx_buf.as_slice(),
+            Staging::Tx => self.dma_tx_buf.as_slice(),
+        }
     }
 
-    async fn wait_for_busy_cleared(&self, timeout_ms: u64) -> bool {
+    /// DMA buffer to fill before a write with [`SdmmcCmd::staged`]
+    pub(crate) fn dma_buf_mut(&mut self, staging: Staging) -> &mut [u8] {
+        match staging {
+            Staging::Rx => self.dma_rx_buf.as_mut_slice(),
+            Staging::Tx => self.dma_tx_buf.as_mut_slice(),
+        }
+    }
+
+    async fn wait_for_busy_cleared(&self, timeout_ms: u64) -> Result<(), Error> {
         if timeout_ms == 0 {
-            !self.card_busy()
+            if self.card_busy() {
+                Err(Error::Timeout)?;
+            }
+            Ok(())
         } else {
             for _ in 0..Duration::from_millis(timeout_ms).as_ticks() {
                 if !self.card_busy() {
-                    return true;
+                    return Ok(());
+                }
+                if self.ext_csd.hpi_enabled && mmc::take_hpi_request(self.slot) {
+                    return Err(Error::Interrupted);
                 }
                 yield_now().await;
             }
-            false
+            Err(Error::Timeout)
         }
     }
 
@@ -0,0 +1,106 @@
+//! Async block device interface.
+//!
+//! [`SdmmcCard`] implements [`block_device_driver::BlockDevice`] directly for a single owner.
+//! [`SharedCard`] puts the card behind an async mutex, and `&SharedCard` implements the trait as
+//! well so several tasks can use the card. The lock is taken per call, waiting for it parks the
+//! task and nothing holds interrupts off during a transfer.
+
+use aligned::A4;
+use block_device_driver::BlockDevice;
+use embassy_sync::{
+    blocking_mutex::raw::RawMutex,
+    mutex::{Mutex, MutexGuard},
+};
+
+use crate::{sdmmc_sd::SdmmcCard, Error};
+
+mod layout;
+
+pub(crate) use layout::{
+    aligned_blocks_as_bytes, aligned_blocks_as_bytes_mut, bytes_as_aligned_blocks,
+    bytes_as_aligned_blocks_mut,
+};
+pub use layout::{AlignedBlock, BLOCK_SIZE};
+
+impl BlockDevice<BLOCK_SIZE> for SdmmcCard {
+    type Error = Error;
+    type Align = A4;
+
+    async fn read(
+        &mut self,
+        block_address: u32,
+        data: &mut [AlignedBlock],
+    ) -> Result<(), Self::Error> {
+        self.read_sectors_split(aligned_blocks_as_bytes_mut(data), block_address)
+            .await
+    }
+
+    async fn write(
+        &mut self,
+        block_address: u32,
+        data: &[AlignedBlock],
+    ) -> Result<(), Self::Error> {
+        self.write_sectors_split(aligned_blocks_as_bytes(data), block_address)
+            .await
+    }
+
+    async fn size(&mut self) -> Result<u64, Self::Error> {
+        if self.sector_size() as usize != BLOCK_SIZE {
+            Err(Error::NotSupported)?;
+        }
+        Ok(self.capacity() as u64 * BLOCK_SIZE as u64)
+    }
+}
+
+/// Card shared between tasks, see the module documentation
+pub struct SharedCard<M: RawMutex> {
+    card: Mutex<M, SdmmcCard>,
+}
+
+impl<M: RawMutex> SharedCard<M> {
+    /// `card` must be initialized, see [`SdmmcCard::init`]
+    pub fn new(card: SdmmcCard) -> Self {
+        Self {
+            card: Mutex::new(card),
+        }
+    }
+
+    pub fn into_inner(self) -> SdmmcCard {
+        self.card.into_inner()
+    }
+
+    /// Exclusive access for sequences that must not interleave with other users
+    pub async fn lock(&self) -> MutexGuard<'_, M, SdmmcCard> {
+        self.card.lock().await
+    }
+
+    /// The card if no other user holds it, for blocking wrappers that must not wait
+    pub(crate) fn try_lock(&self) -> Result<MutexGuard<'_, M, SdmmcCard>, Error> {
+        self.card.try_lock().map_err(|_| Error::InvalidState)
+    }
+}
+
+impl<M: RawMutex> BlockDevice<BLOCK_SIZE> for &SharedCard<M> {
+    type Error = Error;
+    type Align = A4;
+
+    async fn read(
+        &mut self,
+        block_address: u32,
+        data: &mut [AlignedBlock],
+    ) -> Result<(), Self::Error> {
+        self.card.lock().await.read(block_address, data).await
+    }
+
+    async fn write(
+        &mut self,
+        block_address: u32,
+        data: &[AlignedBlock],
+    ) -> Result<(), Self::Error> {
+        self.card.lock().await.write(block_address, data).await
+    }
+
+    async fn size(&mut self) -> Result<u64, Self::Error> {
+        self.card.lock().await.size().await
+    }
+}
@@ -0,0 +1,48 @@
+//! Blocks as the trait implementations and the DMA see them, free of the hardware.
+
+use aligned::{Aligned, A4};
+
+/// Block size of the trait implementations
+pub const BLOCK_SIZE: usize = 512;
+
+/// Word aligned block, as taken by the trait implementations
+pub type AlignedBlock = Aligned<A4, [u8; BLOCK_SIZE]>;
+
+// Blocks are handed to the DMA as one contiguous byte slice
+const _: () = assert!(core::mem::size_of::<AlignedBlock>() == BLOCK_SIZE);
+
+pub(crate) fn aligned_blocks_as_bytes(blocks: &[AlignedBlock]) -> &[u8] {
+    // SAFETY: an aligned block is a padding free byte array, checked above
+    unsafe { core::slice::from_raw_parts(blocks.as_ptr().cast(), blocks.len() * BLOCK_SIZE) }
+}
+
+pub(crate) fn aligned_blocks_as_bytes_mut(blocks: &mut [AlignedBlock]) -> &mut [u8] {
+    // SAFETY: an aligned block is a padding free byte array, checked above
+    unsafe {
+        core::slice::from_raw_parts_mut(blocks.as_mut_ptr().cast(), blocks.len() * BLOCK_SIZE)
+    }
+}
+
+/// `bytes` as blocks, if it is word aligned and a whole number of blocks
+pub(crate) fn bytes_as_aligned_blocks(bytes: &[u8]) -> Option<&[AlignedBlock]> {
+    if bytes.as_ptr() as usize % core::mem::align_of::<AlignedBlock>() != 0
+        || bytes.len() % BLOCK_SIZE != 0
+    {
+        return None;
+    }
+    // SAFETY: alignment and length checked above, an aligned block is a padding free byte array
+    Some(unsafe { core::slice::from_raw_parts(bytes.as_ptr().cast(), bytes.len() / BLOCK_SIZE) })
+}
+
+/// `bytes` as blocks, if it is word aligned and a whole number of blocks
+pub(crate) fn bytes_as_aligned_blocks_mut(bytes: &mut [u8]) -> Option<&mut [AlignedBlock]> {
+    if bytes.as_ptr() as usize % core::mem::align_of::<AlignedBlock>() != 0
+        || bytes.len() % BLOCK_SIZE != 0
+    {
+        return None;
+    }
+    // SAFETY: alignment and length checked above, an aligned block is a padding free byte array
+    Some(unsafe {
+        core::slice::from_raw_parts_mut(bytes.as_mut_ptr().cast(), bytes.len() / BLOCK_SIZE)
+    })
+}
@@ -0,0 +1,326 @@
+//! Write-back sector cache.
+//!
+//! [`SectorCache`] keeps the `N` most recently used sectors of a block device in memory. Reads of
+//! cached sectors do not touch the card and writes only mark their sector dirty. A dirty sector
+//! goes to the device on its own when it is evicted, and all of them on [`SectorCache::flush`],
+//! sorted by address so that adjacent sectors are written by a single multi-block command.
+//! Transfers of `N` sectors or more bypass the cache instead of flushing it over and over.
+//!
+//! [`BlockingCache`] offers the cache to [`embedded_sdmmc::VolumeManager`], whose FAT metadata
+//! accesses keep hitting the same few sectors.
+
+use core::cell::RefCell;
+
+use aligned::{Aligned, A4};
+use block_device_driver::BlockDevice;
+use embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex};
+use embedded_sdmmc::{Block, BlockCount, BlockIdx};
+use log::{debug, warn};
+
+use crate::{
+    sdmmc_sd::{
+        block::{bytes_as_aligned_blocks, bytes_as_aligned_blocks_mut, AlignedBlock, BLOCK_SIZE},
+        blocks_as_bytes, blocks_as_bytes_mut,
+    },
+    Error,
+};
+
+const TAG: &'static str = "[SDMMC_CACHE]";
+
+#[derive(Clone, Copy)]
+struct Line {
+    block: u32,
+    valid: bool,
+    dirty: bool,
+    /// Clock value of the last access
+    used: u64,
+}
+
+const EMPTY_LINE: Line = Line {
+    block: 0,
+    valid: false,
+    dirty: false,
+    used: 0,
+};
+
+/// Cache of `N` sectors in front of a block device, see the module documentation.
+///
+/// Dirty sectors are lost unless [`Self::flush`] runs before the cache is dropped.
+pub struct SectorCache<D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>, const N: usize> {
+    device: D,
+    lines: [Line; N],
+    data: [AlignedBlock; N],
+    clock: u64,
+}
+
+impl<D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>, const N: usize> SectorCache<D, N> {
+    pub fn new(device: D) -> Self {
+        const { assert!(N > 0, "sector cache needs at least one line") };
+        Self {
+            device,
+            lines: [EMPTY_LINE; N],
+            data: [Aligned([0; BLOCK_SIZE]); N],
+            clock: 0,
+        }
+    }
+
+    /// The device, dropping dirty sectors that were not flushed
+    pub fn into_inner(self) -> D {
+        let dirty = self.dirty_count();
+        if dirty != 0 {
+            warn!("{TAG} dropping {dirty} dirty sectors");
+        }
+        self.device
+    }
+
+    /// Sectors written to the cache but not yet to the device
+    pub fn dirty_count(&self) -> usize {
+        self.lines.iter().filter(|l| l.dirty).count()
+    }
+
+    /// Drop all cached sectors, e.g. after the device was written around the cache
+    pub async fn invalidate(&mut self) -> Result<(), Error> {
+        self.flush().await?;
+        self.lines = [EMPTY_LINE; N];
+        Ok(())
+    }
+
+    /// Write all dirty sectors to the device, runs of adjacent sectors as one transfer each
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        if !self.lines.iter().any(|l| l.dirty) {
+            return Ok(());
+        }
+        self.sort_lines();
+        let mut start = 0;
+        while start < N {
+            if !self.lines[start].dirty {
+                start += 1;
+                continue;
+            }
+            let mut end = start + 1;
+            while end < N
+                && self.lines[end].dirty
+                && self.lines[end].block == self.lines[end - 1].block.wrapping_add(1)
+            {
+                end += 1;
+            }
+            debug!(
+                "{TAG} flush {} sectors at {}",
+                end - start,
+                self.lines[start].block
+            );
+            self.device
+                .write(self.lines[start].block, &self.data[start..end])
+                .await?;
+            self.lines[start..end]
+                .iter_mut()
+                .for_each(|l| l.dirty = false);
+            start = end;
+        }
+        Ok(())
+    }
+
+    /// Order the lines by address, empty ones last, so runs of sectors are contiguous in memory
+    fn sort_lines(&mut self) {
+        let key = |l: &Line| (!l.valid, l.block);
+        for i in 0..N {
+            let min = (i..N).min_by_key(|&j| key(&self.lines[j])).unwrap();
+            if min != i {
+                self.lines.swap(i, min);
+                self.data.swap(i, min);
+            }
+        }
+    }
+
+    fn lookup(&self, block: u32) -> Option<usize> {
+        self.lines.iter().position(|l| l.valid && l.block == block)
+    }
+
+    fn touch(&mut self, line: usize) {
+        self.clock += 1;
+        self.lines[line].used = self.clock;
+    }
+
+    /// Empty or least recently used line
+    fn victim(&self) -> usize {
+        (0..N)
+            .min_by_key(|&i| (self.lines[i].valid, self.lines[i].used))
+            .unwrap()
+    }
+
+    /// Store `src` as the cached copy of `block`, evicting a line if needed
+    async fn insert(&mut self, block: u32, src: &AlignedBlock, dirty: bool) -> Result<(), Error> {
+        let (line, was_dirty) = match self.lookup(block) {
+            Some(line) => (line, self.lines[line].dirty),
+            None => {
+                let victim = self.victim();
+                if self.lines[victim].dirty {
+                    let block = self.lines[victim].block;
+                    debug!("{TAG} write back sector {block}");
+                    self.device
+                        .write(block, core::slice::from_ref(&self.data[victim]))
+                        .await?;
+                    self.lines[victim].dirty = false;
+                }
+                (victim, false)
+            }
+        };
+        self.data[line].copy_from_slice(&src[..]);
+        self.lines[line] = Line {
+            block,
+            valid: true,
+            dirty: dirty || was_dirty,
+            used: 0,
+        };
+        self.touch(line);
+        Ok(())
+    }
+
+    fn check_range(block_address: u32, block_count: usize) -> Result<(), Error> {
+        if block_address as u64 + block_count as u64 > u32::MAX as u64 + 1 {
+            warn!("{TAG} {block_count} blocks at {block_address} beyond the block address range");
+            Err(Error::InvalidSize)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>, const N: usize> BlockDevice<BLOCK_SIZE>
+    for SectorCache<D, N>
+{
+    type Error = Error;
+    type Align = A4;
+
+    async fn read(
+        &mut self,
+        block_address: u32,
+        data: &mut [AlignedBlock],
+    ) -> Result<(), Self::Error> {
+        Self::check_range(block_address, data.len())?;
+        let mut i = 0;
+        while i < data.len() {
+            let block = block_address + i as u32;
+            if let Some(line) = self.lookup(block) {
+                data[i].copy_from_slice(&self.data[line][..]);
+                self.touch(line);
+                i += 1;
+                continue;
+            }
+            // sectors missing from the cache are read with one command, straight into `data`
+            let run = (i..data.len())
+                .take_while(|&j| self.lookup(block_address + j as u32).is_none())
+                .count();
+            self.device.read(block, &mut data[i..i + run]).await?;
+            if run < N {
+                for (j, src) in data[i..i + run].iter().enumerate() {
+                    self.insert(block + j as u32, src, false).await?;
+                }
+            }
+            i += run;
+        }
+        Ok(())
+    }
+
+    async fn write(
+        &mut self,
+        block_address: u32,
+        data: &[AlignedBlock],
+    ) -> Result<(), Self::Error> {
+        Self::check_range(block_address, data.len())?;
+        if data.len() < N {
+            for (i, src) in data.iter().enumerate() {
+                self.insert(block_address + i as u32, src, true).await?;
+            }
+            return Ok(());
+        }
+        // larger than the cache, write through and refresh the cached copies
+        self.device.write(block_address, data).await?;
+        for (i, src) in data.iter().enumerate() {
+            if let Some(line) = self.lookup(block_address + i as u32) {
+                self.data[line].copy_from_slice(&src[..]);
+                self.lines[line].dirty = false;
+            }
+        }
+        Ok(())
+    }
+
+    async fn size(&mut self) -> Result<u64, Self::Error> {
+        self.device.size().await
+    }
+}
+
+/// Blocking [`embedded_sdmmc::BlockDevice`] over a [`SectorCache`].
+///
+/// [`embedded_sdmmc`] has no notion of flushing, call [`Self::flush`] once files are closed.
+pub struct BlockingCache<D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>, const N: usize>(
+    Mutex<NoopRawMutex, RefCell<SectorCache<D, N>>>,
+);
+
+impl<D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>, const N: usize> BlockingCache<D, N> {
+    pub fn new(cache: SectorCache<D, N>) -> Self {
+        Self(Mutex::new(RefCell::new(cache)))
+    }
+
+    pub fn into_inner(self) -> SectorCache<D, N> {
+        self.0.into_inner().into_inner()
+    }
+
+    pub fn flush(&self) -> Result<(), Error> {
+        self.with_cache(|cache| embassy_futures::block_on(cache.flush()))
+    }
+
+    fn with_cache<R>(
+        &self,
+        f: impl FnOnce(&mut SectorCache<D, N>) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        self.0.lock(|cache| {
+            let mut cache = cache.try_borrow_mut().map_err(|_| Error::InvalidState)?;
+            f(&mut cache)
+        })
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>, const N: usize>
+    embedded_sdmmc::BlockDevice for BlockingCache<D, N>
+{
+    type Error = Error;
+
+    fn read(&self, blocks: &mut [Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        self.with_cache(|cache| {
+            embassy_futures::block_on(async {
+                if let Some(aligned) = bytes_as_aligned_blocks_mut(blocks_as_bytes_mut(blocks)) {
+                    return cache.read(start_block_idx.0, aligned).await;
+                }
+                let buf = &mut Aligned([0; BLOCK_SIZE]);
+                for (block, dst) in (start_block_idx.0..).zip(blocks.iter_mut()) {
+                    cache.read(block, core::slice::from_mut(buf)).await?;
+                    dst.contents.copy_from_slice(&buf[..]);
+                }
+                Ok(())
+            })
+        })
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        self.with_cache(|cache| {
+            embassy_futures::block_on(async {
+                if let Some(aligned) = bytes_as_aligned_blocks(blocks_as_bytes(blocks)) {
+                    return cache.write(start_block_idx.0, aligned).await;
+                }
+                let buf = &mut Aligned([0; BLOCK_SIZE]);
+                for (block, src) in (start_block_idx.0..).zip(blocks.iter()) {
+                    buf.copy_from_slice(&src.contents);
+                    cache.write(block, core::slice::from_ref(buf)).await?;
+                }
+                Ok(())
+            })
+        })
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        self.with_cache(|cache| {
+            let blocks = embassy_futures::block_on(cache.size())? / BLOCK_SIZE as u64;
+            Ok(BlockCount(u32::try_from(blocks).unwrap_or(u32::MAX)))
+        })
+    }
+}
@@ -1,16 +1,70 @@
-use embassy_time::Timer;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_sdmmc::{Block, BlockIdx};
 use log::{debug, error, info, warn};
 use sdio_host::sd::CSD;
 
-use crate::{cmd::SdmmcCmd, common::*, sdmmc_sd::SdmmcCard, Error, Width};
+use crate::{
+    cmd::{SdmmcCmd, SgList, Staging},
+    common::*,
+    sdmmc::idmac::IDMAC_MAX_BUF_LEN,
+    sdmmc_sd::{blocks_as_bytes, blocks_as_bytes_mut, mmc::PowerState, SdmmcCard, DMA_DESCRIPTORS},
+    Error, Width, EVENT_QUEUE,
+};
 
 const TAG: &'static str = "[SDMMC_CMD]";
 
+/// Longest a card may stay busy programming written blocks
+const SDMMC_READY_FOR_DATA_TIMEOUT_MS: u64 = 5000;
+/// Erase unit of cards that do not report one, 1MB
+const SDMMC_DEFAULT_ERASE_UNIT_SECTORS: u32 = 2048;
+
+/// Failure of a transfer split into several commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferError {
+    /// Sectors from the start of the transfer known to be transferred
+    pub blocks_done: u32,
+    pub err: Error,
+}
+
+impl From<TransferError> for Error {
+    fn from(err: TransferError) -> Self {
+        err.err
+    }
+}
+
 impl SdmmcCard {
     pub async fn send_cmd(&mut self, cmd: &mut SdmmcCmd<'_>) -> Result<(), Error> {
-        cmd.timeout_ms = 1000;
+        if cmd.timeout_ms == 0 {
+            cmd.timeout_ms = 1000;
+        }
+        if self.power_state != PowerState::Active && cmd.opcode != MMC_SLEEP_AWAKE {
+            warn!(
+                "{TAG} cmd {} while card is {:?}",
+                cmd.opcode, self.power_state
+            );
+            Err(Error::InvalidState)?;
+        }
+        self.stop_read_in_flight().await;
         debug!("{TAG} sending cmd {:?}", cmd);
-        self.do_transaction(cmd).await?;
+        match self.do_transaction(cmd).await {
+            Err(Error::Interrupted) if cmd.opcode == SD_IO_RW_EXTENDED => {
+                let func = ((cmd.arg >> SD_ARG_CMD53_FUNC_SHIFT) & SD_ARG_CMD53_FUNC_MASK) as u8;
+                if core::mem::take(&mut self.io_suspending) {
+                    warn!("{TAG} CMD53 to function {func} interrupted, suspending");
+                    self.io_send_suspend(func).await?;
+                } else {
+                    warn!("{TAG} CMD53 to function {func} interrupted, aborting");
+                    self.io_send_abort(func).await?;
+                }
+                Err(Error::Interrupted)?;
+            }
+            Err(Error::Interrupted) => {
+                warn!("{TAG} cmd {} interrupted, sending HPI", cmd.opcode);
+                self.mmc_send_hpi().await?;
+                Err(Error::Interrupted)?;
+            }
+            res => res?,
+        }
         let block = self.sdmmc.host.register_block();
         let state = (block.resp0().read().bits() >> 9) & 0xf;
         log::info!(
@@ -22,13 +76,16 @@ impl SdmmcCard {
             cmd.err,
             state
         );
-        Ok(())
+        match cmd.err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
     }
 
     pub async fn send_app_cmd(&mut self, cmd: &mut SdmmcCmd<'_>) -> Result<(), Error> {
         let mut app_cmd = SdmmcCmd {
             opcode: MMC_APP_CMD,
-            arg: self.rsa << 16,
+            arg: (self.rca as u32) << 16,
             flags: SCF_CMD_AC | SCF_RSP_R1,
             ..Default::default()
         };
@@ -98,7 +155,7 @@ impl SdmmcCard {
                 cmd = SdmmcCmd::default();
                 cmd.arg = ocr;
                 cmd.flags = SCF_CMD_BCR | SCF_RSP_R3;
-                match if self.is_mmc {
+                match if !self.is_mmc {
                     cmd.opcode = SD_APP_OP_COND;
                     self.send_app_cmd(&mut cmd).await
                 } else {
@@ -203,10 +260,12 @@ impl SdmmcCard {
 
         self.send_cmd(cmd).await?;
 
-        assert!(!self.is_mmc);
-        let csd = self.decode_csd(cmd);
-        info!("{TAG} csd={csd:?}");
-        todo!()
+        self.csd = self.decode_csd(cmd)?;
+        info!(
+            "{TAG} csd sector_size={} capacity={}",
+            self.csd.sector_size, self.csd.capacity
+        );
+        Ok(())
     }
 
     pub async fn cmd_select_card(&mut self, rca: u32) -> Result<(), Error> {
@@ -244,28 +303,551 @@ impl SdmmcCard {
         Ok(cmd.responce[0])
     }
 
+    /// SD only: number of blocks of the last write the card programmed without errors
     pub async fn cmd_num_of_written_blocks(&mut self) -> Result<usize, Error> {
-        todo!()
+        if self.is_mmc {
+            Err(Error::NotSupported)?;
+        }
+        let buf = &mut [0u8; 4];
+        self.send_app_cmd(&mut SdmmcCmd {
+            opcode: SD_APP_SEND_NUM_WR_BLOCKS,
+            flags: SCF_CMD_ADTC | SCF_CMD_READ | SCF_RSP_R1,
+            blklen: 4,
+            datalen: 4,
+            buflen: 4,
+            data: Some(buf),
+            ..Default::default()
+        })
+        .await?;
+        Ok(u32::from_be_bytes(*buf) as usize)
+    }
+
+    /// SD Status register, ACMD13
+    pub async fn cmd_sd_status(&mut self) -> Result<[u8; SD_SSR_SIZE], Error> {
+        if self.is_mmc {
+            Err(Error::NotSupported)?;
+        }
+        let buf = &mut [0u8; SD_SSR_SIZE];
+        self.send_app_cmd(&mut SdmmcCmd {
+            opcode: SD_APP_SD_STATUS,
+            flags: SCF_CMD_ADTC | SCF_CMD_READ | SCF_RSP_R1,
+            blklen: SD_SSR_SIZE as u32,
+            datalen: SD_SSR_SIZE as u32,
+            buflen: SD_SSR_SIZE as u32,
+            data: Some(buf),
+            ..Default::default()
+        })
+        .await?;
+        Ok(*buf)
+    }
+
+    /// Erase unit in sectors: the allocation unit of SD cards, the erase group of eMMC, high
+    /// capacity once ERASE_GROUP_DEF is set and from the CSD otherwise.
+    ///
+    /// Partitions and file system clusters aligned to it avoid read-modify-write inside the
+    /// card. Falls back to 1MB when the card does not report it.
+    pub async fn erase_unit_sectors(&mut self) -> Result<u32, Error> {
+        let sectors = if self.is_mmc {
+            if self.ext_csd.erase_group_def & EXT_CSD_ERASE_GROUP_DEF_EN != 0 {
+                self.ext_csd.hc_erase_grp_size as u32 * 1024
+            } else {
+                self.csd.erase_grp_sectors
+            }
+        } else {
+            let ssr = self.cmd_sd_status().await?;
+            SD_SSR_AU_SECTORS[(ssr[SD_SSR_AU_SIZE_BYTE] >> 4) as usize]
+        };
+        Ok(if sectors == 0 {
+            SDMMC_DEFAULT_ERASE_UNIT_SECTORS
+        } else {
+            sectors
+        })
+    }
+
+    /// Predefine the length of the next multi-block transfer, optionally as an eMMC reliable write
+    pub async fn cmd_set_block_count(
+        &mut self,
+        block_count: u32,
+        reliable: bool,
+    ) -> Result<(), Error> {
+        if block_count == 0 || block_count > MMC_SET_BLOCK_COUNT_MASK {
+            Err(Error::InvalidArg)?;
+        }
+        let mut arg = block_count;
+        if reliable {
+            let granularity = self.mmc_reliable_write_granularity();
+            if granularity == 0 || block_count % granularity != 0 {
+                warn!("{TAG} reliable write of {block_count} blocks, granularity {granularity}");
+                Err(Error::InvalidArg)?;
+            }
+            arg |= MMC_SET_BLOCK_COUNT_RELIABLE;
+        }
+        self.send_cmd(&mut SdmmcCmd {
+            opcode: MMC_SET_BLOCK_COUNT,
+            arg,
+            flags: SCF_CMD_AC | SCF_RSP_R1,
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub async fn cmd_set_write_prot(&mut self, group_start_sector: u32) -> Result<(), Error> {
+        self.send_cmd(&mut SdmmcCmd {
+            opcode: MMC_SET_WRITE_PROT,
+            arg: self.sector_arg(group_start_sector),
+            flags: SCF_CMD_AC | SCF_RSP_R1B | SCF_WAIT_BUSY,
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub async fn cmd_clr_write_prot(&mut self, group_start_sector: u32) -> Result<(), Error> {
+        self.send_cmd(&mut SdmmcCmd {
+            opcode: MMC_CLR_WRITE_PROT,
+            arg: self.sector_arg(group_start_sector),
+            flags: SCF_CMD_AC | SCF_RSP_R1B | SCF_WAIT_BUSY,
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Write protection bits of the 32 groups starting at `group_start_sector`, LSB first
+    pub async fn cmd_send_write_prot(&mut self, group_start_sector: u32) -> Result<u32, Error> {
+        let buf = &mut [0u8; 4];
+        self.send_cmd(&mut SdmmcCmd {
+            opcode: MMC_SEND_WRITE_PROT,
+            arg: self.sector_arg(group_start_sector),
+            flags: SCF_CMD_ADTC | SCF_CMD_READ | SCF_RSP_R1,
+            blklen: 4,
+            datalen: 4,
+            buflen: 4,
+            data: Some(buf),
+            ..Default::default()
+        })
+        .await?;
+        Ok(u32::from_be_bytes(*buf))
+    }
+
+    /// eMMC only: 2 bit protection type of the 32 groups starting at `group_start_sector`, LSB
+    /// first
+    pub async fn cmd_send_write_prot_type(
+        &mut self,
+        group_start_sector: u32,
+    ) -> Result<u64, Error> {
+        let buf = &mut [0u8; 8];
+        self.send_cmd(&mut SdmmcCmd {
+            opcode: MMC_SEND_WRITE_PROT_TYPE,
+            arg: self.sector_arg(group_start_sector),
+            flags: SCF_CMD_ADTC | SCF_CMD_READ | SCF_RSP_R1,
+            blklen: 8,
+            datalen: 8,
+            buflen: 8,
+            data: Some(buf),
+            ..Default::default()
+        })
+        .await?;
+        Ok(u64::from_be_bytes(*buf))
+    }
+
+    /// Command argument addressing `sector`, byte addressed on standard capacity cards
+    pub(crate) fn sector_arg(&self, sector: u32) -> u32 {
+        if self.ocr & SD_OCR_SDHC_CAP != 0 {
+            sector
+        } else {
+            sector * 512
+        }
     }
 }
 
 impl SdmmcCard {
-    pub async fn write_sectors(&mut self) -> Result<(), Error> {
-        todo!()
+    pub async fn write_sectors(
+        &mut self,
+        blocks: &[Block],
+        start_block_idx: BlockIdx,
+    ) -> Result<(), Error> {
+        self.write_sectors_split(blocks_as_bytes(blocks), start_block_idx.0)
+            .await
     }
 
-    pub async fn write_sectors_dma(&mut self) -> Result<(), Error> {
-        todo!()
+    /// Write whole sectors from `src` starting at `start_block`, see
+    /// [`Self::write_sectors_pipelined`]
+    pub async fn write_sectors_split(&mut self, src: &[u8], start_block: u32) -> Result<(), Error> {
+        Ok(self.write_sectors_pipelined(src, start_block).await?)
+    }
+
+    /// Write whole sectors from `src` starting at `start_block`, with as many commands as the DMA
+    /// descriptors and buffer need.
+    ///
+    /// Parts of `src` the DMA can use in place are sent from there. The others are copied into the
+    /// DMA buffer, each one while the card programs the one before.
+    pub async fn write_sectors_pipelined(
+        &mut self,
+        src: &[u8],
+        start_block: u32,
+    ) -> Result<(), TransferError> {
+        let sector_size = self.split_sector_size(src.len())?;
+        let bounce_len = self.dma_tx_buf.capacity();
+        let mut done = 0;
+        // the chunk at the start of `rest` is already in the DMA buffer
+        let mut staged = false;
+        let mut rest = src;
+        while !rest.is_empty() {
+            let count = self
+                .chunk_blocks(rest, bounce_len)
+                .map_err(|err| TransferError {
+                    blocks_done: done,
+                    err,
+                })?;
+            let (chunk, tail) = rest.split_at(count as usize * sector_size);
+            rest = tail;
+            let mut cmd = SdmmcCmd {
+                buflen: chunk.len() as u32,
+                ..Default::default()
+            };
+            if self.dma_can_use(chunk.as_ptr(), chunk.len()) {
+                cmd.tx_data = Some(chunk);
+            } else {
+                if !staged {
+                    self.dma_buf_mut(Staging::Tx)[..chunk.len()].copy_from_slice(chunk);
+                }
+                cmd.staged = Some(Staging::Tx);
+            }
+            staged = false;
+
+            let block = start_block + done;
+            let res = match self.prepare_write_cmd(&mut cmd, block, count).await {
+                Ok(()) => {
+                    let res = self.send_cmd(&mut cmd).await;
+                    if res.is_ok() && !rest.is_empty() {
+                        // the DMA buffer is free again, fill it while the card programs
+                        staged = self.stage_write(rest, bounce_len);
+                    }
+                    self.finish_write(res, count).await
+                }
+                Err(err) => Err(TransferError {
+                    blocks_done: 0,
+                    err,
+                }),
+            };
+            res.map_err(|err| TransferError {
+                blocks_done: done + err.blocks_done,
+                err: err.err,
+            })?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Copy the next chunk of a pipelined write into the DMA buffer unless it is sent in place
+    fn stage_write(&mut self, rest: &[u8], bounce_len: usize) -> bool {
+        let Ok(count) = self.chunk_blocks(rest, bounce_len) else {
+            return false;
+        };
+        let next = &rest[..count as usize * self.csd.sector_size as usize];
+        if self.dma_can_use(next.as_ptr(), next.len()) {
+            return false;
+        }
+        self.dma_buf_mut(Staging::Tx)[..next.len()].copy_from_slice(next);
+        true
+    }
+
+    /// Sector size for a split transfer of `len` bytes, which must be whole sectors
+    fn split_sector_size(&self, len: usize) -> Result<usize, TransferError> {
+        let sector_size = self.csd.sector_size as usize;
+        if sector_size == 0 || len % sector_size != 0 {
+            Err(TransferError {
+                blocks_done: 0,
+                err: Error::InvalidSize,
+            })?;
+        }
+        Ok(sector_size)
+    }
+
+    /// Sectors of the next transfer over `buf`: what the descriptors cover if the DMA can use
+    /// `buf` in place, what `bounce_len` bytes of DMA buffer hold otherwise
+    pub(crate) fn chunk_blocks(&self, buf: &[u8], bounce_len: usize) -> Result<u32, Error> {
+        let sector_size = self.csd.sector_size as usize;
+        let chain_len = DMA_DESCRIPTORS * IDMAC_MAX_BUF_LEN;
+        let max_len = if self.dma_can_use(buf.as_ptr(), buf.len().min(chain_len)) {
+            chain_len
+        } else {
+            bounce_len.min(chain_len)
+        };
+        let blocks = max_len.min(buf.len()) / sector_size;
+        if blocks == 0 {
+            warn!("{TAG} DMA buffer of {bounce_len} bytes holds no sector");
+            Err(Error::InvalidSize)?;
+        }
+        Ok(blocks as u32)
+    }
+
+    /// Write `block_count` sectors from `src` with CMD24/CMD25 and wait until they are programmed.
+    /// Multi-block writes are reliable writes while enabled, see [`Self::mmc_set_reliable_write`].
+    pub async fn write_sectors_dma(
+        &mut self,
+        src: &[u8],
+        start_block: u32,
+        block_count: u32,
+    ) -> Result<(), Error> {
+        let mut cmd = SdmmcCmd {
+            tx_data: Some(src),
+            buflen: src.len() as u32,
+            ..Default::default()
+        };
+        self.send_write_cmd(&mut cmd, start_block, block_count)
+            .await
+    }
+
+    /// Write consecutive sectors from several buffers with one command.
+    ///
+    /// The buffers go to the card in order. Each one is sent in place when it is word aligned and
+    /// in internal RAM, otherwise all of them are gathered into the DMA buffer first, which
+    /// must then hold them.
+    pub async fn write_sectors_sg(
+        &mut self,
+        src: &[&[Block]],
+        start_block: u32,
+    ) -> Result<(), Error> {
+        let block_count = self.sg_block_count(src.iter().map(|b| b.len()))?;
+        let mut cmd = SdmmcCmd {
+            sg: Some(SgList::Write(src)),
+            buflen: block_count * Block::LEN as u32,
+            ..Default::default()
+        };
+        self.send_write_cmd(&mut cmd, start_block, block_count)
+            .await
+    }
+
+    /// Read consecutive sectors into several buffers with one command, see
+    /// [`Self::write_sectors_sg`] for how the buffers are used
+    pub async fn read_sectors_sg<'a>(
+        &mut self,
+        dst: &'a mut [&'a mut [Block]],
+        start_block: u32,
+    ) -> Result<(), Error> {
+        let block_count = self.sg_block_count(dst.iter().map(|b| b.len()))?;
+        self.check_sector_range(start_block, block_count)?;
+        let mut cmd = self.read_sectors_cmd(None, start_block, block_count);
+        cmd.sg = Some(SgList::Read(dst));
+
+        let err = self.send_cmd(&mut cmd).await;
+        let err_cmd13 = self.cmd_send_status().await;
+        log_read_error(&err, err_cmd13);
+        err
+    }
+
+    /// Sectors of a scatter-gather transfer, which needs 512 byte sectors
+    fn sg_block_count(&self, lens: impl Iterator<Item = usize>) -> Result<u32, Error> {
+        if self.csd.sector_size as usize != Block::LEN {
+            warn!("{TAG} sector size {} not supported", self.csd.sector_size);
+            Err(Error::NotSupported)?;
+        }
+        let count: usize = lens.sum();
+        u32::try_from(count).map_err(|_| Error::InvalidSize)
+    }
+
+    /// Send a write command around the data of `cmd`, then wait for the card to program it
+    async fn send_write_cmd(
+        &mut self,
+        cmd: &mut SdmmcCmd<'_>,
+        start_block: u32,
+        block_count: u32,
+    ) -> Result<(), Error> {
+        self.prepare_write_cmd(cmd, start_block, block_count)
+            .await?;
+        let res = self.send_cmd(cmd).await;
+        Ok(self.finish_write(res, block_count).await?)
+    }
+
+    /// Turn `cmd` into a CMD24/CMD25 of `block_count` sectors, announced with CMD23 for reliable
+    /// writes
+    async fn prepare_write_cmd(
+        &mut self,
+        cmd: &mut SdmmcCmd<'_>,
+        start_block: u32,
+        block_count: u32,
+    ) -> Result<(), Error> {
+        self.check_sector_range(start_block, block_count)?;
+        let block_size = self.csd.sector_size;
+        let reliable = self.reliable_write && block_count > 1;
+        if reliable {
+            self.cmd_set_block_count(block_count, true)
+                .await
+                .inspect_err(|err| {
+                    warn!("{TAG} write_sectors_dma: set_block_count returned {err:?}")
+                })?;
+        }
+        cmd.opcode = if block_count == 1 {
+            MMC_WRITE_BLOCK_SINGLE
+        } else {
+            MMC_WRITE_BLOCK_MULTIPLE
+        };
+        cmd.flags = SCF_CMD_ADTC | SCF_RSP_R1 | if reliable { SCF_PREDEF_COUNT } else { 0 };
+        cmd.blklen = block_size;
+        cmd.datalen = block_count * block_size;
+        cmd.arg = self.sector_arg(start_block);
+        Ok(())
+    }
+
+    /// Wait for the card to program the `block_count` sectors of a write command that returned
+    /// `res`. SD cards tell how many of them made it when the write failed.
+    async fn finish_write(
+        &mut self,
+        res: Result<(), Error>,
+        block_count: u32,
+    ) -> Result<(), TransferError> {
+        // the card keeps programming after the last block
+        let status = self.wait_ready_for_data().await;
+        let err = match (res, status) {
+            (Err(err), _) | (Ok(()), Err(err)) => err,
+            (Ok(()), Ok(status)) if status & MMC_R1_WRITE_ERRORS != 0 => {
+                warn!("{TAG} write_sectors_dma: programming failed, status {status:#x}");
+                Error::Fail
+            }
+            (Ok(()), Ok(_)) => return Ok(()),
+        };
+
+        let mut blocks_done = 0;
+        if block_count > 1 && !self.is_mmc {
+            match self.cmd_num_of_written_blocks().await {
+                Ok(written) => {
+                    error!("{TAG} write_sectors_dma: {err:?}, {written} of {block_count} blocks written");
+                    blocks_done = (written as u32).min(block_count);
+                }
+                Err(err_acmd22) => {
+                    error!("{TAG} write_sectors_dma: {err:?}, failed to get written blocks ({err_acmd22:?})")
+                }
+            }
+        } else {
+            error!("{TAG} write_sectors_dma: {err:?}");
+        }
+        Err(TransferError { blocks_done, err })
+    }
+
+    /// Poll CMD13 until the card is done programming and ready for data again
+    pub(crate) async fn wait_ready_for_data(&mut self) -> Result<u32, Error> {
+        let t0 = Instant::now();
+        loop {
+            let status = self.cmd_send_status().await.inspect_err(|err| {
+                warn!("{TAG} wait_ready_for_data: send_status returned {err:?}")
+            })?;
+            if status & MMC_R1_READY_FOR_DATA != 0
+                && mmc_r1_current_state(status) != MMC_R1_CURRENT_STATE_PRG
+            {
+                return Ok(status);
+            }
+            let elapsed = t0.elapsed();
+            if elapsed > Duration::from_millis(SDMMC_READY_FOR_DATA_TIMEOUT_MS) {
+                warn!("{TAG} wait_ready_for_data: timeout, status {status:#x}");
+                Err(Error::Timeout)?;
+            }
+            // most writes finish within a few polls, back off for slow ones
+            if elapsed > Duration::from_millis(100) {
+                Timer::after_millis(1).await;
+            }
+        }
     }
 
     pub async fn read_sectors(
         &mut self,
-        blocks: &mut [embedded_sdmmc::Block],
-        start_block_idx: embedded_sdmmc::BlockIdx,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
     ) -> Result<(), Error> {
-        // create dma capable buffer
+        self.read_sectors_split(blocks_as_bytes_mut(blocks), start_block_idx.0)
+            .await
+    }
 
-        todo!()
+    /// Read whole sectors into `dst` starting at `start_block`, see
+    /// [`Self::read_sectors_pipelined`]
+    pub async fn read_sectors_split(
+        &mut self,
+        dst: &mut [u8],
+        start_block: u32,
+    ) -> Result<(), Error> {
+        Ok(self.read_sectors_pipelined(dst, start_block).await?)
+    }
+
+    /// Read whole sectors into `dst` starting at `start_block`, with as many commands as the DMA
+    /// descriptors and buffers need.
+    ///
+    /// Parts of `dst` the DMA can use in place are read straight into it. The others go through
+    /// both DMA buffers in turn, the sectors of one command are copied out while the card sends
+    /// those of the next.
+    pub async fn read_sectors_pipelined(
+        &mut self,
+        dst: &mut [u8],
+        start_block: u32,
+    ) -> Result<(), TransferError> {
+        let sector_size = self.split_sector_size(dst.len())?;
+        let bounce_len = self.dma_rx_buf.capacity().min(self.dma_tx_buf.capacity());
+        let mut block = start_block;
+        let mut done = 0;
+        // sectors read into a DMA buffer and not copied out yet
+        let mut pending = None;
+        let mut staging = Staging::Rx;
+        let mut rest = dst;
+        let mut res = Ok(());
+        while !rest.is_empty() && res.is_ok() {
+            let count = match self.chunk_blocks(rest, bounce_len) {
+                Ok(count) => count,
+                Err(err) => {
+                    res = Err(err);
+                    break;
+                }
+            };
+            let (chunk, tail) =
+                core::mem::take(&mut rest).split_at_mut(count as usize * sector_size);
+            rest = tail;
+            if self.dma_can_use(chunk.as_ptr(), chunk.len()) {
+                done += self.copy_out(pending.take());
+                let len = chunk.len() as u32;
+                res = self.read_sectors_dma(chunk, block, count, len).await;
+                if res.is_ok() {
+                    done += count;
+                }
+            } else {
+                res = self.start_read_sectors(block, count, staging).await;
+                // the card sends this chunk while the one before is copied out
+                done += self.copy_out(pending.take());
+                if res.is_ok() {
+                    res = self.finish_read_sectors(block, count, staging).await;
+                }
+                if res.is_ok() {
+                    pending = Some((chunk, staging));
+                    staging = staging.other();
+                }
+            }
+            block += count;
+        }
+        done += self.copy_out(pending.take());
+        res.map_err(|err| TransferError {
+            blocks_done: done,
+            err,
+        })
+    }
+
+    /// Copy sectors a read left in a DMA buffer to their destination, returns how many
+    fn copy_out(&self, pending: Option<(&mut [u8], Staging)>) -> u32 {
+        let Some((dst, staging)) = pending else {
+            return 0;
+        };
+        dst.copy_from_slice(&self.dma_buf(staging)[..dst.len()]);
+        (dst.len() / self.csd.sector_size as usize) as u32
+    }
+
+    fn check_sector_range(&self, start_block: u32, block_count: u32) -> Result<(), Error> {
+        if block_count == 0
+            || start_block
+                .checked_add(block_count)
+                .map_or(true, |end| end > self.csd.capacity)
+        {
+            warn!(
+                "{TAG} {block_count} sectors at {start_block} out of range, capacity {}",
+                self.csd.capacity
+            );
+            Err(Error::InvalidSize)?;
+        }
+        Ok(())
     }
 
     pub async fn read_sectors_dma(
@@ -275,11 +857,24 @@ impl SdmmcCard {
         block_count: u32,
         buffer_len: u32,
     ) -> Result<(), Error> {
-        // if start_block + block_count > self.csd.capacity {
-        //     Err(Error::InvalidSize)?;
-        // }
-        let block_size = 512; //self.csd.sector_size;
-        let mut cmd = SdmmcCmd {
+        self.check_sector_range(start_block, block_count)?;
+        let mut cmd = self.read_sectors_cmd(Some(dst), start_block, block_count);
+        cmd.buflen = buffer_len;
+
+        let err = self.send_cmd(&mut cmd).await;
+        let err_cmd13 = self.cmd_send_status().await;
+        log_read_error(&err, err_cmd13);
+        err
+    }
+
+    fn read_sectors_cmd<'a>(
+        &self,
+        dst: Option<&'a mut [u8]>,
+        start_block: u32,
+        block_count: u32,
+    ) -> SdmmcCmd<'a> {
+        let block_size = self.csd.sector_size;
+        SdmmcCmd {
             opcode: if block_count == 1 {
                 MMC_READ_BLOCK_SINGLE
             } else {
@@ -287,34 +882,131 @@ impl SdmmcCard {
             },
             flags: SCF_CMD_ADTC | SCF_CMD_READ | SCF_RSP_R1,
             blklen: block_size,
-            data: Some(dst),
+            data: dst,
             datalen: block_count * block_size,
-            buflen: buffer_len,
-            arg: if self.ocr & SD_OCR_SDHC_CAP != 0 {
-                start_block
-            } else {
-                start_block * block_size
-            },
+            buflen: block_count * block_size,
+            arg: self.sector_arg(start_block),
+            timeout_ms: 1000,
             ..Default::default()
-        };
+        }
+    }
 
-        let err = self.send_cmd(&mut cmd).await;
-        let err_cmd13 = self.cmd_send_status().await;
+    /// Start reading sectors into the DMA buffer `staging` and return while the card sends them.
+    ///
+    /// [`Self::finish_read_sectors`] with the same range should follow, the next command stops
+    /// the read otherwise.
+    pub(crate) async fn start_read_sectors(
+        &mut self,
+        start_block: u32,
+        block_count: u32,
+        staging: Staging,
+    ) -> Result<(), Error> {
+        self.check_sector_range(start_block, block_count)?;
+        if (block_count * self.csd.sector_size) as usize > self.dma_buf(staging).len() {
+            Err(Error::InvalidSize)?;
+        }
+        let mut cmd = self.read_sectors_cmd(None, start_block, block_count);
+        cmd.staged = Some(staging);
+        self.start_read(&mut cmd).await
+    }
 
-        if err.is_err() {
-            match err_cmd13 {
-                Ok(status) => {
-                    error!("{TAG} read_sectors_dma: send_cmd returned {err:?}, status {status}")
-                }
-                Err(err) => {
-                    error!("{TAG} read_sectors_dma: send_cmd returned {err:?}, failed to get status ({err_cmd13:?})")
-                }
-            }
+    /// Start reading whole sectors into `dst` and return while the card sends them.
+    ///
+    /// [`Self::finish_read_sectors_into`] with the same range should follow, the next command
+    /// stops the read otherwise.
+    ///
+    /// # Safety
+    ///
+    /// The DMA keeps writing to `dst` after this returns. `dst` must stay in place and untouched
+    /// until [`Self::finish_read_sectors_into`] returned or [`Self::abandon_read`] ran.
+    pub(crate) async unsafe fn start_read_sectors_into(
+        &mut self,
+        dst: &mut [u8],
+        start_block: u32,
+    ) -> Result<(), Error> {
+        let block_count = (dst.len() / self.split_sector_size(dst.len())?) as u32;
+        self.check_sector_range(start_block, block_count)?;
+        let mut cmd = self.read_sectors_cmd(Some(dst), start_block, block_count);
+        self.start_read(&mut cmd).await
+    }
+
+    async fn start_read(&mut self, cmd: &mut SdmmcCmd<'_>) -> Result<(), Error> {
+        if self.power_state != PowerState::Active {
+            warn!("{TAG} read while card is {:?}", self.power_state);
+            Err(Error::InvalidState)?;
         }
+        self.stop_read_in_flight().await;
+        self.start_transaction(cmd).await?;
+        self.read_in_flight = true;
+        Ok(())
+    }
 
+    /// Wait for the sectors of [`Self::start_read_sectors`], which stay in the DMA buffer
+    pub(crate) async fn finish_read_sectors(
+        &mut self,
+        start_block: u32,
+        block_count: u32,
+        staging: Staging,
+    ) -> Result<(), Error> {
+        let mut cmd = self.read_sectors_cmd(None, start_block, block_count);
+        cmd.staged = Some(staging);
+        self.finish_read(&mut cmd).await
+    }
+
+    /// Wait for the sectors of [`Self::start_read_sectors_into`] to be in `dst`
+    pub(crate) async fn finish_read_sectors_into(
+        &mut self,
+        dst: &mut [u8],
+        start_block: u32,
+    ) -> Result<(), Error> {
+        let block_count = dst.len() as u32 / self.csd.sector_size;
+        let mut cmd = self.read_sectors_cmd(Some(dst), start_block, block_count);
+        self.finish_read(&mut cmd).await
+    }
+
+    async fn finish_read(&mut self, cmd: &mut SdmmcCmd<'_>) -> Result<(), Error> {
+        let err = self.finish_transaction(cmd).await;
+        self.read_in_flight = false;
+        let err = err.and(cmd.err.map_or(Ok(()), Err));
+        if err.is_err() {
+            let err_cmd13 = self.cmd_send_status().await;
+            log_read_error(&err, err_cmd13);
+        }
         err
     }
 
+    /// Stop the DMA of a started read that will not be finished, before the buffer it writes to
+    /// goes away. The card itself is stopped by the next command.
+    pub(crate) fn abandon_read(&mut self) {
+        if self.read_in_flight {
+            warn!("{TAG} read abandoned");
+            self.sdmmc.dma_stop();
+        }
+    }
+
+    /// Stop a started read nobody finished, e.g. because the future meant to finish it was
+    /// dropped, so that the next command finds the card in transfer state
+    async fn stop_read_in_flight(&mut self) {
+        if !core::mem::take(&mut self.read_in_flight) {
+            return;
+        }
+        warn!("{TAG} stopping a read left running");
+        self.sdmmc.dma_stop();
+        self.sdmmc.ll_reset_fifo();
+        let cmd = &mut SdmmcCmd {
+            opcode: MMC_STOP_TRANSMISSION,
+            flags: SCF_CMD_AC | SCF_RSP_R1B,
+            ..Default::default()
+        };
+        // Not send_cmd: the stop is itself issued from within send_cmd
+        let res = self.do_transaction(cmd).await;
+        EVENT_QUEUE.clear();
+        if let Err(err) = res.and(cmd.err.map_or(Ok(()), Err)) {
+            // single block reads end by themselves and have nothing to stop
+            debug!("{TAG} stopping the read returned {err:?}");
+        }
+    }
+
     pub async fn erase_sectors(&mut self) -> Result<(), Error> {
         todo!()
     }
@@ -343,3 +1035,16 @@ impl SdmmcCard {
         todo!()
     }
 }
+
+fn log_read_error(err: &Result<(), Error>, err_cmd13: Result<u32, Error>) {
+    if err.is_err() {
+        match err_cmd13 {
+            Ok(status) => {
+                error!("{TAG} read_sectors_dma: send_cmd returned {err:?}, status {status}")
+            }
+            Err(_) => {
+                error!("{TAG} read_sectors_dma: send_cmd returned {err:?}, failed to get status ({err_cmd13:?})")
+            }
+        }
+    }
+}
@@ -0,0 +1,371 @@
+//! Software command queue engine for eMMC 5.1 CMDQ.
+//!
+//! The SDHOST has no command queue engine, so tasks are queued with CMD44/CMD45, the queue status
+//! register is polled with CMD13 and ready tasks are executed with CMD46 (read) or CMD47 (write)
+//! by the callers themselves, each taking the card lock for one command at a time. A task moves
+//! at most one DMA transfer, larger requests are queued as several tasks.
+
+use core::cell::Cell;
+
+use embassy_futures::yield_now;
+use embassy_sync::{
+    blocking_mutex::{self, raw::RawMutex},
+    mutex::Mutex,
+};
+use log::{debug, warn};
+
+use crate::{cmd::SdmmcCmd, common::*, sdmmc_sd::SdmmcCard, Error};
+
+const TAG: &'static str = "[SDMMC_CMDQ]";
+
+impl SdmmcCard {
+    pub fn mmc_can_cmdq(&self) -> bool {
+        self.is_mmc && self.ext_csd.cmdq_support
+    }
+
+    /// Switch the card in or out of command queue mode.
+    ///
+    /// While enabled the card only accepts queued data transfers, see [`CmdQueue`].
+    pub async fn mmc_cmdq_enable(&mut self, en: bool) -> Result<(), Error> {
+        if !self.mmc_can_cmdq() {
+            warn!("{TAG} card does not support command queueing");
+            Err(Error::NotSupported)?;
+        }
+        self.cmd_mmc_switch(
+            EXT_CSD_CMD_SET_NORMAL,
+            EXT_CSD_CMDQ_MODE_EN,
+            if en { EXT_CSD_CMDQ_MODE_ENABLED } else { 0 },
+        )
+        .await
+        .inspect_err(|err| warn!("{TAG} setting CMDQ_MODE_EN returned {err:?}"))?;
+        self.ext_csd.cmdq_enabled = en;
+        Ok(())
+    }
+
+    pub async fn cmd_queue_task(
+        &mut self,
+        task_id: u8,
+        start_block: u32,
+        block_count: u32,
+        read: bool,
+        high_priority: bool,
+    ) -> Result<(), Error> {
+        if block_count == 0 || block_count > MMC_CMDQ_BLOCK_COUNT_MASK {
+            Err(Error::InvalidSize)?;
+        }
+        let mut arg = block_count | ((task_id as u32) << MMC_CMDQ_TASK_ID_POS);
+        if read {
+            arg |= MMC_CMDQ_DATA_DIR_READ;
+        } else if self.reliable_write {
+            arg |= MMC_CMDQ_RELIABLE_WRITE;
+        }
+        if high_priority {
+            arg |= MMC_CMDQ_PRIORITY;
+        }
+        self.send_cmd(&mut SdmmcCmd {
+            opcode: MMC_QUE_TASK_PARAMS,
+            arg,
+            flags: SCF_CMD_AC | SCF_RSP_R1,
+            ..Default::default()
+        })
+        .await?;
+        self.send_cmd(&mut SdmmcCmd {
+            opcode: MMC_QUE_TASK_ADDR,
+            arg: self.sector_arg(start_block),
+            flags: SCF_CMD_AC | SCF_RSP_R1,
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Queue status register: bit n set when task n is ready for execution
+    pub async fn cmd_send_queue_status(&mut self) -> Result<u32, Error> {
+        let cmd = &mut SdmmcCmd {
+            opcode: MMC_SEND_STATUS,
+            arg: ((self.rca as u32) << 16) | MMC_SEND_STATUS_SQS,
+            flags: SCF_CMD_AC | SCF_RSP_R1,
+            ..Default::default()
+        };
+        self.send_cmd(cmd).await?;
+        Ok(cmd.responce[0])
+    }
+
+    pub async fn cmd_execute_read_task(
+        &mut self,
+        task_id: u8,
+        dst: &mut [u8],
+    ) -> Result<(), Error> {
+        let datalen = dst.len() as u32;
+        self.send_cmd(&mut SdmmcCmd {
+            opcode: MMC_EXECUTE_READ_TASK,
+            arg: (task_id as u32) << MMC_CMDQ_TASK_ID_POS,
+            flags: SCF_CMD_ADTC | SCF_CMD_READ | SCF_RSP_R1,
+            blklen: 512,
+            datalen,
+            buflen: datalen,
+            data: Some(dst),
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Send the data of a ready write task and wait until the card programmed it
+    pub async fn cmd_execute_write_task(&mut self, task_id: u8, src: &[u8]) -> Result<(), Error> {
+        let datalen = src.len() as u32;
+        self.send_cmd(&mut SdmmcCmd {
+            opcode: MMC_EXECUTE_WRITE_TASK,
+            arg: (task_id as u32) << MMC_CMDQ_TASK_ID_POS,
+            flags: SCF_CMD_ADTC | SCF_RSP_R1,
+            blklen: 512,
+            datalen,
+            buflen: datalen,
+            tx_data: Some(src),
+            ..Default::default()
+        })
+        .await?;
+        // the card keeps programming after the last block
+        let status = self.wait_ready_for_data().await?;
+        if status & MMC_R1_WRITE_ERRORS != 0 {
+            warn!("{TAG} write task {task_id} failed, status {status:#x}");
+            Err(Error::Fail)?;
+        }
+        Ok(())
+    }
+
+    pub async fn cmd_cmdq_discard_task(&mut self, task_id: u8) -> Result<(), Error> {
+        self.send_cmd(&mut SdmmcCmd {
+            opcode: MMC_CMDQ_TASK_MGMT,
+            arg: ((task_id as u32) << MMC_CMDQ_TASK_ID_POS) | MMC_CMDQ_TM_DISCARD_TASK,
+            flags: SCF_CMD_AC | SCF_RSP_R1B | SCF_WAIT_BUSY,
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub async fn cmd_cmdq_discard_queue(&mut self) -> Result<(), Error> {
+        self.send_cmd(&mut SdmmcCmd {
+            opcode: MMC_CMDQ_TASK_MGMT,
+            arg: MMC_CMDQ_TM_DISCARD_QUEUE,
+            flags: SCF_CMD_AC | SCF_RSP_R1B | SCF_WAIT_BUSY,
+            ..Default::default()
+        })
+        .await
+    }
+}
+
+/// Caller buffer of a task
+enum TaskData<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+impl TaskData<'_> {
+    fn len(&self) -> usize {
+        match self {
+            TaskData::Read(dst) => dst.len(),
+            TaskData::Write(src) => src.len(),
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct TaskMap {
+    /// Task ids handed out to callers
+    allocated: u32,
+    /// Task ids queued on the card by callers that were dropped before executing them
+    orphaned: u32,
+}
+
+/// Shares a card in command queue mode between any number of async callers.
+pub struct CmdQueue<M: RawMutex> {
+    card: Mutex<M, SdmmcCard>,
+    tasks: blocking_mutex::Mutex<M, Cell<TaskMap>>,
+    depth_mask: u32,
+}
+
+impl<M: RawMutex> CmdQueue<M> {
+    /// `card` must already be in command queue mode, see [`SdmmcCard::mmc_cmdq_enable`]
+    pub fn new(card: SdmmcCard) -> Result<Self, Error> {
+        if !card.ext_csd.cmdq_enabled {
+            warn!("{TAG} command queue mode is not enabled");
+            Err(Error::InvalidState)?;
+        }
+        let depth = card.ext_csd.cmdq_depth as u32;
+        Ok(Self {
+            depth_mask: if depth >= 32 {
+                u32::MAX
+            } else {
+                (1 << depth) - 1
+            },
+            card: Mutex::new(card),
+            tasks: blocking_mutex::Mutex::new(Cell::new(TaskMap::default())),
+        })
+    }
+
+    /// Give the card back, it is still in command queue mode
+    pub fn into_inner(self) -> SdmmcCard {
+        self.card.into_inner()
+    }
+
+    /// Queue a read of `dst.len() / 512` blocks and wait until the card executed it
+    pub async fn read(
+        &self,
+        start_block: u32,
+        dst: &mut [u8],
+        high_priority: bool,
+    ) -> Result<(), Error> {
+        Self::check_len(dst.len())?;
+        let mut block = start_block;
+        let mut rest = dst;
+        while !rest.is_empty() {
+            let count = {
+                let card = self.card.lock().await;
+                card.chunk_blocks(rest, card.dma_rx_buf.capacity())?
+            };
+            let (chunk, tail) = core::mem::take(&mut rest).split_at_mut(count as usize * 512);
+            rest = tail;
+            self.run_task(block, TaskData::Read(chunk), high_priority)
+                .await?;
+            block += count;
+        }
+        Ok(())
+    }
+
+    /// Queue a write of `src.len() / 512` blocks and wait until the card programmed it
+    pub async fn write(
+        &self,
+        start_block: u32,
+        src: &[u8],
+        high_priority: bool,
+    ) -> Result<(), Error> {
+        Self::check_len(src.len())?;
+        let mut block = start_block;
+        let mut rest = src;
+        while !rest.is_empty() {
+            let count = {
+                let card = self.card.lock().await;
+                card.chunk_blocks(rest, card.dma_tx_buf.capacity())?
+            };
+            let (chunk, tail) = rest.split_at(count as usize * 512);
+            rest = tail;
+            self.run_task(block, TaskData::Write(chunk), high_priority)
+                .await?;
+            block += count;
+        }
+        Ok(())
+    }
+
+    fn check_len(len: usize) -> Result<(), Error> {
+        if len == 0 || len % 512 != 0 {
+            Err(Error::InvalidSize)?;
+        }
+        Ok(())
+    }
+
+    /// Queue one task over `data` and execute it once the card reports it ready
+    async fn run_task(
+        &self,
+        start_block: u32,
+        mut data: TaskData<'_>,
+        high_priority: bool,
+    ) -> Result<(), Error> {
+        let block_count = (data.len() / 512) as u32;
+        let read = matches!(data, TaskData::Read(_));
+
+        let mut task = self.alloc_task().await;
+        {
+            let mut card = self.card.lock().await;
+            self.discard_orphans(&mut card).await;
+            // Still queued on error: CMD44 may have gone through before CMD45 failed, so the id
+            // is orphaned and discarded before it is handed out again
+            card.cmd_queue_task(task.id, start_block, block_count, read, high_priority)
+                .await?;
+        }
+        debug!(
+            "{TAG} task {} queued, start={start_block} count={block_count}",
+            task.id
+        );
+
+        loop {
+            {
+                let mut card = self.card.lock().await;
+                let qsr = card.cmd_send_queue_status().await?;
+                if qsr & (1 << task.id) != 0 {
+                    task.queued = false;
+                    let res = match &mut data {
+                        TaskData::Read(dst) => card.cmd_execute_read_task(task.id, dst).await,
+                        TaskData::Write(src) => card.cmd_execute_write_task(task.id, src).await,
+                    };
+                    if res.is_err() {
+                        warn!("{TAG} task {} failed {res:?}, discarding", task.id);
+                        let _ = card.cmd_cmdq_discard_task(task.id).await;
+                    }
+                    return res;
+                }
+            }
+            yield_now().await;
+        }
+    }
+
+    async fn alloc_task(&self) -> Task<'_, M> {
+        loop {
+            let id = self.tasks.lock(|tasks| {
+                let mut map = tasks.get();
+                let free = !map.allocated & self.depth_mask;
+                if free == 0 {
+                    return None;
+                }
+                let id = free.trailing_zeros() as u8;
+                map.allocated |= 1 << id;
+                tasks.set(map);
+                Some(id)
+            });
+            if let Some(id) = id {
+                return Task {
+                    queue: self,
+                    id,
+                    queued: true,
+                };
+            }
+            yield_now().await;
+        }
+    }
+
+    async fn discard_orphans(&self, card: &mut SdmmcCard) {
+        let orphaned = self.tasks.lock(|tasks| tasks.get().orphaned);
+        for id in (0..32u8).filter(|id| orphaned & (1 << id) != 0) {
+            if let Err(err) = card.cmd_cmdq_discard_task(id).await {
+                warn!("{TAG} discarding orphaned task {id} returned {err:?}");
+            }
+        }
+        self.tasks.lock(|tasks| {
+            let mut map = tasks.get();
+            map.allocated &= !orphaned;
+            map.orphaned &= !orphaned;
+            tasks.set(map);
+        });
+    }
+}
+
+/// Task id owned by one caller, released when dropped
+struct Task<'a, M: RawMutex> {
+    queue: &'a CmdQueue<M>,
+    id: u8,
+    /// Still sitting in the card queue
+    queued: bool,
+}
+
+impl<M: RawMutex> Drop for Task<'_, M> {
+    fn drop(&mut self) {
+        self.queue.tasks.lock(|tasks| {
+            let mut map = tasks.get();
+            if self.queued {
+                // Caller went away, the next lock holder discards it from the card
+                map.orphaned |= 1 << self.id;
+            } else {
+                map.allocated &= !(1 << self.id);
+            }
+            tasks.set(map);
+        });
+    }
+}
@@ -0,0 +1,201 @@
+//! Shared access to SD combo cards.
+//!
+//! The memory and IO parts of a combo card answer to the same RCA on the same bus, so every
+//! command sequence of either side runs under the lock of one [`SharedCard`]. The memory part is
+//! offered through both block device traits, the IO functions through the `io_*` methods or an
+//! [`SdioDispatcher`](crate::sdmmc_sd::sdio_driver::SdioDispatcher) borrowing
+//! [`ComboCard::shared`]. SDIO interrupts are waited for without the lock, see
+//! [`crate::wait_for_sdio_interrupt`].
+
+use aligned::A4;
+use block_device_driver::BlockDevice;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embedded_sdmmc::{Block, BlockCount, BlockIdx};
+use log::warn;
+
+use crate::{
+    sdmmc_sd::{
+        block::{AlignedBlock, SharedCard, BLOCK_SIZE},
+        io::IoAddressMode,
+        SdmmcCard,
+    },
+    Error,
+};
+
+const TAG: &'static str = "[SDMMC_COMBO]";
+
+/// Combo card shared between block device users and SDIO function users
+pub struct ComboCard<M: RawMutex> {
+    card: SharedCard<M>,
+}
+
+impl<M: RawMutex> ComboCard<M> {
+    /// `card` must be initialized and have both a memory part and IO functions
+    pub fn new(card: SdmmcCard) -> Result<Self, Error> {
+        if !card.is_mem() || !card.is_sdio() {
+            warn!("{TAG} not a combo card");
+            Err(Error::InvalidState)?;
+        }
+        Ok(Self {
+            card: SharedCard::new(card),
+        })
+    }
+
+    pub fn into_inner(self) -> SdmmcCard {
+        self.card.into_inner()
+    }
+
+    /// The card behind its lock, e.g. for an
+    /// [`SdioDispatcher`](crate::sdmmc_sd::sdio_driver::SdioDispatcher) or for sequences that
+    /// must not interleave with other users
+    pub fn shared(&self) -> &SharedCard<M> {
+        &self.card
+    }
+
+    /// Read `dst.len() / 512` blocks from the memory part
+    pub async fn read_blocks(&self, dst: &mut [u8], start_block: u32) -> Result<(), Error> {
+        if dst.is_empty() || dst.len() % BLOCK_SIZE != 0 {
+            Err(Error::InvalidSize)?;
+        }
+        self.card
+            .lock()
+            .await
+            .read_sectors_split(dst, start_block)
+            .await
+    }
+
+    /// Write `src.len() / 512` blocks to the memory part
+    pub async fn write_blocks(&self, src: &[u8], start_block: u32) -> Result<(), Error> {
+        if src.is_empty() || src.len() % BLOCK_SIZE != 0 {
+            Err(Error::InvalidSize)?;
+        }
+        self.card
+            .lock()
+            .await
+            .write_sectors_split(src, start_block)
+            .await
+    }
+
+    pub async fn io_read_byte(&self, func: u8, addr: u32) -> Result<u8, Error> {
+        self.card.lock().await.io_read_byte(func, addr).await
+    }
+
+    pub async fn io_write_byte(&self, func: u8, addr: u32, byte: u8) -> Result<(), Error> {
+        self.card.lock().await.io_write_byte(func, addr, byte).await
+    }
+
+    pub async fn io_read_bytes(
+        &self,
+        func: u8,
+        addr: u32,
+        dst: &mut [u8],
+        mode: IoAddressMode,
+    ) -> Result<(), Error> {
+        self.card
+            .lock()
+            .await
+            .io_read_bytes(func, addr, dst, mode)
+            .await
+    }
+
+    pub async fn io_write_bytes(
+        &self,
+        func: u8,
+        addr: u32,
+        src: &[u8],
+        mode: IoAddressMode,
+    ) -> Result<(), Error> {
+        self.card
+            .lock()
+            .await
+            .io_write_bytes(func, addr, src, mode)
+            .await
+    }
+
+    pub async fn io_read_blocks(
+        &self,
+        func: u8,
+        addr: u32,
+        dst: &mut [u8],
+        mode: IoAddressMode,
+    ) -> Result<(), Error> {
+        self.card
+            .lock()
+            .await
+            .io_read_blocks(func, addr, dst, mode)
+            .await
+    }
+
+    pub async fn io_write_blocks(
+        &self,
+        func: u8,
+        addr: u32,
+        src: &[u8],
+        mode: IoAddressMode,
+    ) -> Result<(), Error> {
+        self.card
+            .lock()
+            .await
+            .io_write_blocks(func, addr, src, mode)
+            .await
+    }
+
+    /// Functions with an interrupt pending, bit n for function n
+    pub async fn io_int_pending(&self) -> Result<u8, Error> {
+        self.card.lock().await.io_int_pending().await
+    }
+
+    fn with_card<R>(&self, f: impl FnOnce(&mut SdmmcCard) -> Result<R, Error>) -> Result<R, Error> {
+        let mut card = self.card.try_lock()?;
+        if card.sector_size() as usize != Block::LEN {
+            warn!("{TAG} sector size {} not supported", card.sector_size());
+            Err(Error::NotSupported)?;
+        }
+        f(&mut card)
+    }
+}
+
+impl<M: RawMutex> BlockDevice<BLOCK_SIZE> for &ComboCard<M> {
+    type Error = Error;
+    type Align = A4;
+
+    async fn read(
+        &mut self,
+        block_address: u32,
+        data: &mut [AlignedBlock],
+    ) -> Result<(), Self::Error> {
+        (&self.card).read(block_address, data).await
+    }
+
+    async fn write(
+        &mut self,
+        block_address: u32,
+        data: &[AlignedBlock],
+    ) -> Result<(), Self::Error> {
+        (&self.card).write(block_address, data).await
+    }
+
+    async fn size(&mut self) -> Result<u64, Self::Error> {
+        (&self.card).size().await
+    }
+}
+
+/// Blocking access for [`embedded_sdmmc::VolumeManager`]. A call made while an async user holds
+/// the card fails with [`Error::InvalidState`] rather than blocking the executor it runs on.
+impl<M: RawMutex> embedded_sdmmc::BlockDevice for ComboCard<M> {
+    type Error = Error;
+
+    fn read(&self, blocks: &mut [Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        self.with_card(|card| embassy_futures::block_on(card.read_sectors(blocks, start_block_idx)))
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        self.with_card(|card| {
+            embassy_futures::block_on(card.write_sectors(blocks, start_block_idx))
+        })
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        self.with_card(|card| Ok(BlockCount(card.capacity())))
+    }
+}
@@ -0,0 +1,140 @@
+//! Card checks and block conversions of the blocking [`SdmmcDevice`](super::SdmmcDevice), free of
+//! the hardware.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex};
+use embedded_sdmmc::{Block, BlockCount};
+use log::warn;
+
+use crate::Error;
+
+const TAG: &'static str = "[SDMMC_DEVICE]";
+
+/// What [`SdmmcDevice`](super::SdmmcDevice) checks on the card, mocked in the tests
+pub(crate) trait DeviceCard {
+    fn sector_size(&self) -> u32;
+    fn capacity(&self) -> u32;
+}
+
+/// Run `f` on the card, failing instead of panicking when a call is already in progress
+pub(crate) fn with_card<C: DeviceCard, R>(
+    card: &Mutex<NoopRawMutex, RefCell<C>>,
+    f: impl FnOnce(&mut C) -> Result<R, Error>,
+) -> Result<R, Error> {
+    card.lock(|card| {
+        let mut card = card.try_borrow_mut().map_err(|_| Error::InvalidState)?;
+        if card.sector_size() as usize != Block::LEN {
+            warn!("{TAG} sector size {} not supported", card.sector_size());
+            Err(Error::NotSupported)?;
+        }
+        f(&mut card)
+    })
+}
+
+pub(crate) fn num_blocks<C: DeviceCard>(
+    card: &Mutex<NoopRawMutex, RefCell<C>>,
+) -> Result<BlockCount, Error> {
+    with_card(card, |card| Ok(BlockCount(card.capacity())))
+}
+
+// Blocks are handed to the DMA as one contiguous byte slice
+const _: () =
+    assert!(core::mem::size_of::<Block>() == Block::LEN && core::mem::align_of::<Block>() == 1);
+
+pub(crate) fn blocks_as_bytes(blocks: &[Block]) -> &[u8] {
+    // SAFETY: Block is a lone byte array, checked above
+    unsafe { core::slice::from_raw_parts(blocks.as_ptr().cast(), blocks.len() * Block::LEN) }
+}
+
+pub(crate) fn blocks_as_bytes_mut(blocks: &mut [Block]) -> &mut [u8] {
+    // SAFETY: Block is a lone byte array, checked above
+    unsafe {
+        core::slice::from_raw_parts_mut(blocks.as_mut_ptr().cast(), blocks.len() * Block::LEN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    struct MockCard {
+        sector_size: u32,
+        capacity: u32,
+    }
+
+    impl DeviceCard for MockCard {
+        fn sector_size(&self) -> u32 {
+            self.sector_size
+        }
+        fn capacity(&self) -> u32 {
+            self.capacity
+        }
+    }
+
+    fn device(sector_size: u32, capacity: u32) -> Mutex<NoopRawMutex, RefCell<MockCard>> {
+        Mutex::new(RefCell::new(MockCard {
+            sector_size,
+            capacity,
+        }))
+    }
+
+    #[test]
+    fn num_blocks_is_the_capacity() {
+        let card = device(512, 7_774_208);
+        assert_eq!(num_blocks(&card).unwrap(), BlockCount(7_774_208));
+    }
+
+    #[test]
+    fn other_sector_sizes_are_not_supported() {
+        let card = device(1024, 1000);
+        assert_eq!(num_blocks(&card), Err(Error::NotSupported));
+        assert_eq!(with_card(&card, |_| Ok(())), Err(Error::NotSupported));
+    }
+
+    #[test]
+    fn reentrant_call_fails() {
+        let card = device(512, 1000);
+        let inner = with_card(&card, |outer| {
+            assert_eq!(outer.capacity, 1000);
+            Ok(with_card(&card, |_| Ok(())))
+        });
+        assert_eq!(inner, Ok(Err(Error::InvalidState)));
+        // The outer call released the card
+        assert_eq!(num_blocks(&card), Ok(BlockCount(1000)));
+    }
+
+    #[test]
+    fn blocks_as_bytes_round_trip() {
+        let mut blocks: Vec<Block> = (0..3u8)
+            .map(|i| {
+                let mut block = Block::new();
+                block.contents.fill(i);
+                block
+            })
+            .collect();
+
+        let bytes = blocks_as_bytes(&blocks);
+        assert_eq!(bytes.len(), 3 * Block::LEN);
+        for (i, chunk) in bytes.chunks(Block::LEN).enumerate() {
+            assert!(chunk.iter().all(|&b| b == i as u8));
+        }
+
+        let bytes = blocks_as_bytes_mut(&mut blocks);
+        bytes[Block::LEN..].fill(0xA5);
+        assert!(blocks[0].contents.iter().all(|&b| b == 0));
+        assert!(blocks[1..]
+            .iter()
+            .all(|block| block.contents.iter().all(|&b| b == 0xA5)));
+    }
+
+    #[test]
+    fn no_blocks_is_no_bytes() {
+        assert!(blocks_as_bytes(&[]).is_empty());
+        assert!(blocks_as_bytes_mut(&mut []).is_empty());
+    }
+}
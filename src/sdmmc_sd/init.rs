@@ -1,8 +1,8 @@
-use log::{trace, warn};
+use log::warn;
 
 use crate::{
     common::{SD_OCR_S18_RA, SD_OCR_SDHC_CAP},
-    sdmmc_sd::SdmmcCard,
+    sdmmc_sd::{mmc::PowerState, SdmmcCard},
     Error,
 };
 
@@ -10,13 +10,15 @@ const TAG: &'static str = "[SDMMC_INIT]";
 
 impl SdmmcCard {
     pub async fn init(&mut self) -> Result<(), Error> {
-        self.is_mmc = true; // for testing
+        // set by init_ocr when the card only answers CMD1
+        self.is_mmc = false;
+        self.power_state = PowerState::Active;
 
         self.fix_host_flags().await?;
 
         self.check_host_function_ptr_integrity().await?;
 
-        // TODO io_reset
+        self.io_reset().await?;
 
         // SD reset - CMD0
         self.cmd_go_idle_state().await?;
@@ -25,21 +27,35 @@ impl SdmmcCard {
         self.init_sd_if_cond().await?;
 
         // CMD5
+        self.init_io().await?;
+
+        if !self.is_mem {
+            // IO only card: no OCR, CID or CSD
+            self.init_rca().await?;
+            self.init_select_card().await?;
+            return self.init_io_card_info().await;
+        }
+
+        // ACMD41
         self.init_ocr().await?;
 
         // Check for UHS-I
-        let is_sdmem = true;
-        let is_uhs1 = is_sdmem && self.ocr & SD_OCR_S18_RA != 0 && self.ocr & SD_OCR_SDHC_CAP != 0;
+        let is_uhs1 =
+            self.is_mem && self.ocr & SD_OCR_S18_RA != 0 && self.ocr & SD_OCR_SDHC_CAP != 0;
         log::info!("{TAG} is_uhs1:{is_uhs1}");
 
-        // CMD2
-        // self.init_cid().await?; // optional
+        // CMD2, on a combo card this also moves the memory part out of the ready state
+        // so that it takes the RCA of the IO part
+        self.raw_cid = self
+            .cmd_all_send_cid()
+            .await
+            .inspect_err(|err| warn!("{TAG} all_send_cid returned {err:?}"))?;
 
         // CMD3
-        // self.init_rca().await?;
+        self.init_rca().await?;
 
         // CMD9
-        // self.init_csd().await?;
+        self.init_csd().await?;
 
         // if self.is_mmc {
         //     self.init_mmc_decode_cid()?;
@@ -47,9 +63,14 @@ impl SdmmcCard {
 
         self.init_select_card().await?;
 
-        let buf = &mut [0u8; 512];
-        self.read_sectors_dma(buf, 2, 1, 512).await?;
-        trace!("{TAG} buf: {buf:?}");
+        if self.is_mmc {
+            self.init_mmc_read_ext_csd().await?;
+        }
+
+        if self.is_sdio {
+            self.init_io_card_info().await?;
+        }
+
         Ok(())
     }
 }
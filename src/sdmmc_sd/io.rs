@@ -1,18 +1,1148 @@
-use crate::{common::*, sdmmc_sd::SdmmcCard, Error};
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::Timer;
+use log::{debug, info, warn};
+
+use crate::{
+    cmd::SdmmcCmd,
+    common::*,
+    sdmmc::idmac::IDMAC_MAX_BUF_LEN,
+    sdmmc_sd::{SdmmcCard, DMA_DESCRIPTORS},
+    Error, Width, EVENT_QUEUE,
+};
 
 const TAG: &'static str = "[SDMMC_IO]";
 
-type CisFunc = fn(*const u8, *mut u8, ()) -> Result<(), Error>;
+/// Tuple handler, called with the function whose chain is walked and the tuple body
+pub type CisFunc = fn(func: u8, body: &[u8], cis: &mut SdioCis) -> Result<(), Error>;
+
+#[derive(Clone, Copy)]
+pub struct CisTup {
+    pub code: u8,
+    pub name: &'static str,
+    pub func: CisFunc,
+}
+
+static CIS_TABLE: [CisTup; 12] = [
+    CisTup::new(CISTPL_CODE_DEVICE, "DEVICE", cis_tuple_func_default),
+    CisTup::new(CISTPL_CODE_CHKSUM, "CHKSUM", cis_tuple_func_default),
+    CisTup::new(CISTPL_CODE_VERS1, "VERS1", cis_tuple_func_vers1),
+    CisTup::new(CISTPL_CODE_ALTSTR, "ALTSTR", cis_tuple_func_default),
+    CisTup::new(CISTPL_CODE_CONFIG, "CONFIG", cis_tuple_func_default),
+    CisTup::new(
+        CISTPL_CODE_CFTABLE_ENTRY,
+        "CFTABLE_ENTRY",
+        cis_tuple_func_default,
+    ),
+    CisTup::new(CISTPL_CODE_MANFID, "MANFID", cis_tuple_func_manfid),
+    CisTup::new(CISTPL_CODE_FUNCID, "FUNCID", cis_tuple_func_funcid),
+    CisTup::new(CISTPL_CODE_FUNCE, "FUNCE", cis_tuple_func_funce),
+    CisTup::new(CISTPL_CODE_SDIO_STD, "SDIO_STD", cis_tuple_func_default),
+    CisTup::new(CISTPL_CODE_SDIO_EXT, "SDIO_EXT", cis_tuple_func_default),
+    CisTup::new(CISTPL_CODE_VENDER_BEGIN, "VENDOR", cis_tuple_func_vendor),
+];
+
+/// Number of application tuple handlers a card can hold
+pub const SDIO_MAX_CIS_HANDLERS: usize = 8;
+
+/// Room kept for the CISTPL_VERS_1 product information strings
+pub const SDIO_CIS_VERS1_SIZE: usize = 64;
 
-struct CisTup {
-    code: u32,
-    name: &'static str,
-    func: CisFunc,
+impl CisTup {
+    pub const fn new(code: u8, name: &'static str, func: CisFunc) -> Self {
+        Self { code, name, func }
+    }
+
+    fn matches(&self, code: u8) -> bool {
+        if self.code == CISTPL_CODE_VENDER_BEGIN {
+            (CISTPL_CODE_VENDER_BEGIN..=CISTPL_CODE_VENDER_END).contains(&code)
+        } else {
+            self.code == code
+        }
+    }
+}
+
+/// Tuples decoded from one CIS chain
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SdioCis {
+    /// TPLMID_MANF, the vendor id
+    pub manf_id: u16,
+    /// TPLMID_CARD, the device id
+    pub card_id: u16,
+    /// TPLFID_FUNCTION, [`CISTPL_FUNCID_SDIO`] for SDIO cards
+    pub func_id: u8,
+    /// FUNCE block size: FN0_BLK_SIZE in the common CIS, MAX_BLK_SIZE for functions
+    pub max_block_size: u16,
+    /// Raw FUNCE MAX_TRAN_SPEED, only in the common CIS
+    pub max_tran_speed: u8,
+    pub vers1_major: u8,
+    pub vers1_minor: u8,
+    vers1: [u8; SDIO_CIS_VERS1_SIZE],
+    vers1_len: usize,
+    /// Bit n set when vendor tuple 0x80 + n was present
+    pub vendor_tuples: u16,
+}
+
+impl Default for SdioCis {
+    fn default() -> Self {
+        Self {
+            manf_id: 0,
+            card_id: 0,
+            func_id: 0,
+            max_block_size: 0,
+            max_tran_speed: 0,
+            vers1_major: 0,
+            vers1_minor: 0,
+            vers1: [0; SDIO_CIS_VERS1_SIZE],
+            vers1_len: 0,
+            vendor_tuples: 0,
+        }
+    }
+}
+
+impl SdioCis {
+    /// Product information strings of CISTPL_VERS_1: manufacturer, product, ...
+    pub fn vers1_strings(&self) -> impl Iterator<Item = &str> {
+        self.vers1[..self.vers1_len]
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| core::str::from_utf8(s).ok())
+    }
+
+    /// MAX_TRAN_SPEED decoded to kHz, 0 if not reported
+    pub fn max_tran_speed_khz(&self) -> u32 {
+        const UNIT_KBIT: [u32; 4] = [100, 1000, 10000, 100000];
+        const VALUE_X10: [u32; 16] = [
+            0, 10, 12, 13, 15, 20, 25, 30, 35, 40, 45, 50, 55, 60, 70, 80,
+        ];
+        let unit = (self.max_tran_speed & 0x07) as usize;
+        let value = ((self.max_tran_speed >> 3) & 0x0F) as usize;
+        match UNIT_KBIT.get(unit) {
+            Some(unit) => unit * VALUE_X10[value] / 10,
+            None => 0,
+        }
+    }
+}
+
+fn cis_tuple_func_default(_func: u8, _body: &[u8], _cis: &mut SdioCis) -> Result<(), Error> {
+    Ok(())
+}
+
+fn cis_tuple_func_manfid(_func: u8, body: &[u8], cis: &mut SdioCis) -> Result<(), Error> {
+    if body.len() < 4 {
+        Err(Error::InvalidSize)?;
+    }
+    cis.manf_id = u16::from_le_bytes([body[0], body[1]]);
+    cis.card_id = u16::from_le_bytes([body[2], body[3]]);
+    Ok(())
+}
+
+fn cis_tuple_func_funcid(_func: u8, body: &[u8], cis: &mut SdioCis) -> Result<(), Error> {
+    cis.func_id = *body.first().ok_or(Error::InvalidSize)?;
+    Ok(())
+}
+
+fn cis_tuple_func_funce(func: u8, body: &[u8], cis: &mut SdioCis) -> Result<(), Error> {
+    match body.first() {
+        Some(&CISTPL_FUNCE_TYPE_FUNC0) if func == 0 => {
+            if body.len() <= CISTPL_FUNCE_FUNC0_MAX_TRAN_SPEED {
+                Err(Error::InvalidSize)?;
+            }
+            let pos = CISTPL_FUNCE_FUNC0_BLK_SIZE;
+            cis.max_block_size = u16::from_le_bytes([body[pos], body[pos + 1]]);
+            cis.max_tran_speed = body[CISTPL_FUNCE_FUNC0_MAX_TRAN_SPEED];
+        }
+        Some(&CISTPL_FUNCE_TYPE_FUNC) if func != 0 => {
+            let pos = CISTPL_FUNCE_FUNC_MAX_BLK_SIZE;
+            if body.len() < pos + 2 {
+                Err(Error::InvalidSize)?;
+            }
+            cis.max_block_size = u16::from_le_bytes([body[pos], body[pos + 1]]);
+        }
+        _ => debug!("{TAG} func {func}: ignoring FUNCE type {:?}", body.first()),
+    }
+    Ok(())
+}
+
+fn cis_tuple_func_vers1(_func: u8, body: &[u8], cis: &mut SdioCis) -> Result<(), Error> {
+    if body.len() < 2 {
+        Err(Error::InvalidSize)?;
+    }
+    cis.vers1_major = body[0];
+    cis.vers1_minor = body[1];
+    // strings are terminated by 0xFF
+    let info = &body[2..];
+    let info = &info[..info.iter().position(|&b| b == 0xFF).unwrap_or(info.len())];
+    let len = info.len().min(SDIO_CIS_VERS1_SIZE);
+    cis.vers1[..len].copy_from_slice(&info[..len]);
+    cis.vers1_len = len;
+    Ok(())
+}
+
+fn cis_tuple_func_vendor(_func: u8, _body: &[u8], _cis: &mut SdioCis) -> Result<(), Error> {
+    // recorded by the chain walker, the contents are vendor specific
+    Ok(())
+}
+
+/// Number of IO functions an SDIO card can have, function 0 excluded
+pub const SDIO_MAX_FUNCTIONS: usize = 7;
+
+/// Largest block size an SDIO function may use
+pub const SDIO_MAX_BLOCK_SIZE: u16 = 2048;
+
+/// Largest block count of one block mode CMD53, 0 would mean an infinite transfer
+const SDIO_MAX_BLOCK_COUNT: u32 = SD_ARG_CMD53_LENGTH_MASK;
+
+static IO_ABORT_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static IO_SUSPEND_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static IO_READ_WAIT_REQUEST: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// Abort the CMD53 transfer currently in flight, e.g. to let a higher priority function through.
+///
+/// Can be called from any task. The task running the transfer aborts it through CCCR ASx and
+/// fails it with [`Error::Interrupted`]. Requests made while no transfer is in flight are dropped.
+pub fn request_io_abort() {
+    IO_ABORT_REQUEST.signal(());
+}
+
+/// Suspend the CMD53 transfer currently in flight, keeping its state on the card.
+///
+/// Can be called from any task. The task running the transfer asks the card to release the bus
+/// (CCCR BR) and fails the transfer with [`Error::Interrupted`] once it has, the function is then
+/// picked up again with [`SdmmcCard::io_resume`]. Cards without suspend/resume (SBS) abort the
+/// transfer instead. Requests made while no transfer is in flight are dropped.
+pub fn request_io_suspend() {
+    IO_SUSPEND_REQUEST.signal(());
+}
+
+/// Assert or release read wait on DAT2, holding the CMD53 read currently in flight between two
+/// blocks.
+///
+/// Can be called from any task. Ignored for writes and for cards without read wait (SRW), and
+/// released when the transfer ends. The hold counts towards the timeout of the transfer.
+pub fn request_io_read_wait(en: bool) {
+    IO_READ_WAIT_REQUEST.signal(en);
+}
+
+/// Request made to the task running a CMD53
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum IoRequest {
+    Abort,
+    Suspend,
+    ReadWait(bool),
+}
+
+pub(crate) async fn wait_io_request() -> IoRequest {
+    match select3(
+        IO_ABORT_REQUEST.wait(),
+        IO_SUSPEND_REQUEST.wait(),
+        IO_READ_WAIT_REQUEST.wait(),
+    )
+    .await
+    {
+        Either3::First(()) => IoRequest::Abort,
+        Either3::Second(()) => IoRequest::Suspend,
+        Either3::Third(en) => IoRequest::ReadWait(en),
+    }
+}
+
+/// Polls of IORx, 10 ms apart, before giving up on a function
+const SDIO_FUNC_READY_RETRIES: u32 = 100;
+
+/// Register address behaviour of CMD53 transfers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IoAddressMode {
+    /// Each byte goes to the next register, for memory like areas
+    Incrementing,
+    /// Every byte goes to the same register, for FIFOs
+    Fixed,
+}
+
+/// Function Basic Registers of one IO function
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct SdioFuncInfo {
+    /// Standard SDIO function interface code, [`FBR_INTERFACE_CODE_EXT`] if extended
+    pub interface_code: u8,
+    pub ext_interface_code: u8,
+    /// Function supports a Code Storage Area
+    pub csa_support: bool,
+    pub cis_ptr: u32,
+    pub block_size: u16,
+    /// Tuples of the function CIS
+    pub cis: SdioCis,
+}
+
+/// SDIO card description read from the CCCR and FBRs during init
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct SdioCardInfo {
+    /// IO OCR reported by CMD5
+    pub ocr: u32,
+    pub num_functions: u8,
+    /// Card also has a memory part
+    pub memory_present: bool,
+    /// CCCR format version
+    pub cccr_revision: u8,
+    /// SDIO specification version, 0 = 1.00 up to 4 = 3.00
+    pub sdio_revision: u8,
+    /// SD physical layer specification version
+    pub sd_revision: u8,
+    /// Raw CCCR card capability register, see `CCCR_CARD_CAP_*`
+    pub card_caps: u8,
+    pub high_speed_support: bool,
+    /// Function 0 block size
+    pub block_size: u16,
+    /// Common CIS pointer
+    pub cis_ptr: u32,
+    /// Tuples of the common CIS
+    pub cis: SdioCis,
+    /// FBR of functions 1 to [`SdioCardInfo::num_functions`]
+    pub functions: [SdioFuncInfo; SDIO_MAX_FUNCTIONS],
+}
+
+impl SdioCardInfo {
+    pub fn low_speed(&self) -> bool {
+        self.card_caps & CCCR_CARD_CAP_LSC != 0
+    }
+
+    pub fn low_speed_4bit(&self) -> bool {
+        self.card_caps & CCCR_CARD_CAP_4BLS != 0
+    }
+
+    pub fn multi_block(&self) -> bool {
+        self.card_caps & CCCR_CARD_CAP_SMB != 0
+    }
+
+    pub fn suspend_resume(&self) -> bool {
+        self.card_caps & CCCR_CARD_CAP_SBS != 0
+    }
+
+    pub fn read_wait(&self) -> bool {
+        self.card_caps & CCCR_CARD_CAP_SRW != 0
+    }
+
+    pub fn direct_command(&self) -> bool {
+        self.card_caps & CCCR_CARD_CAP_SDC != 0
+    }
+
+    /// Function `func` (1-based) info, if the card has it
+    pub fn function(&self, func: u8) -> Option<&SdioFuncInfo> {
+        if func == 0 || func > self.num_functions {
+            None
+        } else {
+            self.functions.get(func as usize - 1)
+        }
+    }
+
+    /// Vendor and device id from the common CISTPL_MANFID, used to pick a function driver
+    pub fn ids(&self) -> (u16, u16) {
+        (self.cis.manf_id, self.cis.card_id)
+    }
+
+    /// Current block size of function `func`, function 0 included
+    pub fn block_size(&self, func: u8) -> Option<u16> {
+        if func == 0 {
+            Some(self.block_size)
+        } else {
+            self.function(func).map(|info| info.block_size)
+        }
+    }
+}
+
+fn check_r5(resp: u32) -> Result<(), Error> {
+    match resp & SD_R5_ERROR_MASK {
+        0 => Ok(()),
+        flags if flags & SD_R5_COM_CRC_ERROR != 0 => Err(Error::InvalidCRC),
+        flags if flags & SD_R5_ILLEGAL_COMMAND != 0 => Err(Error::NotSupported),
+        flags if flags & (SD_R5_FUNCTION_NUMBER | SD_R5_OUT_OF_RANGE) != 0 => {
+            Err(Error::InvalidArg)
+        }
+        _ => Err(Error::Fail),
+    }
 }
 
 impl SdmmcCard {
+    pub fn sdio_info(&self) -> Option<&SdioCardInfo> {
+        if self.is_sdio {
+            Some(&self.sdio)
+        } else {
+            None
+        }
+    }
+
+    /// Reset SDIO (CMD52, RES) before re-initializing IO (CMD5)
+    pub async fn io_reset(&mut self) -> Result<(), Error> {
+        let mut sdio_reset = CCCR_CTL_RES;
+        match self
+            .cmd_io_rw_direct(0, SD_IO_CCCR_CTL, SD_ARG_CMD52_WRITE, &mut sdio_reset)
+            .await
+        {
+            // Non-IO cards are allowed to time out
+            Ok(()) | Err(Error::Timeout) => Ok(()),
+            Err(Error::NotFound) => {
+                debug!("{TAG} io_reset: card not present");
+                Err(Error::NotFound)
+            }
+            Err(err) => {
+                warn!("{TAG} io_reset: unexpected return {err:?}");
+                Err(err)
+            }
+        }
+    }
+
     pub async fn init_io(&mut self) -> Result<(), Error> {
-        // new io file :3
+        // IO_SEND_OP_COND(CMD5), Determine if the card is an IO card.
+        // Non-IO cards will not respond to this command.
+        let ocr = match self.cmd_io_send_op_cond(0).await {
+            Ok(ocr) => ocr,
+            Err(err) => {
+                debug!("{TAG} io_send_op_cond (1) returned {err:?}; not IO card");
+                self.is_sdio = false;
+                self.is_mem = true;
+                return Ok(());
+            }
+        };
+
+        self.sdio.num_functions = sd_io_ocr_num_functions(ocr);
+        self.sdio.memory_present = ocr & SD_IO_OCR_MEM_PRESENT != 0;
+        self.is_mem = self.sdio.memory_present;
+        self.is_sdio = self.sdio.num_functions != 0;
+        debug!(
+            "{TAG} number of IO functions: {}, memory present: {}",
+            self.sdio.num_functions, self.is_mem
+        );
+        if !self.is_sdio {
+            return Ok(());
+        }
+
+        let host_ocr = (MMC_OCR_3_2V_3_3V | MMC_OCR_3_3V_3_4V) & ocr;
+        self.sdio.ocr = self
+            .cmd_io_send_op_cond(host_ocr)
+            .await
+            .inspect_err(|err| warn!("{TAG} io_send_op_cond (2) returned {err:?}"))?;
+
+        // IO only and combo cards are SD cards
+        self.is_mmc = false;
+        Ok(())
+    }
+
+    /// Read the CCCR and the FBR of every function, the card must be selected
+    pub async fn init_io_card_info(&mut self) -> Result<(), Error> {
+        let revision = self.io_read_common(SD_IO_CCCR_REVISION).await?;
+        self.sdio.cccr_revision = revision & 0x0F;
+        self.sdio.sdio_revision = revision >> 4;
+        self.sdio.sd_revision = self.io_read_common(SD_IO_CCCR_SD_REVISION).await? & 0x0F;
+        self.sdio.card_caps = self.io_read_common(SD_IO_CCCR_CARD_CAP).await?;
+        self.sdio.high_speed_support =
+            self.io_read_common(SD_IO_CCCR_HIGHSPEED).await? & CCCR_HIGHSPEED_SUPPORT != 0;
+        self.sdio.cis_ptr = self.io_read_ptr(0, SD_IO_CCCR_CISPTR).await?;
+        self.sdio.block_size = self.io_read_common(SD_IO_CCCR_BLKSIZEL).await? as u16
+            | (self.io_read_common(SD_IO_CCCR_BLKSIZEH).await? as u16) << 8;
+
+        for func in 1..=self.sdio.num_functions {
+            let fbr = sd_io_fbr(func);
+            let interface = self.io_read_common(fbr + SD_IO_FBR_INTERFACE).await?;
+            let ext_interface_code = self.io_read_common(fbr + SD_IO_FBR_EXT_INTERFACE).await?;
+            let cis_ptr = self.io_read_ptr(0, fbr + SD_IO_FBR_CISPTR).await?;
+            let block_size = self.io_read_common(fbr + SD_IO_FBR_BLKSIZEL).await? as u16
+                | (self.io_read_common(fbr + SD_IO_FBR_BLKSIZEH).await? as u16) << 8;
+            self.sdio.functions[func as usize - 1] = SdioFuncInfo {
+                interface_code: interface & FBR_INTERFACE_CODE_MASK,
+                ext_interface_code,
+                csa_support: interface & FBR_INTERFACE_CSA_SUPPORT != 0,
+                cis_ptr,
+                block_size,
+                cis: SdioCis::default(),
+            };
+        }
+
+        for func in 0..=self.sdio.num_functions {
+            if let Err(err) = self.io_parse_cis(func).await {
+                warn!("{TAG} parsing CIS of function {func} returned {err:?}");
+            }
+        }
+
+        for func in 1..=self.sdio.num_functions {
+            let max = self.sdio.functions[func as usize - 1].cis.max_block_size;
+            let size = if max == 0 { 512 } else { max.min(512) };
+            if let Err(err) = self.io_set_block_size(func, size).await {
+                warn!("{TAG} setting function {func} block size returned {err:?}");
+            }
+        }
+
+        info!("{TAG} sdio card {:?}", self.sdio);
+        Ok(())
+    }
+
+    async fn io_read_common(&mut self, reg: u32) -> Result<u8, Error> {
+        let mut byte = 0;
+        self.cmd_io_rw_direct(0, reg, SD_ARG_CMD52_READ, &mut byte)
+            .await
+            .inspect_err(|err| warn!("{TAG} reading CCCR/FBR {reg:#x} returned {err:?}"))?;
+        Ok(byte)
+    }
+
+    /// 24 bit little endian pointer, as used for CIS and CSA
+    async fn io_read_ptr(&mut self, func: u8, reg: u32) -> Result<u32, Error> {
+        let mut ptr = 0;
+        for i in 0..3 {
+            let mut byte = 0;
+            self.cmd_io_rw_direct(func, reg + i, SD_ARG_CMD52_READ, &mut byte)
+                .await?;
+            ptr |= (byte as u32) << (8 * i);
+        }
+        Ok(ptr)
+    }
+
+    pub async fn cmd_io_send_op_cond(&mut self, ocr: u32) -> Result<u32, Error> {
+        let mut cmd;
+        for _ in 0..100 {
+            cmd = SdmmcCmd {
+                opcode: SD_IO_SEND_OP_COND,
+                arg: ocr,
+                flags: SCF_CMD_BCR | SCF_RSP_R4,
+                ..Default::default()
+            };
+            self.send_cmd(&mut cmd).await?;
+            if cmd.responce[0] & SD_IO_OCR_MEM_READY != 0 || ocr == 0 {
+                return Ok(cmd.responce[0]);
+            }
+            Timer::after_millis(10).await;
+        }
+        Err(Error::Timeout)
+    }
+
+    pub(crate) async fn cmd_io_rw_direct(
+        &mut self,
+        func: u8,
+        reg: u32,
+        arg: u32,
+        byte: &mut u8,
+    ) -> Result<(), Error> {
+        let cmd = &mut Self::io_direct_cmd(func, reg, arg, *byte);
+        self.send_cmd(cmd).await?;
+        check_r5(cmd.responce[0])
+            .inspect_err(|err| warn!("{TAG} CMD52 response flags {:#x}", cmd.responce[0]))?;
+        *byte = sd_r5_data(cmd.responce[0]);
+        Ok(())
+    }
+
+    fn io_direct_cmd(func: u8, reg: u32, mut arg: u32, byte: u8) -> SdmmcCmd<'static> {
+        arg |= (func as u32 & SD_ARG_CMD52_FUNC_MASK) << SD_ARG_CMD52_FUNC_SHIFT;
+        arg |= (reg & SD_ARG_CMD52_REG_MASK) << SD_ARG_CMD52_REG_SHIFT;
+        arg |= (byte as u32 & SD_ARG_CMD52_DATA_MASK) << SD_ARG_CMD52_DATA_SHIFT;
+        SdmmcCmd {
+            opcode: SD_IO_RW_DIRECT,
+            arg,
+            flags: SCF_CMD_AC | SCF_RSP_R5,
+            ..Default::default()
+        }
+    }
+
+    /// CMD53 without data attached, `len` bytes in byte mode or `len / blklen` blocks in block mode
+    fn io_ext_cmd(func: u8, reg: u32, mut arg: u32, len: u32, blklen: u32) -> SdmmcCmd<'static> {
+        let count = if arg & SD_ARG_CMD53_BLOCK_MODE != 0 {
+            len / blklen
+        } else {
+            // 512 bytes are encoded as 0
+            len
+        };
+        arg |= (func as u32 & SD_ARG_CMD53_FUNC_MASK) << SD_ARG_CMD53_FUNC_SHIFT;
+        arg |= (reg & SD_ARG_CMD53_REG_MASK) << SD_ARG_CMD53_REG_SHIFT;
+        arg |= (count & SD_ARG_CMD53_LENGTH_MASK) << SD_ARG_CMD53_LENGTH_SHIFT;
+        SdmmcCmd {
+            opcode: SD_IO_RW_EXTENDED,
+            arg,
+            flags: SCF_CMD_ADTC | SCF_RSP_R5,
+            blklen,
+            datalen: len,
+            buflen: len,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) async fn cmd_io_rw_extended(&mut self, cmd: &mut SdmmcCmd<'_>) -> Result<(), Error> {
+        // only a request made while this transfer is in flight applies to it
+        IO_ABORT_REQUEST.reset();
+        IO_SUSPEND_REQUEST.reset();
+        IO_READ_WAIT_REQUEST.reset();
+        let res = self.send_cmd(cmd).await;
+        self.sdmmc.set_read_wait(false);
+        res?;
+        check_r5(cmd.responce[0])
+            .inspect_err(|err| warn!("{TAG} CMD53 response flags {:#x}", cmd.responce[0]))
+    }
+
+    /// Check `func` exists and return its current block size
+    fn io_func_block_size(&self, func: u8) -> Result<u32, Error> {
+        if !self.is_sdio {
+            Err(Error::NotSupported)?;
+        }
+        match self.sdio.block_size(func) {
+            Some(size) => Ok(size as u32),
+            None => {
+                warn!("{TAG} function {func} not present");
+                Err(Error::InvalidArg)
+            }
+        }
+    }
+
+    /// Largest byte mode transfer for `func`, bounded by its block size
+    fn io_max_byte_count(&self, func: u8) -> Result<u32, Error> {
+        let block_size = self.io_func_block_size(func)?;
+        Ok(if block_size == 0 {
+            SD_ARG_CMD53_LENGTH_MAX
+        } else {
+            block_size.min(SD_ARG_CMD53_LENGTH_MAX)
+        })
+    }
+
+    /// Length of the next byte mode chunk.
+    /// The host can't transfer more than 4 bytes unless the length is a multiple of 4,
+    /// so the remaining 1-3 bytes go in a separate transfer.
+    fn io_byte_chunk(remaining: usize, max: u32) -> usize {
+        let chunk = remaining.min(max as usize);
+        if chunk > 4 && chunk % 4 != 0 {
+            chunk & !3
+        } else {
+            chunk
+        }
+    }
+
+    fn io_mode_arg(mode: IoAddressMode) -> u32 {
+        match mode {
+            IoAddressMode::Incrementing => SD_ARG_CMD53_INCREMENT,
+            IoAddressMode::Fixed => 0,
+        }
+    }
+
+    /// Switch the card bus width through the CCCR, then the host to match.
+    ///
+    /// Low speed cards only support 4-bit mode when they report 4BLS.
+    pub async fn io_set_bus_width(&mut self, width: Width) -> Result<(), Error> {
+        if !self.is_sdio {
+            Err(Error::NotSupported)?;
+        }
+        let bus_width = match width {
+            Width::Bit1 => CCCR_BUS_WIDTH_1,
+            Width::Bit4 => {
+                if self.sdio.low_speed() && !self.sdio.low_speed_4bit() {
+                    warn!("{TAG} low speed card without 4-bit support");
+                    Err(Error::NotSupported)?;
+                }
+                CCCR_BUS_WIDTH_4 | CCCR_BUS_WIDTH_CD_DISABLE
+            }
+            Width::Bit8 => Err(Error::NotSupported)?,
+        };
+        let reg = self.io_read_byte(0, SD_IO_CCCR_BUS_WIDTH).await?;
+        let reg = (reg & !(CCCR_BUS_WIDTH_MASK | CCCR_BUS_WIDTH_CD_DISABLE)) | bus_width;
+        self.io_write_byte(0, SD_IO_CCCR_BUS_WIDTH, reg)
+            .await
+            .inspect_err(|err| warn!("{TAG} setting bus width returned {err:?}"))?;
+
+        self.width = width;
+        self.set_bus_width()
+    }
+
+    /// Enable high speed (EHS) when both the card and `max_freq_khz` allow it, and clock the
+    /// card accordingly. Low speed cards are limited to 400 kHz.
+    pub async fn io_enable_hs_mode(&mut self, max_freq_khz: u32) -> Result<(), Error> {
+        if !self.is_sdio {
+            Err(Error::NotSupported)?;
+        }
+        let freq_khz = if self.sdio.low_speed() {
+            SDMMC_FREQ_PROBING
+        } else if max_freq_khz < SDMMC_FREQ_HIGHSPEED || !self.sdio.high_speed_support {
+            SDMMC_FREQ_DEFAULT
+        } else {
+            // EHS and SHS both read back set once high speed is enabled
+            let val = self
+                .io_write_read_byte(0, SD_IO_CCCR_HIGHSPEED, CCCR_HIGHSPEED_ENABLE)
+                .await?;
+            if val & CCCR_HIGHSPEED_SUPPORT != 0 && val & CCCR_HIGHSPEED_ENABLE != 0 {
+                SDMMC_FREQ_HIGHSPEED
+            } else {
+                warn!("{TAG} high speed not enabled, CCCR {val:#x}");
+                SDMMC_FREQ_DEFAULT
+            }
+        };
+
+        self.freq_khz = freq_khz.min(max_freq_khz);
+        debug!("{TAG} card clock {} kHz", self.freq_khz);
+        self.sdmmc.set_card_clk(self.slot, self.freq_khz).await
+    }
+
+    /// Abort the CMD53 transfer of `func` (ASx) and drop what is left of it on the host side
+    pub async fn io_abort(&mut self, func: u8) -> Result<(), Error> {
+        if !self.is_sdio {
+            Err(Error::NotSupported)?;
+        }
+        self.io_send_abort(func).await
+    }
+
+    pub(crate) async fn io_send_abort(&mut self, func: u8) -> Result<(), Error> {
+        let cmd = &mut Self::io_direct_cmd(
+            0,
+            SD_IO_CCCR_CTL,
+            SD_ARG_CMD52_WRITE,
+            func & CCCR_CTL_AS_MASK,
+        );
+        // Not send_cmd: the abort is itself issued from within send_cmd
+        let res = self.do_transaction(cmd).await;
+
+        // the aborted transfer may still have data and events in flight
+        self.sdmmc.dma_stop();
+        self.sdmmc.ll_reset_fifo();
+        EVENT_QUEUE.clear();
+
+        res?;
+        if let Some(err) = cmd.err {
+            warn!("{TAG} abort of function {func} returned {err:?}");
+            Err(err)?;
+        }
+        check_r5(cmd.responce[0])?;
+        self.wait_for_busy_cleared(1000).await
+    }
+
+    /// Ask the card to suspend the CMD53 of `func` still holding the data lines (BR), then drop
+    /// the host side of the transfer once the bus is released (BS)
+    pub(crate) async fn io_send_suspend(&mut self, func: u8) -> Result<(), Error> {
+        // Not send_cmd: the suspend is itself issued from within send_cmd
+        let cmd = &mut Self::io_direct_cmd(
+            0,
+            SD_IO_CCCR_BUS_SUSPEND,
+            SD_ARG_CMD52_WRITE,
+            CCCR_BUS_SUSPEND_BR,
+        );
+        let mut res = self
+            .do_transaction(cmd)
+            .await
+            .and(check_r5(cmd.responce[0]));
+        if res.is_ok() {
+            res = Err(Error::Timeout);
+            for _ in 0..SDIO_FUNC_READY_RETRIES {
+                let cmd = &mut Self::io_direct_cmd(0, SD_IO_CCCR_BUS_SUSPEND, SD_ARG_CMD52_READ, 0);
+                if let Err(err) = self.do_transaction(cmd).await {
+                    res = Err(err);
+                    break;
+                }
+                if sd_r5_data(cmd.responce[0]) & CCCR_BUS_SUSPEND_BS == 0 {
+                    debug!("{TAG} suspended function {func}");
+                    res = Ok(());
+                    break;
+                }
+                Timer::after_millis(1).await;
+            }
+        }
+
+        // the card stopped sending or taking data halfway through the transfer
+        self.sdmmc.dma_stop();
+        self.sdmmc.ll_reset_fifo();
+        EVENT_QUEUE.clear();
+
+        if res.is_err() {
+            warn!("{TAG} function {func} did not release the bus, aborting");
+            self.io_send_abort(func).await?;
+        }
+        res
+    }
+
+    /// Resume the transfer of `func` suspended by [`request_io_suspend`] (FSx). Returns whether
+    /// the function has data left to transfer (DF), in which case the transfer continues on the
+    /// data lines.
+    pub async fn io_resume(&mut self, func: u8) -> Result<bool, Error> {
+        if !self.sdio.suspend_resume() {
+            Err(Error::NotSupported)?;
+        }
+        if func == 0 || self.sdio.function(func).is_none() {
+            Err(Error::InvalidArg)?;
+        }
+        let val = self
+            .io_write_read_byte(0, SD_IO_CCCR_FUNC_SELECT, func)
+            .await?;
+        Ok(val & CCCR_FUNC_SELECT_DF != 0)
+    }
+
+    /// Functions currently executing a command (EXx)
+    pub async fn io_exec_flags(&mut self) -> Result<u8, Error> {
+        self.io_read_byte(0, SD_IO_CCCR_EXEC_FLAGS).await
+    }
+
+    /// Functions ready for a data transfer after a resume (RFx)
+    pub async fn io_ready_flags(&mut self) -> Result<u8, Error> {
+        self.io_read_byte(0, SD_IO_CCCR_READY_FLAGS).await
+    }
+
+    /// Enable or disable function `func` (IOEx), waiting for it to report ready (IORx)
+    pub async fn io_enable_func(&mut self, func: u8, en: bool) -> Result<(), Error> {
+        if func == 0 || self.sdio.function(func).is_none() {
+            warn!("{TAG} function {func} not present");
+            Err(Error::InvalidArg)?;
+        }
+        let mut fn_enable = self.io_read_byte(0, SD_IO_CCCR_FN_ENABLE).await?;
+        if en {
+            fn_enable |= 1 << func;
+        } else {
+            fn_enable &= !(1 << func);
+        }
+        self.io_write_byte(0, SD_IO_CCCR_FN_ENABLE, fn_enable)
+            .await
+            .inspect_err(|err| warn!("{TAG} setting IOE{func} returned {err:?}"))?;
+        if !en {
+            return Ok(());
+        }
+        for _ in 0..SDIO_FUNC_READY_RETRIES {
+            if self.io_read_byte(0, SD_IO_CCCR_FN_READY).await? & (1 << func) != 0 {
+                return Ok(());
+            }
+            Timer::after_millis(10).await;
+        }
+        warn!("{TAG} function {func} not ready");
+        Err(Error::Timeout)
+    }
+
+    /// Enable or disable the interrupt of function `func` (IENx), keeping the master enable
+    /// (IENM) set while any function interrupt is enabled.
+    ///
+    /// Interrupts are then delivered through [`crate::wait_for_sdio_interrupt`].
+    pub async fn io_enable_func_int(&mut self, func: u8, en: bool) -> Result<(), Error> {
+        if func == 0 || self.sdio.function(func).is_none() {
+            warn!("{TAG} function {func} not present");
+            Err(Error::InvalidArg)?;
+        }
+        let mut int_enable = self.io_read_byte(0, SD_IO_CCCR_INT_ENABLE).await?;
+        if en {
+            int_enable |= 1 << func;
+        } else {
+            int_enable &= !(1 << func);
+        }
+        if int_enable & !CCCR_INT_ENABLE_IENM != 0 {
+            int_enable |= CCCR_INT_ENABLE_IENM;
+        } else {
+            int_enable &= !CCCR_INT_ENABLE_IENM;
+        }
+        self.io_write_byte(0, SD_IO_CCCR_INT_ENABLE, int_enable)
+            .await
+            .inspect_err(|err| warn!("{TAG} setting IEN{func} returned {err:?}"))
+    }
+
+    /// Functions with an interrupt pending, bit n for function n
+    pub async fn io_int_pending(&mut self) -> Result<u8, Error> {
+        self.io_read_byte(0, SD_IO_CCCR_INT_PENDING).await
+    }
+
+    /// Attach a handler for tuple `code`, run instead of the built-in one.
+    /// Handlers registered before [`SdmmcCard::init`] see the chains walked during init.
+    pub fn io_register_cis_handler(
+        &mut self,
+        code: u8,
+        name: &'static str,
+        func: CisFunc,
+    ) -> Result<(), Error> {
+        let tup = CisTup::new(code, name, func);
+        if let Some(slot) = self
+            .cis_handlers
+            .iter_mut()
+            .find(|slot| slot.map_or(true, |handler| handler.code == code))
+        {
+            *slot = Some(tup);
+            Ok(())
+        } else {
+            warn!("{TAG} no room for CIS handler {name}");
+            Err(Error::InvalidSize)
+        }
+    }
+
+    fn cis_handler(&self, code: u8) -> Option<CisTup> {
+        self.cis_handlers
+            .iter()
+            .flatten()
+            .find(|handler| handler.code == code)
+            .or_else(|| CIS_TABLE.iter().find(|handler| handler.matches(code)))
+            .copied()
+    }
+
+    /// Walk the CIS chain of `func`, 0 being the common CIS, and store the decoded tuples
+    pub async fn io_parse_cis(&mut self, func: u8) -> Result<(), Error> {
+        let mut addr = if func == 0 {
+            self.sdio.cis_ptr
+        } else {
+            self.sdio.function(func).ok_or(Error::InvalidArg)?.cis_ptr
+        };
+        let mut cis = SdioCis::default();
+        let cis_end = SD_IO_CIS_START + SD_IO_CIS_SIZE;
+        let body = &mut [0u8; 255];
+        loop {
+            if addr < SD_IO_CIS_START || addr >= cis_end {
+                warn!("{TAG} func {func}: CIS address {addr:#x} out of range");
+                Err(Error::InvalidResponce)?;
+            }
+            let code = self.io_read_byte(0, addr).await?;
+            if code == CISTPL_CODE_NULL {
+                addr += 1;
+                continue;
+            }
+            if code == CISTPL_CODE_END {
+                break;
+            }
+            let link = self.io_read_byte(0, addr + 1).await?;
+            if link == CISTPL_LINK_END {
+                break;
+            }
+            let body = &mut body[..link as usize];
+            for (i, byte) in body.iter_mut().enumerate() {
+                *byte = self.io_read_byte(0, addr + 2 + i as u32).await?;
+            }
+
+            if (CISTPL_CODE_VENDER_BEGIN..=CISTPL_CODE_VENDER_END).contains(&code) {
+                cis.vendor_tuples |= 1 << (code - CISTPL_CODE_VENDER_BEGIN);
+            }
+            match self.cis_handler(code) {
+                Some(handler) => {
+                    debug!(
+                        "{TAG} func {func}: tuple {} ({code:#x}), {link} bytes",
+                        handler.name
+                    );
+                    (handler.func)(func, body, &mut cis).inspect_err(|err| {
+                        warn!("{TAG} func {func}: tuple {} returned {err:?}", handler.name)
+                    })?;
+                }
+                None => debug!("{TAG} func {func}: unknown tuple {code:#x}, {link} bytes"),
+            }
+            addr += 2 + link as u32;
+        }
+
+        if func == 0 {
+            self.sdio.cis = cis;
+        } else {
+            self.sdio.functions[func as usize - 1].cis = cis;
+        }
+        Ok(())
+    }
+
+    /// Set the block size of `func` through its FBR, or the CCCR for function 0
+    pub async fn io_set_block_size(&mut self, func: u8, size: u16) -> Result<(), Error> {
+        self.io_func_block_size(func)?;
+        let max = if func == 0 {
+            self.sdio.cis.max_block_size
+        } else {
+            self.sdio.functions[func as usize - 1].cis.max_block_size
+        };
+        if size == 0 || size > SDIO_MAX_BLOCK_SIZE || (max != 0 && size > max) {
+            warn!("{TAG} function {func} block size {size} not supported, max {max}");
+            Err(Error::InvalidArg)?;
+        }
+        let reg = if func == 0 {
+            SD_IO_CCCR_BLKSIZEL
+        } else {
+            sd_io_fbr(func) + SD_IO_FBR_BLKSIZEL
+        };
+        self.io_write_byte(0, reg, size as u8).await?;
+        self.io_write_byte(0, reg + 1, (size >> 8) as u8).await?;
+        if func == 0 {
+            self.sdio.block_size = size;
+        } else {
+            self.sdio.functions[func as usize - 1].block_size = size;
+        }
+        debug!("{TAG} function {func} block size {size}");
+        Ok(())
+    }
+
+    pub async fn io_read_byte(&mut self, func: u8, addr: u32) -> Result<u8, Error> {
+        let mut byte = 0;
+        self.cmd_io_rw_direct(func, addr, SD_ARG_CMD52_READ, &mut byte)
+            .await
+            .inspect_err(|err| {
+                warn!("{TAG} io_read_byte: func {func} addr {addr:#x} returned {err:?}")
+            })?;
+        Ok(byte)
+    }
+
+    pub async fn io_write_byte(&mut self, func: u8, addr: u32, byte: u8) -> Result<(), Error> {
+        let mut byte = byte;
+        self.cmd_io_rw_direct(func, addr, SD_ARG_CMD52_WRITE, &mut byte)
+            .await
+            .inspect_err(|err| {
+                warn!("{TAG} io_write_byte: func {func} addr {addr:#x} returned {err:?}")
+            })
+    }
+
+    /// Write a byte and return the register value read back after the write (RAW)
+    pub async fn io_write_read_byte(&mut self, func: u8, addr: u32, byte: u8) -> Result<u8, Error> {
+        let mut byte = byte;
+        self.cmd_io_rw_direct(
+            func,
+            addr,
+            SD_ARG_CMD52_WRITE | SD_ARG_CMD52_EXCHANGE,
+            &mut byte,
+        )
+        .await
+        .inspect_err(|err| {
+            warn!("{TAG} io_write_read_byte: func {func} addr {addr:#x} returned {err:?}")
+        })?;
+        Ok(byte)
+    }
+
+    /// Byte mode CMD53 read, split in chunks of at most the function block size
+    pub async fn io_read_bytes(
+        &mut self,
+        func: u8,
+        addr: u32,
+        dst: &mut [u8],
+        mode: IoAddressMode,
+    ) -> Result<(), Error> {
+        let max = self.io_max_byte_count(func)?;
+        let mut addr = addr;
+        let mut dst = dst;
+        while !dst.is_empty() {
+            let len = Self::io_byte_chunk(dst.len(), max);
+            let (chunk, rest) = dst.split_at_mut(len);
+            let mut cmd = Self::io_ext_cmd(
+                func,
+                addr,
+                SD_ARG_CMD53_READ | Self::io_mode_arg(mode),
+                len as u32,
+                len as u32,
+            );
+            cmd.flags |= SCF_CMD_READ;
+            cmd.data = Some(chunk);
+            self.cmd_io_rw_extended(&mut cmd).await?;
+            if mode == IoAddressMode::Incrementing {
+                addr += len as u32;
+            }
+            dst = rest;
+        }
+        Ok(())
+    }
+
+    /// Byte mode CMD53 write, split in chunks of at most the function block size
+    pub async fn io_write_bytes(
+        &mut self,
+        func: u8,
+        addr: u32,
+        src: &[u8],
+        mode: IoAddressMode,
+    ) -> Result<(), Error> {
+        let max = self.io_max_byte_count(func)?;
+        let mut addr = addr;
+        let mut src = src;
+        while !src.is_empty() {
+            let len = Self::io_byte_chunk(src.len(), max);
+            let (chunk, rest) = src.split_at(len);
+            let mut cmd = Self::io_ext_cmd(
+                func,
+                addr,
+                SD_ARG_CMD53_WRITE | Self::io_mode_arg(mode),
+                len as u32,
+                len as u32,
+            );
+            cmd.tx_data = Some(chunk);
+            self.cmd_io_rw_extended(&mut cmd).await?;
+            if mode == IoAddressMode::Incrementing {
+                addr += len as u32;
+            }
+            src = rest;
+        }
+        Ok(())
+    }
+
+    /// Block size of `func` if `len` bytes can be moved with block mode CMD53
+    fn io_check_block_transfer(&self, func: u8, len: usize) -> Result<u32, Error> {
+        let block_size = self.io_func_block_size(func)?;
+        if !self.sdio.multi_block() {
+            warn!("{TAG} card does not support block mode");
+            Err(Error::NotSupported)?;
+        }
+        if block_size == 0 || len == 0 || len % block_size as usize != 0 {
+            warn!("{TAG} {len} bytes is not a multiple of block size {block_size}");
+            Err(Error::InvalidSize)?;
+        }
+        Ok(block_size)
+    }
+
+    /// Bytes of the next block mode CMD53 over `buf`, within the CMD53 block count and the DMA
+    /// descriptors, and within `bounce_len` bytes of DMA buffer unless the DMA can use `buf` in
+    /// place
+    fn io_block_chunk(
+        &self,
+        buf: &[u8],
+        block_size: u32,
+        bounce_len: usize,
+    ) -> Result<usize, Error> {
+        let chain_len = DMA_DESCRIPTORS * IDMAC_MAX_BUF_LEN;
+        let dma_len = if self.dma_can_use(buf.as_ptr(), buf.len().min(chain_len)) {
+            chain_len
+        } else {
+            bounce_len.min(chain_len)
+        };
+        let max = ((SDIO_MAX_BLOCK_COUNT * block_size) as usize).min(dma_len);
+        let len = buf.len().min(max - max % block_size as usize);
+        if len == 0 {
+            warn!("{TAG} DMA buffer of {bounce_len} bytes holds no block of {block_size} bytes");
+            Err(Error::InvalidSize)?;
+        }
+        Ok(len)
+    }
+
+    /// Block mode CMD53 read through DMA, `dst` must be a multiple of the function block size
+    pub async fn io_read_blocks(
+        &mut self,
+        func: u8,
+        addr: u32,
+        dst: &mut [u8],
+        mode: IoAddressMode,
+    ) -> Result<(), Error> {
+        let block_size = self.io_check_block_transfer(func, dst.len())?;
+        let mut addr = addr;
+        let mut dst = dst;
+        while !dst.is_empty() {
+            let len = self.io_block_chunk(dst, block_size, self.dma_rx_buf.capacity())?;
+            let (chunk, rest) = dst.split_at_mut(len);
+            let mut cmd = Self::io_ext_cmd(
+                func,
+                addr,
+                SD_ARG_CMD53_READ | SD_ARG_CMD53_BLOCK_MODE | Self::io_mode_arg(mode),
+                len as u32,
+                block_size,
+            );
+            cmd.flags |= SCF_CMD_READ;
+            cmd.data = Some(chunk);
+            self.cmd_io_rw_extended(&mut cmd).await?;
+            if mode == IoAddressMode::Incrementing {
+                addr += len as u32;
+            }
+            dst = rest;
+        }
+        Ok(())
+    }
+
+    /// Block mode CMD53 write through DMA, `src` must be a multiple of the function block size
+    pub async fn io_write_blocks(
+        &mut self,
+        func: u8,
+        addr: u32,
+        src: &[u8],
+        mode: IoAddressMode,
+    ) -> Result<(), Error> {
+        let block_size = self.io_check_block_transfer(func, src.len())?;
+        let mut addr = addr;
+        let mut src = src;
+        while !src.is_empty() {
+            let len = self.io_block_chunk(src, block_size, self.dma_tx_buf.capacity())?;
+            let (chunk, rest) = src.split_at(len);
+            let mut cmd = Self::io_ext_cmd(
+                func,
+                addr,
+                SD_ARG_CMD53_WRITE | SD_ARG_CMD53_BLOCK_MODE | Self::io_mode_arg(mode),
+                len as u32,
+                block_size,
+            );
+            cmd.tx_data = Some(chunk);
+            self.cmd_io_rw_extended(&mut cmd).await?;
+            if mode == IoAddressMode::Incrementing {
+                addr += len as u32;
+            }
+            src = rest;
+        }
         Ok(())
     }
 }
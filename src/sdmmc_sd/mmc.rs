@@ -0,0 +1,818 @@
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use log::{info, warn};
+
+use crate::{cmd::SdmmcCmd, common::*, sdmmc_sd::SdmmcCard, Error, Slot};
+
+const TAG: &'static str = "[SDMMC_MMC]";
+
+/// HPI requests of the card in each slot
+static HPI_REQUEST: [Signal<CriticalSectionRawMutex, ()>; 2] = [Signal::new(), Signal::new()];
+
+/// Ask the card in `slot` to abandon the busy operation currently in flight (erase, cache flush,
+/// BKOPS...).
+///
+/// Can be called from any task. The request is picked up by the task waiting on the card busy
+/// signal, which then sends HPI and fails the interrupted operation with [`Error::Interrupted`].
+/// It is ignored unless HPI was enabled with [`SdmmcCard::mmc_enable_hpi`], and dropped when no
+/// busy operation is in flight.
+pub fn request_hpi(slot: Slot) {
+    HPI_REQUEST[slot.num() as usize].signal(());
+}
+
+pub(crate) fn take_hpi_request(slot: Slot) -> bool {
+    HPI_REQUEST[slot.num() as usize].try_take().is_some()
+}
+
+/// Forget requests made before the busy operation about to start on `slot`
+pub(crate) fn reset_hpi_request(slot: Slot) {
+    HPI_REQUEST[slot.num() as usize].reset();
+}
+
+/// Write protection applied to groups by [`SdmmcCard::mmc_write_protect`]
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum WriteProtect {
+    /// Cleared again with [`SdmmcCard::mmc_write_unprotect`]
+    Temporary,
+    /// Cleared by the next power cycle or hardware reset
+    PowerOn,
+    /// Can never be cleared
+    Permanent,
+}
+
+/// User area layout programmed once at provisioning, all sizes in 512 byte sectors.
+///
+/// Sizes and the enhanced area start must be multiples of the high capacity write protection
+/// group, see [`PartitionLayout::validate`].
+#[derive(Default, PartialEq, Clone, Copy, Debug)]
+pub struct PartitionLayout {
+    /// First sector of the enhanced (pSLC) user area range
+    pub enh_start_sector: u32,
+    /// Length of the enhanced user area range, 0 for none
+    pub enh_sectors: u32,
+    /// Size of general purpose partitions 1 to 4, 0 for none
+    pub gp_sectors: [u32; 4],
+    /// General purpose partitions to be enhanced
+    pub gp_enhanced: [bool; 4],
+}
+
+/// EXT_CSD values a validated [`PartitionLayout`] is committed as
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct PartitionPlan {
+    pub enh_start_addr: u32,
+    pub enh_size_mult: u32,
+    pub gp_size_mult: [u32; 4],
+    pub partitions_attribute: u8,
+    /// User area sectors left once the general purpose partitions are carved out
+    pub user_sectors: u32,
+}
+
+const MMC_SIZE_MULT_MAX: u32 = 0xFF_FFFF;
+
+/// Little endian multi-byte EXT_CSD field
+fn ext_csd_le(ext_csd: &[u8], index: usize, len: usize) -> u32 {
+    ext_csd[index..index + len]
+        .iter()
+        .rev()
+        .fold(0, |acc, b| acc << 8 | *b as u32)
+}
+
+impl PartitionLayout {
+    /// Dry run: check the layout against the card limits in `ext_csd` without touching the card
+    pub fn validate(&self, ext_csd: &[u8], sector_mode: bool) -> Result<PartitionPlan, Error> {
+        let support = ext_csd[EXT_CSD_PARTITIONING_SUPPORT];
+        if support & EXT_CSD_PARTITIONING_EN == 0 {
+            warn!("{TAG} card does not support partitioning");
+            Err(Error::NotSupported)?;
+        }
+        if ext_csd[EXT_CSD_PARTITION_SETTING_COMPLETED] & EXT_CSD_PARTITION_SETTING_DONE != 0 {
+            warn!("{TAG} partitioning already completed");
+            Err(Error::InvalidState)?;
+        }
+
+        let wp_grp_sectors = ext_csd[EXT_CSD_HC_WP_GRP_SIZE] as u32
+            * ext_csd[EXT_CSD_HC_ERASE_GRP_SIZE] as u32
+            * 1024;
+        if wp_grp_sectors == 0 {
+            warn!("{TAG} card reports no high capacity write protection group size");
+            Err(Error::NotSupported)?;
+        }
+        let size_mult = |sectors: u32| -> Result<u32, Error> {
+            if sectors % wp_grp_sectors != 0 {
+                warn!("{TAG} {sectors} sectors is not a multiple of {wp_grp_sectors}");
+                Err(Error::InvalidArg)?;
+            }
+            let mult = sectors / wp_grp_sectors;
+            if mult > MMC_SIZE_MULT_MAX {
+                Err(Error::InvalidSize)?;
+            }
+            Ok(mult)
+        };
+
+        let enh_size_mult = size_mult(self.enh_sectors)?;
+        let mut gp_size_mult = [0u32; 4];
+        for (mult, sectors) in gp_size_mult.iter_mut().zip(self.gp_sectors) {
+            *mult = size_mult(sectors)?;
+        }
+        if self.enh_start_sector % wp_grp_sectors != 0 {
+            warn!(
+                "{TAG} enhanced area start {} not aligned to {wp_grp_sectors}",
+                self.enh_start_sector
+            );
+            Err(Error::InvalidArg)?;
+        }
+
+        let mut partitions_attribute = 0;
+        let mut enh_total_mult = 0;
+        if enh_size_mult != 0 {
+            partitions_attribute |= EXT_CSD_PARTITIONS_ATTRIBUTE_ENH_USR;
+            enh_total_mult += enh_size_mult;
+        }
+        for (i, mult) in gp_size_mult.iter().enumerate() {
+            if self.gp_enhanced[i] && *mult != 0 {
+                partitions_attribute |= EXT_CSD_PARTITIONS_ATTRIBUTE_ENH_GP1 << i;
+                enh_total_mult += mult;
+            }
+        }
+        if partitions_attribute != 0 && support & EXT_CSD_ENH_ATTRIBUTE_EN == 0 {
+            warn!("{TAG} card does not support enhanced partitions");
+            Err(Error::NotSupported)?;
+        }
+        let max_enh_size_mult = ext_csd_le(ext_csd, EXT_CSD_MAX_ENH_SIZE_MULT, 3);
+        if enh_total_mult > max_enh_size_mult {
+            warn!("{TAG} enhanced size mult {enh_total_mult} exceeds max {max_enh_size_mult}");
+            Err(Error::InvalidSize)?;
+        }
+
+        let sec_cnt = ext_csd_le(ext_csd, EXT_CSD_SEC_CNT, 4);
+        let gp_total: u64 = self.gp_sectors.iter().map(|s| *s as u64).sum();
+        let user_sectors = (sec_cnt as u64)
+            .checked_sub(gp_total)
+            .ok_or(Error::InvalidSize)
+            .inspect_err(|_| warn!("{TAG} GP partitions exceed {sec_cnt} sectors"))?
+            as u32;
+        if self.enh_sectors != 0
+            && self.enh_start_sector as u64 + self.enh_sectors as u64 > user_sectors as u64
+        {
+            warn!("{TAG} enhanced area ends past user area of {user_sectors} sectors");
+            Err(Error::InvalidSize)?;
+        }
+
+        Ok(PartitionPlan {
+            enh_start_addr: if sector_mode {
+                self.enh_start_sector
+            } else {
+                self.enh_start_sector * 512
+            },
+            enh_size_mult,
+            gp_size_mult,
+            partitions_attribute,
+            user_sectors,
+        })
+    }
+}
+
+/// eMMC power management state, see [`SdmmcCard::mmc_sleep`]
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum PowerState {
+    Active,
+    /// Card in sleep state (CMD5), only wakes up with [`SdmmcCard::mmc_awake`]
+    Sleep,
+    /// Power off was notified, power may be cut and the card needs a new [`SdmmcCard::init`]
+    PoweredOff,
+}
+
+/// Power off handshake flavour, see [`SdmmcCard::mmc_power_off_notify`]
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum PowerOffNotification {
+    /// Card only saves what is needed to stay consistent, within GENERIC_CMD6_TIME
+    Short,
+    /// Card may also finish its housekeeping, within POWER_OFF_LONG_TIME
+    Long,
+}
+
+/// Background operations urgency level reported by EXT_CSD BKOPS_STATUS
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum BkopsStatus {
+    NotRequired,
+    NonCritical,
+    PerformanceImpacted,
+    Critical,
+}
+
+impl From<u8> for BkopsStatus {
+    fn from(value: u8) -> Self {
+        match value & EXT_CSD_BKOPS_STATUS_MASK {
+            0 => BkopsStatus::NotRequired,
+            1 => BkopsStatus::NonCritical,
+            2 => BkopsStatus::PerformanceImpacted,
+            _ => BkopsStatus::Critical,
+        }
+    }
+}
+
+impl SdmmcCard {
+    pub async fn cmd_send_ext_csd_data(&mut self, out_data: &mut [u8]) -> Result<(), Error> {
+        assert!(out_data.len() == EXT_CSD_MMC_SIZE);
+        let datalen = out_data.len() as u32;
+        self.send_cmd(&mut SdmmcCmd {
+            opcode: MMC_SEND_EXT_CSD,
+            flags: SCF_CMD_ADTC | SCF_CMD_READ | SCF_RSP_R1,
+            blklen: datalen,
+            datalen,
+            buflen: datalen,
+            data: Some(out_data),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub async fn cmd_mmc_switch(&mut self, set: u8, index: usize, value: u8) -> Result<(), Error> {
+        let timeout_ms = self.mmc_cmd6_timeout_ms();
+        self.mmc_switch_timeout(MMC_SWITCH_MODE_WRITE_BYTE, set, index, value, timeout_ms)
+            .await
+    }
+
+    fn mmc_cmd6_timeout_ms(&self) -> u64 {
+        match self.ext_csd.generic_cmd6_time_ms {
+            0 => 1000,
+            ms => ms as u64,
+        }
+    }
+
+    async fn mmc_switch_timeout(
+        &mut self,
+        mode: u32,
+        set: u8,
+        index: usize,
+        value: u8,
+        timeout_ms: u64,
+    ) -> Result<(), Error> {
+        let cmd = &mut SdmmcCmd {
+            opcode: MMC_SWITCH,
+            arg: (mode << 24) | ((index as u32) << 16) | ((value as u32) << 8) | set as u32,
+            flags: SCF_RSP_R1B | SCF_CMD_AC | SCF_WAIT_BUSY,
+            timeout_ms,
+            ..Default::default()
+        };
+        self.send_cmd(cmd).await?;
+
+        // check response bit to see that switch was accepted
+        if cmd.responce[0] & MMC_R1_SWITCH_ERROR != 0 {
+            warn!("{TAG} switch of EXT_CSD[{index}] to {value} rejected");
+            Err(Error::InvalidResponce)?;
+        }
+        Ok(())
+    }
+
+    pub async fn init_mmc_read_ext_csd(&mut self) -> Result<(), Error> {
+        let ext_csd = &mut [0u8; EXT_CSD_MMC_SIZE];
+        self.cmd_send_ext_csd_data(ext_csd)
+            .await
+            .inspect_err(|err| warn!("{TAG} send_ext_csd_data returned {err:?}"))?;
+
+        self.ext_csd.rev = ext_csd[EXT_CSD_REV];
+        self.ext_csd.hpi_features = ext_csd[EXT_CSD_HPI_FEATURES];
+        self.ext_csd.hpi_enabled = ext_csd[EXT_CSD_HPI_MGMT] & EXT_CSD_HPI_MGMT_EN != 0;
+        self.ext_csd.bkops_support = ext_csd[EXT_CSD_BKOPS_SUPPORT] & EXT_CSD_BKOPS_SUPPORTED != 0;
+        self.ext_csd.bkops_en = ext_csd[EXT_CSD_BKOPS_EN];
+        self.ext_csd.out_of_interrupt_time_ms = ext_csd[EXT_CSD_OUT_OF_INTERRUPT_TIME] as u32 * 10;
+        self.ext_csd.generic_cmd6_time_ms = ext_csd[EXT_CSD_GENERIC_CMD6_TIME] as u32 * 10;
+        self.ext_csd.wr_rel_param = ext_csd[EXT_CSD_WR_REL_PARAM];
+        self.ext_csd.rel_wr_sec_c = ext_csd[EXT_CSD_REL_WR_SEC_C];
+        self.ext_csd.erase_group_def = ext_csd[EXT_CSD_ERASE_GROUP_DEF];
+        self.ext_csd.hc_wp_grp_size = ext_csd[EXT_CSD_HC_WP_GRP_SIZE];
+        self.ext_csd.hc_erase_grp_size = ext_csd[EXT_CSD_HC_ERASE_GRP_SIZE];
+        self.ext_csd.power_off_notification = ext_csd[EXT_CSD_POWER_OFF_NOTIFICATION];
+        self.ext_csd.cmdq_support = ext_csd[EXT_CSD_CMDQ_SUPPORT] & EXT_CSD_CMDQ_SUPPORTED != 0;
+        self.ext_csd.cmdq_depth = (ext_csd[EXT_CSD_CMDQ_DEPTH] & EXT_CSD_CMDQ_DEPTH_MASK) + 1;
+        self.ext_csd.cmdq_enabled = ext_csd[EXT_CSD_CMDQ_MODE_EN] & EXT_CSD_CMDQ_MODE_ENABLED != 0;
+        self.ext_csd.power_off_long_time_ms = ext_csd[EXT_CSD_POWER_OFF_LONG_TIME] as u32 * 10;
+        // cards above 2GB only report their size in SEC_COUNT
+        let sectors = ext_csd_le(ext_csd, EXT_CSD_SEC_CNT, 4);
+        if sectors > (2 * 1024 * 1024 * 1024) / 512 {
+            self.csd.capacity = sectors;
+        }
+        // 10us * 2^SLEEP_NOTIFICATION_TIME
+        self.ext_csd.sleep_notification_time_ms =
+            (10u64 << ext_csd[EXT_CSD_SLEEP_NOTIFICATION_TIME].min(23)).div_ceil(1000) as u32;
+        // 100ns * 2^S_A_TIMEOUT
+        self.ext_csd.s_a_timeout_ms =
+            (100u64 << ext_csd[EXT_CSD_S_A_TIMEOUT].min(23)).div_ceil(1_000_000) as u32;
+
+        info!(
+            "{TAG} ext_csd rev={} hpi_features={:#x} bkops_support={} bkops_en={:#x}",
+            self.ext_csd.rev,
+            self.ext_csd.hpi_features,
+            self.ext_csd.bkops_support,
+            self.ext_csd.bkops_en
+        );
+        Ok(())
+    }
+
+    pub fn mmc_can_hpi(&self) -> bool {
+        self.is_mmc && self.ext_csd.hpi_features & EXT_CSD_HPI_SUPPORTED != 0
+    }
+
+    pub async fn mmc_enable_hpi(&mut self) -> Result<(), Error> {
+        if !self.mmc_can_hpi() {
+            warn!("{TAG} card does not support HPI");
+            Err(Error::NotSupported)?;
+        }
+        if self.ext_csd.hpi_enabled {
+            return Ok(());
+        }
+        self.cmd_mmc_switch(
+            EXT_CSD_CMD_SET_NORMAL,
+            EXT_CSD_HPI_MGMT,
+            EXT_CSD_HPI_MGMT_EN,
+        )
+        .await
+        .inspect_err(|err| warn!("{TAG} enabling HPI failed {err:?}"))?;
+
+        // Drop requests made before HPI could be honoured
+        reset_hpi_request(self.slot);
+        self.ext_csd.hpi_enabled = true;
+        Ok(())
+    }
+
+    /// Interrupt the operation keeping the card busy and wait until it is back in transfer state
+    pub async fn mmc_send_hpi(&mut self) -> Result<(), Error> {
+        if !self.ext_csd.hpi_enabled {
+            Err(Error::InvalidState)?;
+        }
+
+        let use_cmd12 = self.ext_csd.hpi_features & EXT_CSD_HPI_IMPL_CMD12 != 0;
+        let cmd = &mut SdmmcCmd {
+            opcode: if use_cmd12 {
+                MMC_STOP_TRANSMISSION
+            } else {
+                MMC_SEND_STATUS
+            },
+            arg: ((self.rca as u32) << 16) | MMC_HPI_ARG,
+            flags: SCF_CMD_AC | if use_cmd12 { SCF_RSP_R1B } else { SCF_RSP_R1 },
+            ..Default::default()
+        };
+        // Not send_cmd: HPI is itself issued from within send_cmd
+        self.do_transaction(cmd).await?;
+        if let Some(err) = cmd.err {
+            warn!("{TAG} HPI returned {err:?}");
+            Err(err)?;
+        }
+
+        let timeout = Duration::from_millis(match self.ext_csd.out_of_interrupt_time_ms {
+            0 => 1000,
+            ms => ms as u64,
+        });
+        let t0 = Instant::now();
+        loop {
+            let status = &mut SdmmcCmd {
+                opcode: MMC_SEND_STATUS,
+                arg: (self.rca as u32) << 16,
+                flags: SCF_CMD_AC | SCF_RSP_R1,
+                ..Default::default()
+            };
+            self.do_transaction(status).await?;
+            if let Some(err) = status.err {
+                Err(err)?;
+            }
+            if mmc_r1_current_state(status.responce[0]) != MMC_R1_CURRENT_STATE_PRG {
+                info!("{TAG} HPI done after {}ms", t0.elapsed().as_millis());
+                return Ok(());
+            }
+            if t0.elapsed() > timeout {
+                warn!("{TAG} card still programming after HPI");
+                Err(Error::Timeout)?;
+            }
+            Timer::after_millis(1).await;
+        }
+    }
+
+    pub fn mmc_can_bkops(&self) -> bool {
+        self.is_mmc && self.ext_csd.bkops_support
+    }
+
+    /// Select who may run background operations: the host (`manual`) or the card itself (`auto`).
+    ///
+    /// On most devices the manual enable bit is persistent across power cycles.
+    pub async fn mmc_enable_bkops(&mut self, manual: bool, auto: bool) -> Result<(), Error> {
+        if !self.mmc_can_bkops() {
+            warn!("{TAG} card does not support BKOPS");
+            Err(Error::NotSupported)?;
+        }
+        if auto && self.ext_csd.rev < EXT_CSD_REV_5_1 {
+            warn!(
+                "{TAG} auto BKOPS needs eMMC 5.1, card rev={}",
+                self.ext_csd.rev
+            );
+            Err(Error::NotSupported)?;
+        }
+
+        let mut value = 0;
+        if manual {
+            value |= EXT_CSD_BKOPS_MANUAL_EN;
+        }
+        if auto {
+            value |= EXT_CSD_BKOPS_AUTO_EN;
+        }
+        self.cmd_mmc_switch(EXT_CSD_CMD_SET_NORMAL, EXT_CSD_BKOPS_EN, value)
+            .await
+            .inspect_err(|err| warn!("{TAG} setting BKOPS_EN failed {err:?}"))?;
+        self.ext_csd.bkops_en = value;
+        Ok(())
+    }
+
+    pub async fn mmc_bkops_status(&mut self) -> Result<BkopsStatus, Error> {
+        if !self.mmc_can_bkops() {
+            Err(Error::NotSupported)?;
+        }
+        let ext_csd = &mut [0u8; EXT_CSD_MMC_SIZE];
+        self.cmd_send_ext_csd_data(ext_csd).await?;
+        Ok(ext_csd[EXT_CSD_BKOPS_STATUS].into())
+    }
+
+    /// Whether the card raised an exception event (e.g. urgent BKOPS) in its last status
+    pub async fn mmc_exception_pending(&mut self) -> Result<bool, Error> {
+        let status = self.cmd_send_status().await?;
+        Ok(status & MMC_R1_EXCEPTION_EVENT != 0)
+    }
+
+    /// Run manual background operations until the card is done or `timeout_ms` passes.
+    ///
+    /// Returns [`Error::Interrupted`] if cut short by [`request_hpi`].
+    pub async fn mmc_start_bkops(&mut self, timeout_ms: u32) -> Result<(), Error> {
+        if self.ext_csd.bkops_en & EXT_CSD_BKOPS_MANUAL_EN == 0 {
+            warn!("{TAG} manual BKOPS not enabled");
+            Err(Error::InvalidState)?;
+        }
+        if self.mmc_bkops_status().await? == BkopsStatus::NotRequired {
+            return Ok(());
+        }
+        self.mmc_switch_timeout(
+            MMC_SWITCH_MODE_WRITE_BYTE,
+            EXT_CSD_CMD_SET_NORMAL,
+            EXT_CSD_BKOPS_START,
+            EXT_CSD_BKOPS_START_MANUAL,
+            timeout_ms as u64,
+        )
+        .await
+        .inspect_err(|err| info!("{TAG} BKOPS ended with {err:?}"))
+    }
+
+    pub fn mmc_can_reliable_write(&self) -> bool {
+        self.is_mmc && self.mmc_reliable_write_granularity() != 0
+    }
+
+    /// Sectors a reliable write length must be a multiple of, 0 if unsupported
+    pub(crate) fn mmc_reliable_write_granularity(&self) -> u32 {
+        if self.ext_csd.wr_rel_param & EXT_CSD_WR_REL_PARAM_EN_REL_WR != 0 {
+            1
+        } else {
+            self.ext_csd.rel_wr_sec_c as u32
+        }
+    }
+
+    /// Issue multi-block writes as reliable writes, so that every sector is either fully old or
+    /// fully new after a power loss
+    pub fn mmc_set_reliable_write(&mut self, en: bool) -> Result<(), Error> {
+        if en && !self.mmc_can_reliable_write() {
+            warn!("{TAG} card does not support reliable write");
+            Err(Error::NotSupported)?;
+        }
+        self.reliable_write = en;
+        Ok(())
+    }
+
+    /// Size of a write protection group in sectors: the high capacity group of eMMC, the CSD
+    /// group of standard capacity SD cards
+    pub fn mmc_wp_group_sectors(&self) -> Result<u32, Error> {
+        let group = if self.is_mmc {
+            if self.ext_csd.erase_group_def & EXT_CSD_ERASE_GROUP_DEF_EN == 0 {
+                warn!(
+                    "{TAG} write protection group size only known for high capacity erase groups"
+                );
+                Err(Error::NotSupported)?;
+            }
+            self.ext_csd.hc_wp_grp_size as u32 * self.ext_csd.hc_erase_grp_size as u32 * 1024
+        } else {
+            self.csd.wp_grp_sectors
+        };
+        if group == 0 {
+            warn!("{TAG} card has no write protection groups");
+            Err(Error::NotSupported)?;
+        }
+        Ok(group)
+    }
+
+    /// End of the group aligned range, which must lie on the card
+    fn wp_range_end(&self, start_sector: u32, sector_count: u32, group: u32) -> Result<u32, Error> {
+        if start_sector % group != 0 || sector_count % group != 0 {
+            Err(Error::InvalidArg)?;
+        }
+        match start_sector.checked_add(sector_count) {
+            Some(end) if end <= self.csd.capacity => Ok(end),
+            _ => {
+                warn!("{TAG} {sector_count} sectors at {start_sector} beyond the card");
+                Err(Error::InvalidArg)
+            }
+        }
+    }
+
+    /// Write protect the user area groups covering `sector_count` sectors from `start_sector`.
+    ///
+    /// Both must be aligned to [`SdmmcCard::mmc_wp_group_sectors`]. SD cards only have
+    /// [`WriteProtect::Temporary`].
+    pub async fn mmc_write_protect(
+        &mut self,
+        start_sector: u32,
+        sector_count: u32,
+        kind: WriteProtect,
+    ) -> Result<(), Error> {
+        let group = self.mmc_wp_group_sectors()?;
+        let end = self.wp_range_end(start_sector, sector_count, group)?;
+        if !self.is_mmc && kind != WriteProtect::Temporary {
+            warn!("{TAG} SD cards only have temporary write protection");
+            Err(Error::NotSupported)?;
+        }
+
+        let user_wp = match kind {
+            WriteProtect::Temporary => 0,
+            WriteProtect::PowerOn => EXT_CSD_USER_WP_US_PWR_WP_EN,
+            WriteProtect::Permanent => EXT_CSD_USER_WP_US_PERM_WP_EN,
+        };
+        if user_wp != 0 {
+            self.cmd_mmc_switch_bits(EXT_CSD_USER_WP, user_wp, true)
+                .await
+                .inspect_err(|err| warn!("{TAG} setting USER_WP failed {err:?}"))?;
+        }
+
+        let mut res = Ok(());
+        for sector in (start_sector..end).step_by(group as usize) {
+            res = self.cmd_set_write_prot(sector).await;
+            if res.is_err() {
+                warn!("{TAG} set_write_prot at {sector} returned {res:?}");
+                break;
+            }
+        }
+
+        // Leave later CMD28s temporary
+        if user_wp != 0 {
+            self.cmd_mmc_switch_bits(EXT_CSD_USER_WP, user_wp, false)
+                .await?;
+        }
+        res
+    }
+
+    /// Clear temporary write protection of the groups covering the range
+    pub async fn mmc_write_unprotect(
+        &mut self,
+        start_sector: u32,
+        sector_count: u32,
+    ) -> Result<(), Error> {
+        let group = self.mmc_wp_group_sectors()?;
+        let end = self.wp_range_end(start_sector, sector_count, group)?;
+        for sector in (start_sector..end).step_by(group as usize) {
+            self.cmd_clr_write_prot(sector).await?;
+        }
+        Ok(())
+    }
+
+    /// Write protect both boot partitions, until power cycle or forever
+    pub async fn mmc_boot_write_protect(&mut self, permanent: bool) -> Result<(), Error> {
+        if !self.is_mmc {
+            Err(Error::NotSupported)?;
+        }
+        let bits = if permanent {
+            EXT_CSD_BOOT_WP_B_PERM_WP_EN
+        } else {
+            EXT_CSD_BOOT_WP_B_PWR_WP_EN
+        };
+        self.cmd_mmc_switch_bits(EXT_CSD_BOOT_WP, bits, true).await
+    }
+
+    async fn cmd_mmc_switch_bits(
+        &mut self,
+        index: usize,
+        bits: u8,
+        set: bool,
+    ) -> Result<(), Error> {
+        let timeout_ms = self.mmc_cmd6_timeout_ms();
+        let mode = if set {
+            MMC_SWITCH_MODE_SET_BITS
+        } else {
+            MMC_SWITCH_MODE_CLEAR_BITS
+        };
+        self.mmc_switch_timeout(mode, EXT_CSD_CMD_SET_NORMAL, index, bits, timeout_ms)
+            .await
+    }
+}
+
+impl SdmmcCard {
+    /// Validate `layout` against the card without programming anything
+    pub async fn mmc_validate_partitions(
+        &mut self,
+        layout: &PartitionLayout,
+    ) -> Result<PartitionPlan, Error> {
+        if !self.is_mmc {
+            Err(Error::NotSupported)?;
+        }
+        let ext_csd = &mut [0u8; EXT_CSD_MMC_SIZE];
+        self.cmd_send_ext_csd_data(ext_csd).await?;
+        layout.validate(ext_csd, self.ocr & MMC_OCR_SECTOR_MODE != 0)
+    }
+
+    /// Program `layout` and set PARTITION_SETTING_COMPLETED.
+    ///
+    /// This can only be done once in the lifetime of the device and takes effect after the next
+    /// power cycle.
+    pub async fn mmc_commit_partitions(
+        &mut self,
+        layout: &PartitionLayout,
+    ) -> Result<PartitionPlan, Error> {
+        let plan = self.mmc_validate_partitions(layout).await?;
+        info!("{TAG} committing partition layout {plan:?}");
+
+        // Sizes are expressed in high capacity groups
+        self.cmd_mmc_switch(
+            EXT_CSD_CMD_SET_NORMAL,
+            EXT_CSD_ERASE_GROUP_DEF,
+            EXT_CSD_ERASE_GROUP_DEF_EN,
+        )
+        .await?;
+        self.ext_csd.erase_group_def = EXT_CSD_ERASE_GROUP_DEF_EN;
+
+        for (i, b) in plan.enh_start_addr.to_le_bytes().into_iter().enumerate() {
+            self.cmd_mmc_switch(EXT_CSD_CMD_SET_NORMAL, EXT_CSD_ENH_START_ADDR + i, b)
+                .await?;
+        }
+        for i in 0..3 {
+            let b = (plan.enh_size_mult >> (8 * i)) as u8;
+            self.cmd_mmc_switch(EXT_CSD_CMD_SET_NORMAL, EXT_CSD_ENH_SIZE_MULT + i, b)
+                .await?;
+        }
+        for (gp, mult) in plan.gp_size_mult.into_iter().enumerate() {
+            for i in 0..3 {
+                let b = (mult >> (8 * i)) as u8;
+                self.cmd_mmc_switch(EXT_CSD_CMD_SET_NORMAL, EXT_CSD_GP_SIZE_MULT + gp * 3 + i, b)
+                    .await?;
+            }
+        }
+        self.cmd_mmc_switch(
+            EXT_CSD_CMD_SET_NORMAL,
+            EXT_CSD_PARTITIONS_ATTRIBUTE,
+            plan.partitions_attribute,
+        )
+        .await?;
+
+        self.cmd_mmc_switch(
+            EXT_CSD_CMD_SET_NORMAL,
+            EXT_CSD_PARTITION_SETTING_COMPLETED,
+            EXT_CSD_PARTITION_SETTING_DONE,
+        )
+        .await
+        .inspect_err(|err| warn!("{TAG} setting PARTITION_SETTING_COMPLETED failed {err:?}"))?;
+
+        warn!("{TAG} partition layout takes effect after the next power cycle");
+        Ok(plan)
+    }
+}
+
+impl SdmmcCard {
+    pub fn mmc_power_state(&self) -> PowerState {
+        self.power_state
+    }
+
+    /// Tell the card that the host will notify it before cutting power
+    pub async fn mmc_enable_power_off_notify(&mut self) -> Result<(), Error> {
+        if !self.is_mmc || self.ext_csd.rev < EXT_CSD_REV_4_5 {
+            warn!("{TAG} power off notification needs eMMC 4.5");
+            Err(Error::NotSupported)?;
+        }
+        self.cmd_mmc_switch(
+            EXT_CSD_CMD_SET_NORMAL,
+            EXT_CSD_POWER_OFF_NOTIFICATION,
+            EXT_CSD_POWER_ON,
+        )
+        .await
+        .inspect_err(|err| warn!("{TAG} setting POWER_OFF_NOTIFICATION failed {err:?}"))?;
+        self.ext_csd.power_off_notification = EXT_CSD_POWER_ON;
+        Ok(())
+    }
+
+    /// Prepare the card for power removal, the card clock is stopped once it is done.
+    ///
+    /// Power can be cut when this returns. The card must be initialized again afterwards.
+    pub async fn mmc_power_off_notify(&mut self, kind: PowerOffNotification) -> Result<(), Error> {
+        if self.ext_csd.power_off_notification != EXT_CSD_POWER_ON {
+            warn!("{TAG} power off notification not enabled");
+            Err(Error::InvalidState)?;
+        }
+        let (value, timeout_ms) = match kind {
+            PowerOffNotification::Short => (EXT_CSD_POWER_OFF_SHORT, self.mmc_cmd6_timeout_ms()),
+            PowerOffNotification::Long => (
+                EXT_CSD_POWER_OFF_LONG,
+                match self.ext_csd.power_off_long_time_ms {
+                    0 => 1000,
+                    ms => ms as u64,
+                },
+            ),
+        };
+        self.sdmmc.set_clk_always_on(self.slot, true).await;
+        let res = self
+            .mmc_switch_timeout(
+                MMC_SWITCH_MODE_WRITE_BYTE,
+                EXT_CSD_CMD_SET_NORMAL,
+                EXT_CSD_POWER_OFF_NOTIFICATION,
+                value,
+                timeout_ms,
+            )
+            .await;
+        self.sdmmc.set_clk_always_on(self.slot, false).await;
+        res.inspect_err(|err| warn!("{TAG} power off notification returned {err:?}"))?;
+
+        self.ext_csd.power_off_notification = value;
+        self.power_state = PowerState::PoweredOff;
+        self.sdmmc.gate_card_clk(self.slot, true).await
+    }
+
+    /// Deselect the card and put it to sleep (CMD5), then stop the card clock
+    pub async fn mmc_sleep(&mut self) -> Result<(), Error> {
+        if !self.is_mmc {
+            Err(Error::NotSupported)?;
+        }
+        if self.power_state != PowerState::Active {
+            Err(Error::InvalidState)?;
+        }
+
+        if self.ext_csd.power_off_notification == EXT_CSD_POWER_ON
+            && self.ext_csd.rev >= EXT_CSD_REV_5_0
+        {
+            self.mmc_switch_timeout(
+                MMC_SWITCH_MODE_WRITE_BYTE,
+                EXT_CSD_CMD_SET_NORMAL,
+                EXT_CSD_POWER_OFF_NOTIFICATION,
+                EXT_CSD_SLEEP_NOTIFICATION,
+                self.ext_csd.sleep_notification_time_ms.max(1) as u64,
+            )
+            .await
+            .inspect_err(|err| warn!("{TAG} sleep notification returned {err:?}"))?;
+            self.ext_csd.power_off_notification = EXT_CSD_SLEEP_NOTIFICATION;
+        }
+
+        self.cmd_select_card(0)
+            .await
+            .inspect_err(|err| warn!("{TAG} mmc_sleep: deselect returned {err:?}"))?;
+
+        self.sdmmc.set_clk_always_on(self.slot, true).await;
+        let res = self.cmd_sleep_awake(true).await;
+        self.sdmmc.set_clk_always_on(self.slot, false).await;
+        res?;
+
+        self.power_state = PowerState::Sleep;
+        self.sdmmc.gate_card_clk(self.slot, true).await
+    }
+
+    /// Restart the card clock, wake the card up (CMD5) and select it again
+    pub async fn mmc_awake(&mut self) -> Result<(), Error> {
+        if self.power_state != PowerState::Sleep {
+            Err(Error::InvalidState)?;
+        }
+
+        self.sdmmc.gate_card_clk(self.slot, false).await?;
+        self.sdmmc.set_clk_always_on(self.slot, true).await;
+        let res = self.cmd_sleep_awake(false).await;
+        self.sdmmc.set_clk_always_on(self.slot, false).await;
+        res?;
+        self.power_state = PowerState::Active;
+
+        self.cmd_select_card(self.rca as u32)
+            .await
+            .inspect_err(|err| warn!("{TAG} mmc_awake: select returned {err:?}"))?;
+
+        if self.ext_csd.power_off_notification == EXT_CSD_SLEEP_NOTIFICATION {
+            self.cmd_mmc_switch(
+                EXT_CSD_CMD_SET_NORMAL,
+                EXT_CSD_POWER_OFF_NOTIFICATION,
+                EXT_CSD_POWER_ON,
+            )
+            .await?;
+            self.ext_csd.power_off_notification = EXT_CSD_POWER_ON;
+        }
+        Ok(())
+    }
+
+    pub async fn cmd_sleep_awake(&mut self, sleep: bool) -> Result<(), Error> {
+        let mut arg = (self.rca as u32) << 16;
+        if sleep {
+            arg |= MMC_SLEEP_AWAKE_SLEEP;
+        }
+        self.send_cmd(&mut SdmmcCmd {
+            opcode: MMC_SLEEP_AWAKE,
+            arg,
+            flags: SCF_CMD_AC | SCF_RSP_R1B | SCF_WAIT_BUSY,
+            timeout_ms: self.ext_csd.s_a_timeout_ms.max(1) as u64,
+            ..Default::default()
+        })
+        .await
+    }
+}
@@ -0,0 +1,1145 @@
+//! MBR and GPT partition tables.
+//!
+//! [`PartitionTable::read`] parses the table of a block device: an MBR including the chain of
+//! logical partitions in its extended partition, or the GPT behind a protective MBR. GPT header
+//! and entry CRCs are checked, and the backup GPT at the end of the device is used when the
+//! primary one is damaged. [`PartitionDevice`] turns one partition into a bounds checked block
+//! device of its own.
+//!
+//! Tables can also be created, edited in memory and written back with [`PartitionTable::write`].
+//! Logical partitions are read only, an MBR with an extended partition is not written back.
+
+use core::fmt;
+
+use aligned::{Aligned, A4};
+use block_device_driver::BlockDevice;
+use log::{debug, warn};
+
+use crate::{
+    sdmmc_sd::block::{AlignedBlock, BLOCK_SIZE},
+    Error,
+};
+
+const TAG: &'static str = "[SDMMC_PART]";
+
+pub(crate) const MBR_DISK_SIGNATURE_OFFSET: usize = 440;
+pub(crate) const MBR_SIGNATURE_OFFSET: usize = 510;
+pub(crate) const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+pub(crate) const MBR_ENTRIES_OFFSET: usize = 446;
+pub(crate) const MBR_ENTRY_SIZE: usize = 16;
+pub(crate) const MBR_NUM_ENTRIES: usize = 4;
+pub(crate) const MBR_BOOTABLE: u8 = 0x80;
+/// Logical partitions followed at most, guards against EBR loops
+const MBR_MAX_LOGICAL: usize = 128;
+
+pub const MBR_TYPE_EMPTY: u8 = 0x00;
+pub const MBR_TYPE_EXTENDED_CHS: u8 = 0x05;
+pub const MBR_TYPE_FAT16: u8 = 0x06;
+pub const MBR_TYPE_FAT32_LBA: u8 = 0x0C;
+pub const MBR_TYPE_EXTENDED_LBA: u8 = 0x0F;
+pub const MBR_TYPE_LINUX: u8 = 0x83;
+pub const MBR_TYPE_EXTENDED_LINUX: u8 = 0x85;
+pub const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
+pub(crate) const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+pub(crate) const GPT_REVISION_1_0: u32 = 0x0001_0000;
+pub(crate) const GPT_HEADER_LBA: u32 = 1;
+pub(crate) const GPT_HEADER_SIZE: usize = 92;
+pub(crate) const GPT_ENTRY_SIZE: usize = 128;
+pub(crate) const GPT_HEADER_CRC_OFFSET: usize = 16;
+/// Entries written by [`PartitionTable::write`], the minimum the specification allows
+pub(crate) const GPT_NUM_ENTRIES: usize = 128;
+/// Blocks of a GPT entry array of [`GPT_NUM_ENTRIES`]
+const GPT_ENTRIES_BLOCKS: u32 = (GPT_NUM_ENTRIES * GPT_ENTRY_SIZE / BLOCK_SIZE) as u32;
+/// CHS address telling that only the LBA fields are meaningful
+const MBR_CHS_LBA_ONLY: [u8; 3] = [0xFE, 0xFF, 0xFF];
+
+async fn device_blocks<D>(device: &mut D) -> Result<u32, Error>
+where
+    D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>,
+{
+    u32::try_from(device.size().await? / BLOCK_SIZE as u64).map_err(|_| Error::NotSupported)
+}
+
+const fn is_extended(kind: u8) -> bool {
+    matches!(
+        kind,
+        MBR_TYPE_EXTENDED_CHS | MBR_TYPE_EXTENDED_LBA | MBR_TYPE_EXTENDED_LINUX
+    )
+}
+
+pub(crate) fn le_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+pub(crate) fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+pub(crate) fn le_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC32 of GPT, continue a running `!crc` over `data`
+pub(crate) fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, b| {
+        CRC32_TABLE[((crc ^ *b as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+/// GUID in its on-disk byte order, the first three fields little endian
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const ZERO: Guid = Guid([0; 16]);
+
+    /// From the fields of the textual form `d1-d2-d3-d4[0..2]-d4[2..8]`
+    pub const fn from_fields(d1: u32, d2: u16, d3: u16, d4: [u8; 8]) -> Self {
+        let a = d1.to_le_bytes();
+        let b = d2.to_le_bytes();
+        let c = d3.to_le_bytes();
+        Guid([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d4[0], d4[1], d4[2], d4[3], d4[4],
+            d4[5], d4[6], d4[7],
+        ])
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Self::ZERO
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let g = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            le_u32(g, 0),
+            le_u16(g, 4),
+            le_u16(g, 6),
+            g[8],
+            g[9]
+        )?;
+        g[10..].iter().try_for_each(|b| write!(f, "{b:02X}"))
+    }
+}
+
+/// EFI system partition
+pub const GPT_TYPE_EFI_SYSTEM: Guid = Guid::from_fields(
+    0xC12A7328,
+    0xF81F,
+    0x11D2,
+    [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B],
+);
+/// FAT and exFAT volumes
+pub const GPT_TYPE_BASIC_DATA: Guid = Guid::from_fields(
+    0xEBD0A0A2,
+    0xB9E5,
+    0x4433,
+    [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7],
+);
+pub const GPT_TYPE_LINUX_FS: Guid = Guid::from_fields(
+    0x0FC63DAF,
+    0x8483,
+    0x4772,
+    [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4],
+);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PartitionType {
+    /// MBR system id
+    Mbr(u8),
+    /// GPT partition type GUID
+    Gpt(Guid),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Partition {
+    /// First block
+    pub start: u32,
+    pub num_blocks: u32,
+    pub kind: PartitionType,
+    /// Unique partition GUID, zero on MBR
+    pub guid: Guid,
+    /// GPT attribute bits, bit 7 of the boot indicator on MBR
+    pub attributes: u64,
+}
+
+impl Partition {
+    /// Block after the last one, wide enough not to overflow
+    pub fn end(&self) -> u64 {
+        self.start as u64 + self.num_blocks as u64
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scheme {
+    Mbr { disk_signature: u32 },
+    Gpt { disk_guid: Guid },
+}
+
+/// Up to `N` partitions of a device, in table order.
+///
+/// Tables are read from a device with [`Self::read`] or created empty with [`Self::new_mbr`] or
+/// [`Self::new_gpt`], edited in memory and stored with [`Self::write`]. Partition indices are
+/// table slots and stay stable when other partitions are removed.
+#[derive(Clone, Debug)]
+pub struct PartitionTable<const N: usize> {
+    pub scheme: Scheme,
+    device_blocks: u32,
+    first_usable: u32,
+    last_usable: u32,
+    partitions: [Option<Partition>; N],
+    /// Read from an MBR with an extended partition, whose logical partitions are in `partitions`
+    extended: bool,
+}
+
+struct GptHeader {
+    my_lba: u64,
+    first_usable: u64,
+    last_usable: u64,
+    disk_guid: Guid,
+    entries_lba: u64,
+    num_entries: u32,
+    entry_size: u32,
+    entries_crc: u32,
+}
+
+impl<const N: usize> PartitionTable<N> {
+    pub async fn read<D>(device: &mut D) -> Result<Self, Error>
+    where
+        D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>,
+    {
+        let device_blocks = device_blocks(device).await?;
+        let buf = &mut Aligned([0; BLOCK_SIZE]);
+        device.read(0, core::slice::from_mut(buf)).await?;
+        if buf[MBR_SIGNATURE_OFFSET..] != MBR_SIGNATURE {
+            warn!("{TAG} no MBR signature");
+            Err(Error::NotFound)?;
+        }
+        let protective = (0..MBR_NUM_ENTRIES)
+            .any(|i| buf[MBR_ENTRIES_OFFSET + i * MBR_ENTRY_SIZE + 4] == MBR_TYPE_GPT_PROTECTIVE);
+        if protective {
+            Self::read_gpt(device, buf, device_blocks).await
+        } else {
+            Self::read_mbr(device, buf, device_blocks).await
+        }
+    }
+
+    /// Partitions in table order
+    pub fn partitions(&self) -> impl Iterator<Item = &Partition> {
+        self.partitions.iter().flatten()
+    }
+
+    /// Partitions with their index
+    pub fn entries(&self) -> impl Iterator<Item = (usize, &Partition)> {
+        self.partitions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, p)| Some((i, p.as_ref()?)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.partitions().count()
+    }
+
+    /// Partition at `index`
+    pub fn get(&self, index: usize) -> Option<&Partition> {
+        self.partitions.get(index)?.as_ref()
+    }
+
+    /// First partition of type `kind`
+    pub fn find(&self, kind: PartitionType) -> Option<&Partition> {
+        self.partitions().find(|p| p.kind == kind)
+    }
+
+    /// Empty MBR for a device of `device_blocks` blocks
+    pub fn new_mbr(device_blocks: u32, disk_signature: u32) -> Self {
+        Self {
+            scheme: Scheme::Mbr { disk_signature },
+            device_blocks,
+            first_usable: 1,
+            last_usable: device_blocks.saturating_sub(1),
+            partitions: [None; N],
+            extended: false,
+        }
+    }
+
+    /// Empty GPT for a device of `device_blocks` blocks, with room for [`GPT_NUM_ENTRIES`]
+    /// entries in front of and behind the usable blocks
+    pub fn new_gpt(device_blocks: u32, disk_guid: Guid) -> Result<Self, Error> {
+        let reserved = GPT_HEADER_LBA + 1 + GPT_ENTRIES_BLOCKS;
+        if device_blocks <= 2 * reserved {
+            warn!("{TAG} {device_blocks} blocks too small for a GPT");
+            Err(Error::InvalidSize)?;
+        }
+        Ok(Self {
+            scheme: Scheme::Gpt { disk_guid },
+            device_blocks,
+            first_usable: reserved,
+            last_usable: device_blocks - reserved,
+            partitions: [None; N],
+            extended: false,
+        })
+    }
+
+    /// First and last block partitions may use
+    pub fn usable_blocks(&self) -> (u32, u32) {
+        (self.first_usable, self.last_usable)
+    }
+
+    /// Slots the scheme can store
+    fn max_entries(&self) -> usize {
+        match self.scheme {
+            Scheme::Mbr { .. } => N.min(MBR_NUM_ENTRIES),
+            Scheme::Gpt { .. } => N.min(GPT_NUM_ENTRIES),
+        }
+    }
+
+    fn check_kind(&self, kind: PartitionType) -> Result<(), Error> {
+        let valid = match (self.scheme, kind) {
+            (Scheme::Mbr { .. }, PartitionType::Mbr(kind)) => {
+                kind != MBR_TYPE_EMPTY && kind != MBR_TYPE_GPT_PROTECTIVE && !is_extended(kind)
+            }
+            (Scheme::Gpt { .. }, PartitionType::Gpt(kind)) => !kind.is_zero(),
+            _ => false,
+        };
+        if !valid {
+            warn!(
+                "{TAG} partition type {kind:?} not valid in {:?}",
+                self.scheme
+            );
+            Err(Error::InvalidArg)?;
+        }
+        Ok(())
+    }
+
+    /// Whether `[start, end)` is usable and clear of all partitions but `skip`
+    fn is_free(&self, start: u32, end: u64, skip: Option<usize>) -> bool {
+        start >= self.first_usable
+            && start as u64 <= end
+            && end <= self.last_usable as u64 + 1
+            && self
+                .entries()
+                .filter(|(i, _)| Some(*i) != skip)
+                .all(|(_, p)| end <= p.start as u64 || start as u64 >= p.end())
+    }
+
+    /// Add a partition at the first free block aligned to `align` blocks, of `num_blocks` or of
+    /// all the free space there when `None`. Returns its index.
+    ///
+    /// `align` is usually [`SdmmcCard::erase_unit_sectors`], so that partitions do not share
+    /// erase units of the card.
+    ///
+    /// [`SdmmcCard::erase_unit_sectors`]: crate::sdmmc_sd::SdmmcCard::erase_unit_sectors
+    pub fn add(
+        &mut self,
+        kind: PartitionType,
+        guid: Guid,
+        num_blocks: Option<u32>,
+        align: u32,
+    ) -> Result<usize, Error> {
+        self.check_kind(kind)?;
+        let Some(index) = self.partitions[..self.max_entries()]
+            .iter()
+            .position(|p| p.is_none())
+        else {
+            warn!("{TAG} no free entry of {}", self.max_entries());
+            return Err(Error::InvalidSize);
+        };
+        let align = align.max(1) as u64;
+        let align_up = |block: u64| block.div_ceil(align) * align;
+
+        // free space starts at the first usable block or right after a partition, and reaches
+        // the next partition or the end of the usable blocks
+        let best = core::iter::once(self.first_usable as u64)
+            .chain(self.partitions().map(|p| p.end()))
+            .filter_map(|block| u32::try_from(align_up(block)).ok())
+            .filter_map(|start| {
+                let limit = self
+                    .partitions()
+                    .map(|p| p.start as u64)
+                    .filter(|&p| p >= start as u64)
+                    .fold(self.last_usable as u64 + 1, u64::min);
+                let len = match num_blocks {
+                    Some(n) => n,
+                    None => u32::try_from(limit.saturating_sub(start as u64)).unwrap_or(u32::MAX),
+                };
+                let free = len != 0 && self.is_free(start, start as u64 + len as u64, None);
+                free.then_some((start, len))
+            })
+            .min_by_key(|&(start, _)| start);
+        let Some((start, num_blocks)) = best else {
+            warn!("{TAG} no free space for {num_blocks:?} blocks aligned to {align}");
+            return Err(Error::InvalidSize);
+        };
+
+        let partition = Partition {
+            start,
+            num_blocks,
+            kind,
+            guid,
+            attributes: 0,
+        };
+        debug!("{TAG} add {index}: {partition:?}");
+        self.partitions[index] = Some(partition);
+        Ok(index)
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<Partition, Error> {
+        self.partitions
+            .get_mut(index)
+            .and_then(Option::take)
+            .ok_or(Error::NotFound)
+    }
+
+    /// Grow or shrink partition `index` in place, its start stays where it is
+    pub fn resize(&mut self, index: usize, num_blocks: u32) -> Result<(), Error> {
+        let start = self.get(index).ok_or(Error::NotFound)?.start;
+        if num_blocks == 0 || !self.is_free(start, start as u64 + num_blocks as u64, Some(index)) {
+            warn!("{TAG} partition {index} cannot hold {num_blocks} blocks");
+            Err(Error::InvalidSize)?;
+        }
+        self.partitions[index].as_mut().unwrap().num_blocks = num_blocks;
+        Ok(())
+    }
+
+    /// Change the type of partition `index`, GPT type GUID or MBR system id
+    pub fn set_kind(&mut self, index: usize, kind: PartitionType) -> Result<(), Error> {
+        self.check_kind(kind)?;
+        let partition = self.partitions.get_mut(index).and_then(Option::as_mut);
+        partition.ok_or(Error::NotFound)?.kind = kind;
+        Ok(())
+    }
+
+    /// GPT attribute bits, or [`MBR_BOOTABLE`] on MBR
+    pub fn set_attributes(&mut self, index: usize, attributes: u64) -> Result<(), Error> {
+        let partition = self.partitions.get_mut(index).and_then(Option::as_mut);
+        partition.ok_or(Error::NotFound)?.attributes = attributes;
+        Ok(())
+    }
+
+    fn push(&mut self, partition: Partition) -> Result<(), Error> {
+        debug!("{TAG} {partition:?}");
+        let Some(slot) = self.partitions.iter_mut().find(|p| p.is_none()) else {
+            warn!("{TAG} more than {N} partitions");
+            return Err(Error::InvalidSize);
+        };
+        *slot = Some(partition);
+        Ok(())
+    }
+
+    /// `(type, status, start, num_blocks)` of MBR or EBR entry `i`
+    fn mbr_entry(buf: &[u8], i: usize) -> (u8, u8, u32, u32) {
+        let entry = &buf[MBR_ENTRIES_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        (entry[4], entry[0], le_u32(entry, 8), le_u32(entry, 12))
+    }
+
+    /// Check `[start, start + num_blocks)` ends within the first `limit` blocks
+    fn check_mbr_range(start: u32, num_blocks: u32, limit: u64) -> Result<(), Error> {
+        if start as u64 + num_blocks as u64 > limit {
+            warn!("{TAG} {num_blocks} blocks at {start} beyond block {limit}");
+            Err(Error::InvalidSize)?;
+        }
+        Ok(())
+    }
+
+    async fn read_mbr<D>(
+        device: &mut D,
+        buf: &mut AlignedBlock,
+        device_blocks: u32,
+    ) -> Result<Self, Error>
+    where
+        D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>,
+    {
+        let mut table = Self::new_mbr(device_blocks, le_u32(&buf[..], MBR_DISK_SIGNATURE_OFFSET));
+        let mut extended = None;
+        for i in 0..MBR_NUM_ENTRIES {
+            match Self::mbr_entry(&buf[..], i) {
+                (MBR_TYPE_EMPTY, ..) | (_, _, _, 0) => {}
+                (kind, _, start, num_blocks) if is_extended(kind) => {
+                    Self::check_mbr_range(start, num_blocks, device_blocks as u64)?;
+                    extended = Some((start, num_blocks))
+                }
+                (kind, status, start, num_blocks) => {
+                    Self::check_mbr_range(start, num_blocks, device_blocks as u64)?;
+                    table.push(Partition {
+                        start,
+                        num_blocks,
+                        kind: PartitionType::Mbr(kind),
+                        guid: Guid::ZERO,
+                        attributes: (status & MBR_BOOTABLE) as u64,
+                    })?
+                }
+            }
+        }
+
+        let Some((ext_start, ext_blocks)) = extended else {
+            return Ok(table);
+        };
+        table.extended = true;
+        // logical partitions are relative to their EBR, the next EBR to the extended partition
+        let ext_end = ext_start as u64 + ext_blocks as u64;
+        let mut ebr = ext_start;
+        for _ in 0..MBR_MAX_LOGICAL {
+            device.read(ebr, core::slice::from_mut(buf)).await?;
+            if buf[MBR_SIGNATURE_OFFSET..] != MBR_SIGNATURE {
+                warn!("{TAG} no EBR signature at {ebr}");
+                Err(Error::InvalidResponce)?;
+            }
+            let (kind, status, start, num_blocks) = Self::mbr_entry(&buf[..], 0);
+            if kind != MBR_TYPE_EMPTY && num_blocks != 0 {
+                let start = ebr.checked_add(start).ok_or(Error::InvalidSize)?;
+                Self::check_mbr_range(start, num_blocks, ext_end)?;
+                table.push(Partition {
+                    start,
+                    num_blocks,
+                    kind: PartitionType::Mbr(kind),
+                    guid: Guid::ZERO,
+                    attributes: (status & MBR_BOOTABLE) as u64,
+                })?;
+            }
+            let (next_kind, _, next, _) = Self::mbr_entry(&buf[..], 1);
+            if !is_extended(next_kind) || next == 0 {
+                return Ok(table);
+            }
+            if next >= ext_blocks {
+                warn!("{TAG} EBR at {next} outside the extended partition");
+                Err(Error::InvalidSize)?;
+            }
+            ebr = ext_start.checked_add(next).ok_or(Error::InvalidSize)?;
+        }
+        warn!("{TAG} more than {MBR_MAX_LOGICAL} logical partitions");
+        Err(Error::InvalidSize)
+    }
+
+    async fn read_gpt<D>(
+        device: &mut D,
+        buf: &mut AlignedBlock,
+        device_blocks: u32,
+    ) -> Result<Self, Error>
+    where
+        D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>,
+    {
+        match Self::read_gpt_at(device, buf, device_blocks, GPT_HEADER_LBA).await {
+            Err(Error::InvalidCRC | Error::NotFound | Error::InvalidResponce) => {
+                let last = device_blocks.saturating_sub(1);
+                warn!("{TAG} primary GPT damaged, trying backup at {last}");
+                Self::read_gpt_at(device, buf, device_blocks, last).await
+            }
+            res => res,
+        }
+    }
+
+    async fn read_gpt_at<D>(
+        device: &mut D,
+        buf: &mut AlignedBlock,
+        device_blocks: u32,
+        lba: u32,
+    ) -> Result<Self, Error>
+    where
+        D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>,
+    {
+        device.read(lba, core::slice::from_mut(buf)).await?;
+        let header = Self::parse_gpt_header(&buf[..], lba)?;
+        let entry_size = header.entry_size as usize;
+        if header.first_usable > header.last_usable || header.last_usable >= device_blocks as u64 {
+            warn!(
+                "{TAG} GPT usable blocks [{}, {}] outside the device",
+                header.first_usable, header.last_usable
+            );
+            Err(Error::InvalidSize)?;
+        }
+        let mut table = Self {
+            scheme: Scheme::Gpt {
+                disk_guid: header.disk_guid,
+            },
+            device_blocks,
+            first_usable: header.first_usable as u32,
+            last_usable: header.last_usable as u32,
+            partitions: [None; N],
+            extended: false,
+        };
+
+        let mut crc = !0;
+        let mut remaining = header.num_entries as usize;
+        let mut block = u32::try_from(header.entries_lba).map_err(|_| Error::InvalidSize)?;
+        while remaining > 0 {
+            device.read(block, core::slice::from_mut(buf)).await?;
+            for entry in buf.chunks(entry_size).take(remaining) {
+                crc = crc32_update(crc, entry);
+                remaining -= 1;
+                let kind = Guid(entry[0..16].try_into().unwrap());
+                if kind.is_zero() {
+                    continue;
+                }
+                let (first, last) = (le_u64(entry, 32), le_u64(entry, 40));
+                if first < header.first_usable || last > header.last_usable || last < first {
+                    warn!("{TAG} GPT entry [{first}, {last}] outside the usable blocks");
+                    Err(Error::InvalidSize)?;
+                }
+                table.push(Partition {
+                    start: u32::try_from(first).map_err(|_| Error::NotSupported)?,
+                    num_blocks: u32::try_from(last - first + 1).map_err(|_| Error::NotSupported)?,
+                    kind: PartitionType::Gpt(kind),
+                    guid: Guid(entry[16..32].try_into().unwrap()),
+                    attributes: le_u64(entry, 48),
+                })?;
+            }
+            block += 1;
+        }
+        if !crc != header.entries_crc {
+            warn!("{TAG} GPT entries CRC mismatch");
+            Err(Error::InvalidCRC)?;
+        }
+        Ok(table)
+    }
+
+    fn parse_gpt_header(buf: &[u8], lba: u32) -> Result<GptHeader, Error> {
+        if buf[..8] != *GPT_SIGNATURE {
+            warn!("{TAG} no GPT header at {lba}");
+            Err(Error::NotFound)?;
+        }
+        let header_size = le_u32(buf, 12) as usize;
+        if !(GPT_HEADER_SIZE..=BLOCK_SIZE).contains(&header_size) {
+            warn!("{TAG} GPT header size {header_size}");
+            Err(Error::InvalidResponce)?;
+        }
+        let crc = crc32_update(!0, &buf[..GPT_HEADER_CRC_OFFSET]);
+        let crc = crc32_update(crc, &[0; 4]);
+        let crc = !crc32_update(crc, &buf[GPT_HEADER_CRC_OFFSET + 4..header_size]);
+        if crc != le_u32(buf, GPT_HEADER_CRC_OFFSET) {
+            warn!("{TAG} GPT header CRC mismatch at {lba}");
+            Err(Error::InvalidCRC)?;
+        }
+        let header = GptHeader {
+            my_lba: le_u64(buf, 24),
+            first_usable: le_u64(buf, 40),
+            last_usable: le_u64(buf, 48),
+            disk_guid: Guid(buf[56..72].try_into().unwrap()),
+            entries_lba: le_u64(buf, 72),
+            num_entries: le_u32(buf, 80),
+            entry_size: le_u32(buf, 84),
+            entries_crc: le_u32(buf, 88),
+        };
+        if header.my_lba != lba as u64 {
+            warn!(
+                "{TAG} GPT header at {lba} claims to be at {}",
+                header.my_lba
+            );
+            Err(Error::InvalidResponce)?;
+        }
+        let entry_size = header.entry_size as usize;
+        if entry_size < GPT_ENTRY_SIZE || BLOCK_SIZE % entry_size != 0 {
+            warn!("{TAG} GPT entry size {entry_size} not supported");
+            Err(Error::NotSupported)?;
+        }
+        Ok(header)
+    }
+}
+
+impl<const N: usize> PartitionTable<N> {
+    /// Store the table on `device`, which must be the one it was read from or created for.
+    ///
+    /// An MBR keeps the boot code of block 0. A GPT is written backup first, then the primary
+    /// table and the protective MBR last, so an interrupted write leaves one valid copy behind.
+    ///
+    /// MBRs read with an extended partition are refused with [`Error::NotSupported`], writing
+    /// their logical partitions as primary ones would drop the EBR chain.
+    pub async fn write<D>(&self, device: &mut D) -> Result<(), Error>
+    where
+        D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>,
+    {
+        if device_blocks(device).await? != self.device_blocks {
+            warn!("{TAG} table made for {} blocks", self.device_blocks);
+            Err(Error::InvalidArg)?;
+        }
+        if self.entries().any(|(i, _)| i >= self.max_entries()) {
+            warn!(
+                "{TAG} {:?} holds at most {} entries",
+                self.scheme,
+                self.max_entries()
+            );
+            Err(Error::NotSupported)?;
+        }
+        if self.extended {
+            warn!("{TAG} MBR with logical partitions not written");
+            Err(Error::NotSupported)?;
+        }
+        let buf = &mut Aligned([0; BLOCK_SIZE]);
+        match self.scheme {
+            Scheme::Mbr { disk_signature } => {
+                device.read(0, core::slice::from_mut(buf)).await?;
+                Self::mbr_block(&mut buf[..], disk_signature);
+                for (i, p) in self.entries() {
+                    let status = if p.attributes & MBR_BOOTABLE as u64 != 0 {
+                        MBR_BOOTABLE
+                    } else {
+                        0
+                    };
+                    let PartitionType::Mbr(kind) = p.kind else {
+                        return Err(Error::InvalidArg);
+                    };
+                    Self::set_mbr_entry(&mut buf[..], i, status, kind, p.start, p.num_blocks);
+                }
+                device.write(0, core::slice::from_ref(buf)).await
+            }
+            Scheme::Gpt { disk_guid } => self.write_gpt(device, buf, disk_guid).await,
+        }
+    }
+
+    async fn write_gpt<D>(
+        &self,
+        device: &mut D,
+        buf: &mut AlignedBlock,
+        disk_guid: Guid,
+    ) -> Result<(), Error>
+    where
+        D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>,
+    {
+        let last = self.device_blocks - 1;
+        let reserved = GPT_HEADER_LBA + 1 + GPT_ENTRIES_BLOCKS;
+        if self.first_usable < reserved
+            || self.last_usable > self.device_blocks.saturating_sub(reserved)
+        {
+            warn!("{TAG} no room for {GPT_NUM_ENTRIES} GPT entries around the usable blocks");
+            Err(Error::NotSupported)?;
+        }
+
+        let mut crc = !0;
+        for i in 0..GPT_ENTRIES_BLOCKS {
+            self.gpt_entries_block(i, &mut buf[..]);
+            crc = crc32_update(crc, &buf[..]);
+        }
+        let entries_crc = !crc;
+
+        let copies = [
+            (last, GPT_HEADER_LBA, last - GPT_ENTRIES_BLOCKS),
+            (GPT_HEADER_LBA, last, GPT_HEADER_LBA + 1),
+        ];
+        for (my_lba, alternate_lba, entries_lba) in copies {
+            for i in 0..GPT_ENTRIES_BLOCKS {
+                self.gpt_entries_block(i, &mut buf[..]);
+                device
+                    .write(entries_lba + i, core::slice::from_ref(buf))
+                    .await?;
+            }
+            buf.fill(0);
+            let header = &mut buf[..GPT_HEADER_SIZE];
+            header[0..8].copy_from_slice(GPT_SIGNATURE);
+            header[8..12].copy_from_slice(&GPT_REVISION_1_0.to_le_bytes());
+            header[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+            header[24..32].copy_from_slice(&(my_lba as u64).to_le_bytes());
+            header[32..40].copy_from_slice(&(alternate_lba as u64).to_le_bytes());
+            header[40..48].copy_from_slice(&(self.first_usable as u64).to_le_bytes());
+            header[48..56].copy_from_slice(&(self.last_usable as u64).to_le_bytes());
+            header[56..72].copy_from_slice(&disk_guid.0);
+            header[72..80].copy_from_slice(&(entries_lba as u64).to_le_bytes());
+            header[80..84].copy_from_slice(&(GPT_NUM_ENTRIES as u32).to_le_bytes());
+            header[84..88].copy_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+            header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+            let crc = crc32(header);
+            header[GPT_HEADER_CRC_OFFSET..GPT_HEADER_CRC_OFFSET + 4]
+                .copy_from_slice(&crc.to_le_bytes());
+            device.write(my_lba, core::slice::from_ref(buf)).await?;
+        }
+
+        buf.fill(0);
+        Self::mbr_block(&mut buf[..], 0);
+        Self::set_mbr_entry(&mut buf[..], 0, 0, MBR_TYPE_GPT_PROTECTIVE, 1, last);
+        device.write(0, core::slice::from_ref(buf)).await
+    }
+
+    /// Block `i` of the GPT entry array
+    fn gpt_entries_block(&self, i: u32, buf: &mut [u8]) {
+        buf.fill(0);
+        let first = i as usize * (BLOCK_SIZE / GPT_ENTRY_SIZE);
+        for (entry, slot) in buf.chunks_mut(GPT_ENTRY_SIZE).zip(first..) {
+            let Some(Some(p)) = self.partitions.get(slot) else {
+                continue;
+            };
+            let PartitionType::Gpt(kind) = p.kind else {
+                continue;
+            };
+            entry[0..16].copy_from_slice(&kind.0);
+            entry[16..32].copy_from_slice(&p.guid.0);
+            entry[32..40].copy_from_slice(&(p.start as u64).to_le_bytes());
+            entry[40..48].copy_from_slice(&(p.end() - 1).to_le_bytes());
+            entry[48..56].copy_from_slice(&p.attributes.to_le_bytes());
+        }
+    }
+
+    /// Clear the entries of an MBR block, set its disk signature and boot signature
+    fn mbr_block(buf: &mut [u8], disk_signature: u32) {
+        buf[MBR_DISK_SIGNATURE_OFFSET..MBR_DISK_SIGNATURE_OFFSET + 4]
+            .copy_from_slice(&disk_signature.to_le_bytes());
+        buf[MBR_DISK_SIGNATURE_OFFSET + 4..MBR_SIGNATURE_OFFSET].fill(0);
+        buf[MBR_SIGNATURE_OFFSET..].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    fn set_mbr_entry(buf: &mut [u8], i: usize, status: u8, kind: u8, start: u32, num_blocks: u32) {
+        let entry = &mut buf[MBR_ENTRIES_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        entry[0] = status;
+        entry[1..4].copy_from_slice(&MBR_CHS_LBA_ONLY);
+        entry[4] = kind;
+        entry[5..8].copy_from_slice(&MBR_CHS_LBA_ONLY);
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&num_blocks.to_le_bytes());
+    }
+}
+
+/// Block device over one partition, block 0 being its first block
+pub struct PartitionDevice<D> {
+    device: D,
+    start: u32,
+    num_blocks: u32,
+}
+
+impl<D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>> PartitionDevice<D> {
+    pub async fn new(mut device: D, partition: &Partition) -> Result<Self, Error> {
+        let device_blocks = device.size().await? / BLOCK_SIZE as u64;
+        if partition.start as u64 + partition.num_blocks as u64 > device_blocks {
+            warn!("{TAG} {partition:?} beyond the {device_blocks} device blocks");
+            Err(Error::InvalidSize)?;
+        }
+        Ok(Self {
+            device,
+            start: partition.start,
+            num_blocks: partition.num_blocks,
+        })
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    fn check_range(&self, block_address: u32, block_count: usize) -> Result<u32, Error> {
+        if block_address as u64 + block_count as u64 > self.num_blocks as u64 {
+            warn!(
+                "{TAG} {block_count} blocks at {block_address} out of range, partition has {}",
+                self.num_blocks
+            );
+            Err(Error::InvalidSize)?;
+        }
+        Ok(self.start + block_address)
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>> BlockDevice<BLOCK_SIZE>
+    for PartitionDevice<D>
+{
+    type Error = Error;
+    type Align = A4;
+
+    async fn read(
+        &mut self,
+        block_address: u32,
+        data: &mut [AlignedBlock],
+    ) -> Result<(), Self::Error> {
+        let block = self.check_range(block_address, data.len())?;
+        self.device.read(block, data).await
+    }
+
+    async fn write(
+        &mut self,
+        block_address: u32,
+        data: &[AlignedBlock],
+    ) -> Result<(), Self::Error> {
+        let block = self.check_range(block_address, data.len())?;
+        self.device.write(block, data).await
+    }
+
+    async fn size(&mut self) -> Result<u64, Self::Error> {
+        Ok(self.num_blocks as u64 * BLOCK_SIZE as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{format, vec, vec::Vec};
+
+    use embassy_futures::block_on;
+
+    use super::*;
+
+    type Table = PartitionTable<8>;
+
+    /// Block device in memory
+    struct RamDevice(Vec<AlignedBlock>);
+
+    impl RamDevice {
+        fn new(blocks: usize) -> Self {
+            Self(vec![Aligned([0; BLOCK_SIZE]); blocks])
+        }
+
+        fn range(&self, block_address: u32, len: usize) -> Result<core::ops::Range<usize>, Error> {
+            let start = block_address as usize;
+            if start + len > self.0.len() {
+                Err(Error::InvalidSize)?;
+            }
+            Ok(start..start + len)
+        }
+    }
+
+    impl BlockDevice<BLOCK_SIZE> for RamDevice {
+        type Error = Error;
+        type Align = A4;
+
+        async fn read(
+            &mut self,
+            block_address: u32,
+            data: &mut [AlignedBlock],
+        ) -> Result<(), Self::Error> {
+            let range = self.range(block_address, data.len())?;
+            data.copy_from_slice(&self.0[range]);
+            Ok(())
+        }
+
+        async fn write(
+            &mut self,
+            block_address: u32,
+            data: &[AlignedBlock],
+        ) -> Result<(), Self::Error> {
+            let range = self.range(block_address, data.len())?;
+            self.0[range].copy_from_slice(data);
+            Ok(())
+        }
+
+        async fn size(&mut self) -> Result<u64, Self::Error> {
+            Ok((self.0.len() * BLOCK_SIZE) as u64)
+        }
+    }
+
+    /// Store an MBR or EBR with `entries` of `(type, start, num_blocks)` at `block`
+    fn put_mbr(device: &mut RamDevice, block: u32, entries: &[(u8, u32, u32)]) {
+        let buf = &mut device.0[block as usize][..];
+        Table::mbr_block(buf, 0x1234_5678);
+        for (i, &(kind, start, num_blocks)) in entries.iter().enumerate() {
+            Table::set_mbr_entry(buf, i, 0, kind, start, num_blocks);
+        }
+    }
+
+    fn extents(table: &Table) -> Vec<(u32, u32)> {
+        table
+            .partitions()
+            .map(|p| (p.start, p.num_blocks))
+            .collect()
+    }
+
+    #[test]
+    fn crc32_table() {
+        assert_eq!(CRC32_TABLE[0], 0);
+        assert_eq!(CRC32_TABLE[1], 0x7707_3096);
+        assert_eq!(CRC32_TABLE[128], 0xEDB8_8320);
+        assert_eq!(CRC32_TABLE[255], 0x2D02_EF8D);
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        let crc = crc32_update(!0, b"1234");
+        assert_eq!(!crc32_update(crc, b"56789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn guid_format() {
+        assert_eq!(
+            format!("{:?}", GPT_TYPE_EFI_SYSTEM),
+            "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+        );
+        assert_eq!(
+            format!("{:?}", GPT_TYPE_BASIC_DATA),
+            "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7"
+        );
+        assert_eq!(GPT_TYPE_EFI_SYSTEM.0[..4], [0x28, 0x73, 0x2A, 0xC1]);
+        assert_eq!(
+            format!("{:?}", Guid::ZERO),
+            "00000000-0000-0000-0000-000000000000"
+        );
+    }
+
+    #[test]
+    fn partition_end_does_not_overflow() {
+        let partition = Partition {
+            start: u32::MAX,
+            num_blocks: 2,
+            kind: PartitionType::Mbr(MBR_TYPE_LINUX),
+            guid: Guid::ZERO,
+            attributes: 0,
+        };
+        assert_eq!(partition.end(), u32::MAX as u64 + 2);
+    }
+
+    #[test]
+    fn ebr_chain_walk() {
+        let mut device = RamDevice::new(64);
+        put_mbr(
+            &mut device,
+            0,
+            &[(MBR_TYPE_FAT16, 2, 6), (MBR_TYPE_EXTENDED_LBA, 16, 40)],
+        );
+        // logical partitions relative to their EBR, next EBR relative to the extended partition
+        put_mbr(
+            &mut device,
+            16,
+            &[(MBR_TYPE_LINUX, 1, 7), (MBR_TYPE_EXTENDED_CHS, 8, 12)],
+        );
+        put_mbr(&mut device, 24, &[(MBR_TYPE_FAT32_LBA, 2, 10)]);
+        let table = block_on(Table::read(&mut device)).unwrap();
+        assert_eq!(
+            table.scheme,
+            Scheme::Mbr {
+                disk_signature: 0x1234_5678
+            }
+        );
+        assert_eq!(extents(&table), [(2, 6), (17, 7), (26, 10)]);
+        assert_eq!(
+            table.get(2).unwrap().kind,
+            PartitionType::Mbr(MBR_TYPE_FAT32_LBA)
+        );
+    }
+
+    #[test]
+    fn mbr_round_trip() {
+        let mut device = RamDevice::new(64);
+        let mut table = Table::new_mbr(64, 0xCAFE_F00D);
+        let kind = PartitionType::Mbr(MBR_TYPE_FAT32_LBA);
+        assert_eq!(table.add(kind, Guid::ZERO, Some(10), 8), Ok(0));
+        let linux = PartitionType::Mbr(MBR_TYPE_LINUX);
+        assert_eq!(table.add(linux, Guid::ZERO, None, 8), Ok(1));
+        table.set_attributes(0, MBR_BOOTABLE as u64).unwrap();
+        block_on(table.write(&mut device)).unwrap();
+
+        let read = block_on(Table::read(&mut device)).unwrap();
+        assert_eq!(read.scheme, table.scheme);
+        assert_eq!(extents(&read), [(8, 10), (24, 40)]);
+        assert!(read.partitions().eq(table.partitions()));
+    }
+
+    #[test]
+    fn gpt_round_trip() {
+        let mut device = RamDevice::new(128);
+        let disk_guid = Guid::from_fields(1, 2, 3, [4; 8]);
+        let mut table = Table::new_gpt(128, disk_guid).unwrap();
+        let guid = Guid::from_fields(5, 6, 7, [8; 8]);
+        let index = table.add(PartitionType::Gpt(GPT_TYPE_EFI_SYSTEM), guid, Some(16), 8);
+        assert_eq!(index, Ok(0));
+        block_on(table.write(&mut device)).unwrap();
+
+        let read = block_on(Table::read(&mut device)).unwrap();
+        assert_eq!(read.scheme, Scheme::Gpt { disk_guid });
+        assert_eq!(read.usable_blocks(), table.usable_blocks());
+        assert_eq!(extents(&read), [(40, 16)]);
+        assert!(read.partitions().eq(table.partitions()));
+
+        // the backup table takes over from a damaged primary one
+        device.0[GPT_HEADER_LBA as usize][0] ^= 1;
+        let backup = block_on(Table::read(&mut device)).unwrap();
+        assert!(backup.partitions().eq(table.partitions()));
+    }
+
+    #[test]
+    fn extended_mbr_is_not_written() {
+        let mut device = RamDevice::new(64);
+        put_mbr(&mut device, 0, &[(MBR_TYPE_EXTENDED_LBA, 16, 40)]);
+        put_mbr(&mut device, 16, &[(MBR_TYPE_LINUX, 1, 7)]);
+        let table = block_on(Table::read(&mut device)).unwrap();
+        assert_eq!(extents(&table), [(17, 7)]);
+        let before = device.0.clone();
+        assert_eq!(block_on(table.write(&mut device)), Err(Error::NotSupported));
+        assert!(device.0.iter().zip(&before).all(|(a, b)| a[..] == b[..]));
+    }
+
+    #[test]
+    fn ebr_loop_is_bounded() {
+        let mut device = RamDevice::new(64);
+        put_mbr(&mut device, 0, &[(MBR_TYPE_EXTENDED_LBA, 16, 40)]);
+        // the second EBR links back to itself
+        put_mbr(
+            &mut device,
+            16,
+            &[(MBR_TYPE_EMPTY, 0, 0), (MBR_TYPE_EXTENDED_LBA, 8, 8)],
+        );
+        put_mbr(
+            &mut device,
+            24,
+            &[(MBR_TYPE_EMPTY, 0, 0), (MBR_TYPE_EXTENDED_LBA, 8, 8)],
+        );
+        assert_eq!(
+            block_on(Table::read(&mut device)).unwrap_err(),
+            Error::InvalidSize
+        );
+    }
+
+    #[test]
+    fn entries_past_the_device_are_rejected() {
+        let mut device = RamDevice::new(64);
+        put_mbr(&mut device, 0, &[(MBR_TYPE_FAT16, 60, 10)]);
+        assert_eq!(
+            block_on(Table::read(&mut device)).unwrap_err(),
+            Error::InvalidSize
+        );
+
+        put_mbr(&mut device, 0, &[(MBR_TYPE_FAT16, u32::MAX, 2)]);
+        assert_eq!(
+            block_on(Table::read(&mut device)).unwrap_err(),
+            Error::InvalidSize
+        );
+
+        put_mbr(&mut device, 0, &[(MBR_TYPE_EXTENDED_LBA, 16, 64)]);
+        assert_eq!(
+            block_on(Table::read(&mut device)).unwrap_err(),
+            Error::InvalidSize
+        );
+    }
+
+    #[test]
+    fn logical_partitions_stay_in_the_extended_partition() {
+        let mut device = RamDevice::new(64);
+        put_mbr(&mut device, 0, &[(MBR_TYPE_EXTENDED_LBA, 16, 16)]);
+        put_mbr(&mut device, 16, &[(MBR_TYPE_LINUX, 1, 20)]);
+        assert_eq!(
+            block_on(Table::read(&mut device)).unwrap_err(),
+            Error::InvalidSize
+        );
+
+        put_mbr(
+            &mut device,
+            16,
+            &[(MBR_TYPE_LINUX, 1, 7), (MBR_TYPE_EXTENDED_LBA, 16, 8)],
+        );
+        assert_eq!(
+            block_on(Table::read(&mut device)).unwrap_err(),
+            Error::InvalidSize
+        );
+    }
+}
@@ -0,0 +1,279 @@
+//! Sequential read-ahead.
+//!
+//! [`ReadAhead`] watches the addresses it is asked to read. Once reads follow each other, it
+//! starts a multi-block read of the sectors after the last one returned and leaves it running on
+//! the card while the caller works on the data it got. The DMA writes those sectors straight into
+//! a ring of `N` sector buffers, from which the next reads are served without another command
+//! round trip. The ring must be in DMA capable memory for that, it is filled through the DMA
+//! buffer of the card otherwise.
+//!
+//! The DMA of a prefetch outlives the read that started it, so the read-ahead owns the card and
+//! the ring and only reads once pinned, e.g. with [`core::pin::pin!`]. `Pin<&mut ReadAhead>` is
+//! the block device. Small reads coming through a
+//! [`SectorCache`](crate::sdmmc_sd::cache::SectorCache) stacked on top are detected the same way.
+
+use core::{marker::PhantomPinned, pin::Pin};
+
+use aligned::{Aligned, A4};
+use block_device_driver::BlockDevice;
+use log::{debug, warn};
+
+use crate::{
+    sdmmc_sd::{
+        block::{aligned_blocks_as_bytes, aligned_blocks_as_bytes_mut, AlignedBlock, BLOCK_SIZE},
+        SdmmcCard,
+    },
+    Error,
+};
+
+const TAG: &'static str = "[SDMMC_READAHEAD]";
+
+/// Reads in a row at consecutive addresses before prefetching starts
+const SEQUENTIAL_READS: u32 = 2;
+
+/// Card with read-ahead into a ring of `N` sectors, see the module documentation.
+///
+/// A prefetch may still be running on the card, [`Self::take_card`] waits for it. Dropping the
+/// read-ahead stops the DMA of the prefetch, and the card is stopped by its next command.
+pub struct ReadAhead<const N: usize> {
+    /// Only taken by [`Self::take_card`], with no prefetch running
+    card: Option<SdmmcCard>,
+    ring: [AlignedBlock; N],
+    /// Address of the sector in `ring[head]`
+    ring_block: u32,
+    head: usize,
+    len: usize,
+    /// Prefetch running on the card, `(start, count)`, into the ring right after `len`
+    in_flight: Option<(u32, u32)>,
+    /// Address following the last read
+    next_block: u32,
+    /// Reads in a row that started at `next_block`
+    streak: u32,
+    /// The IDMAC walks the ring and the descriptors inside the card while a prefetch runs
+    _pinned: PhantomPinned,
+}
+
+impl<const N: usize> ReadAhead<N> {
+    /// `card` must be initialized, with 512 byte sectors
+    pub fn new(card: SdmmcCard) -> Result<Self, Error> {
+        if card.sector_size() as usize != BLOCK_SIZE {
+            warn!("{TAG} sector size {} not supported", card.sector_size());
+            Err(Error::NotSupported)?;
+        }
+        Ok(Self {
+            card: Some(card),
+            ring: [Aligned([0; BLOCK_SIZE]); N],
+            ring_block: 0,
+            head: 0,
+            len: 0,
+            in_flight: None,
+            next_block: 0,
+            streak: 0,
+            _pinned: PhantomPinned,
+        })
+    }
+
+    /// The card, once a running prefetch completed. Later calls and reads fail with
+    /// [`Error::InvalidState`].
+    pub async fn take_card(self: Pin<&mut Self>) -> Result<SdmmcCard, Error> {
+        // SAFETY: the card only moves out once no DMA is running
+        let this = unsafe { self.get_unchecked_mut() };
+        if let Err(err) = this.settle().await {
+            warn!("{TAG} prefetch failed: {err:?}");
+        }
+        this.len = 0;
+        this.card.take().ok_or(Error::InvalidState)
+    }
+
+    fn card(&mut self) -> Result<&mut SdmmcCard, Error> {
+        self.card.as_mut().ok_or(Error::InvalidState)
+    }
+
+    fn ring_has(&self, block: u32) -> bool {
+        self.len != 0 && block >= self.ring_block && block - self.ring_block < self.len as u32
+    }
+
+    /// Ring slots a prefetch of `count` sectors fills, the free ones after the ring contents
+    fn free_slots(&self, count: usize) -> core::ops::Range<usize> {
+        let at = (self.head + self.len) % N;
+        at..at + count
+    }
+
+    /// Wait for the running prefetch, whose sectors then join the ring
+    async fn settle(&mut self) -> Result<(), Error> {
+        let Some((start, count)) = self.in_flight.take() else {
+            return Ok(());
+        };
+        let slots = self.free_slots(count as usize);
+        let card = self.card.as_mut().ok_or(Error::InvalidState)?;
+        let dst = aligned_blocks_as_bytes_mut(&mut self.ring[slots]);
+        card.finish_read_sectors_into(dst, start).await?;
+        if self.len == 0 {
+            self.ring_block = start;
+        }
+        self.len += count as usize;
+        Ok(())
+    }
+
+    /// Start reading the sectors after the ring into the free slots up to the end of the ring
+    async fn prefetch(&mut self) {
+        if self.len == 0 {
+            self.ring_block = self.next_block;
+            self.head = 0;
+        }
+        let start = self.ring_block + self.len as u32;
+        let at = (self.head + self.len) % N;
+        let free = if self.len == N {
+            0
+        } else if at < self.head {
+            self.head - at
+        } else {
+            N - at
+        };
+        let Some(capacity) = self.card.as_ref().map(SdmmcCard::capacity) else {
+            return;
+        };
+        let rest = capacity.saturating_sub(start) as usize;
+        let slots = self.free_slots(free.min(rest));
+        if slots.is_empty() {
+            return;
+        }
+        let Some(card) = self.card.as_mut() else {
+            return;
+        };
+        let dst = aligned_blocks_as_bytes_mut(&mut self.ring[slots]);
+        let count = match card.chunk_blocks(dst, card.dma_rx_buf.capacity()) {
+            Ok(count) => count,
+            Err(err) => {
+                warn!("{TAG} no room to prefetch: {err:?}");
+                return;
+            }
+        };
+        let dst = &mut dst[..count as usize * BLOCK_SIZE];
+        debug!("{TAG} prefetch {count} sectors at {start}");
+        // SAFETY: the read-ahead is pinned, so the ring and the card stay in place until `settle`
+        // finishes the read or `drop` abandons it
+        match unsafe { card.start_read_sectors_into(dst, start) }.await {
+            Ok(()) => self.in_flight = Some((start, count)),
+            Err(err) => warn!("{TAG} prefetch of {count} sectors at {start} failed: {err:?}"),
+        }
+    }
+}
+
+impl<const N: usize> Drop for ReadAhead<N> {
+    fn drop(&mut self) {
+        // the DMA must not write to the ring once it is gone. Also covers a read cancelled in
+        // `settle`, which already took `in_flight`.
+        if let Some(card) = self.card.as_mut() {
+            card.abandon_read();
+        }
+    }
+}
+
+impl<const N: usize> ReadAhead<N> {
+    async fn read_blocks(
+        &mut self,
+        block_address: u32,
+        data: &mut [AlignedBlock],
+    ) -> Result<(), Error> {
+        if block_address.checked_add(data.len() as u32).is_none() {
+            warn!(
+                "{TAG} {} blocks at {block_address} beyond the block address range",
+                data.len()
+            );
+            Err(Error::InvalidSize)?;
+        }
+        self.streak = if block_address == self.next_block {
+            self.streak.saturating_add(1)
+        } else {
+            0
+        };
+        let mut i = 0;
+        while i < data.len() {
+            let block = block_address + i as u32;
+            if self.ring_has(block) {
+                let skip = (block - self.ring_block) as usize;
+                data[i].copy_from_slice(&self.ring[(self.head + skip) % N][..]);
+                self.head = (self.head + skip + 1) % N;
+                self.len -= skip + 1;
+                self.ring_block = block + 1;
+                i += 1;
+                continue;
+            }
+            let prefetched = matches!(self.in_flight, Some((start, count))
+                if block >= start && block - start < count);
+            // the card takes no other command before the prefetch is collected
+            let settled = self.settle().await;
+            if prefetched && settled.is_ok() {
+                continue;
+            }
+            if let Err(err) = settled {
+                warn!("{TAG} prefetch failed: {err:?}");
+            }
+            self.len = 0;
+            self.card()?
+                .read_sectors_split(aligned_blocks_as_bytes_mut(&mut data[i..]), block)
+                .await?;
+            break;
+        }
+        self.next_block = block_address + data.len() as u32;
+
+        if self.streak >= SEQUENTIAL_READS && self.in_flight.is_none() {
+            self.prefetch().await;
+        }
+        Ok(())
+    }
+
+    async fn write_blocks(
+        &mut self,
+        block_address: u32,
+        data: &[AlignedBlock],
+    ) -> Result<(), Error> {
+        if let Err(err) = self.settle().await {
+            warn!("{TAG} prefetch failed: {err:?}");
+            self.len = 0;
+        }
+        let end = block_address as u64 + data.len() as u64;
+        if self.len != 0
+            && (block_address as u64) < self.ring_block as u64 + self.len as u64
+            && end > self.ring_block as u64
+        {
+            // the prefetched copies are stale now
+            self.len = 0;
+        }
+        self.card()?
+            .write_sectors_split(aligned_blocks_as_bytes(data), block_address)
+            .await
+    }
+}
+
+impl<const N: usize> BlockDevice<BLOCK_SIZE> for Pin<&mut ReadAhead<N>> {
+    type Error = Error;
+    type Align = A4;
+
+    async fn read(
+        &mut self,
+        block_address: u32,
+        data: &mut [AlignedBlock],
+    ) -> Result<(), Self::Error> {
+        // SAFETY: nothing moves out of the read-ahead
+        let this = unsafe { self.as_mut().get_unchecked_mut() };
+        this.read_blocks(block_address, data).await
+    }
+
+    async fn write(
+        &mut self,
+        block_address: u32,
+        data: &[AlignedBlock],
+    ) -> Result<(), Self::Error> {
+        // SAFETY: nothing moves out of the read-ahead
+        let this = unsafe { self.as_mut().get_unchecked_mut() };
+        this.write_blocks(block_address, data).await
+    }
+
+    async fn size(&mut self) -> Result<u64, Self::Error> {
+        // SAFETY: nothing moves out of the read-ahead
+        let this = unsafe { self.as_mut().get_unchecked_mut() };
+        this.card()?.size().await
+    }
+}
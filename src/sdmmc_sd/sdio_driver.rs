@@ -0,0 +1,299 @@
+//! SDIO function drivers.
+//!
+//! A [`SdioDispatcher`] borrows an initialized SDIO or combo card, binds each IO function to
+//! the first driver whose [`SdioFunctionDriver::probe`] accepts it and routes card interrupts to
+//! the driver of the function that raised them. Cards mixing several kinds of functions are served
+//! by an enum of drivers implementing the trait by delegation.
+
+use core::future::Future;
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use log::{debug, info, warn};
+
+use crate::{
+    sdmmc_sd::{
+        block::SharedCard,
+        io::{IoAddressMode, SdioFuncInfo, SDIO_MAX_FUNCTIONS},
+        SdmmcCard,
+    },
+    wait_for_sdio_interrupt, Error,
+};
+
+const TAG: &'static str = "[SDIO_DRIVER]";
+
+/// What a driver gets to match on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SdioFunctionId {
+    /// Function number, 1 to 7
+    pub func: u8,
+    /// MANFID of the function CIS, or of the common CIS if the function has none
+    pub vendor: u16,
+    pub device: u16,
+    /// Standard SDIO function interface code
+    pub class: u8,
+    /// Extended interface code, meaningful when `class` is `FBR_INTERFACE_CODE_EXT`
+    pub ext_class: u8,
+}
+
+/// Driver for one kind of SDIO function
+pub trait SdioFunctionDriver {
+    /// Whether this driver handles the function
+    fn probe(&self, id: &SdioFunctionId) -> bool;
+
+    /// Bring the function up, typically enabling it and setting its block size.
+    /// The function interrupt is enabled by the dispatcher afterwards.
+    fn init(&mut self, func: &mut SdioFunction<'_>) -> impl Future<Output = Result<(), Error>>;
+
+    /// Service the function interrupt, the card keeps it asserted until the source is cleared
+    fn handle_interrupt(
+        &mut self,
+        func: &mut SdioFunction<'_>,
+    ) -> impl Future<Output = Result<(), Error>>;
+
+    /// Release the function, its interrupt is already disabled
+    fn teardown(&mut self, func: &mut SdioFunction<'_>) -> impl Future<Output = Result<(), Error>>;
+}
+
+/// Access to one IO function of the card, handed to its driver
+pub struct SdioFunction<'a> {
+    card: &'a mut SdmmcCard,
+    num: u8,
+}
+
+impl<'a> SdioFunction<'a> {
+    /// Function `num` of an initialized SDIO or combo card
+    pub fn new(card: &'a mut SdmmcCard, num: u8) -> Result<Self, Error> {
+        if card
+            .sdio_info()
+            .and_then(|info| info.function(num))
+            .is_none()
+        {
+            Err(Error::InvalidArg)?;
+        }
+        Ok(Self { card, num })
+    }
+
+    pub fn num(&self) -> u8 {
+        self.num
+    }
+
+    pub fn info(&self) -> &SdioFuncInfo {
+        &self.card.sdio.functions[self.num as usize - 1]
+    }
+
+    /// Escape hatch for function 0 registers and vendor specific sequences
+    pub fn card(&mut self) -> &mut SdmmcCard {
+        self.card
+    }
+
+    pub async fn enable(&mut self, en: bool) -> Result<(), Error> {
+        self.card.io_enable_func(self.num, en).await
+    }
+
+    pub async fn set_block_size(&mut self, size: u16) -> Result<(), Error> {
+        self.card.io_set_block_size(self.num, size).await
+    }
+
+    pub async fn read_byte(&mut self, addr: u32) -> Result<u8, Error> {
+        self.card.io_read_byte(self.num, addr).await
+    }
+
+    pub async fn write_byte(&mut self, addr: u32, byte: u8) -> Result<(), Error> {
+        self.card.io_write_byte(self.num, addr, byte).await
+    }
+
+    pub async fn write_read_byte(&mut self, addr: u32, byte: u8) -> Result<u8, Error> {
+        self.card.io_write_read_byte(self.num, addr, byte).await
+    }
+
+    pub async fn read_bytes(
+        &mut self,
+        addr: u32,
+        dst: &mut [u8],
+        mode: IoAddressMode,
+    ) -> Result<(), Error> {
+        self.card.io_read_bytes(self.num, addr, dst, mode).await
+    }
+
+    pub async fn write_bytes(
+        &mut self,
+        addr: u32,
+        src: &[u8],
+        mode: IoAddressMode,
+    ) -> Result<(), Error> {
+        self.card.io_write_bytes(self.num, addr, src, mode).await
+    }
+
+    pub async fn read_blocks(
+        &mut self,
+        addr: u32,
+        dst: &mut [u8],
+        mode: IoAddressMode,
+    ) -> Result<(), Error> {
+        self.card.io_read_blocks(self.num, addr, dst, mode).await
+    }
+
+    pub async fn write_blocks(
+        &mut self,
+        addr: u32,
+        src: &[u8],
+        mode: IoAddressMode,
+    ) -> Result<(), Error> {
+        self.card.io_write_blocks(self.num, addr, src, mode).await
+    }
+}
+
+/// Binds SDIO functions to drivers and dispatches their interrupts.
+///
+/// The card stays shared: every driver call runs under its lock, so block device users of a
+/// combo card keep access between interrupts.
+pub struct SdioDispatcher<'a, M: RawMutex, D: SdioFunctionDriver, const N: usize> {
+    card: &'a SharedCard<M>,
+    drivers: [D; N],
+    /// Driver index bound to each function
+    bound: [Option<usize>; SDIO_MAX_FUNCTIONS],
+}
+
+impl<'a, M: RawMutex, D: SdioFunctionDriver, const N: usize> SdioDispatcher<'a, M, D, N> {
+    /// The card behind `card` must be initialized, see [`SdmmcCard::init`]. For a combo card pass
+    /// [`ComboCard::shared`](crate::sdmmc_sd::combo::ComboCard::shared).
+    pub fn new(card: &'a SharedCard<M>, drivers: [D; N]) -> Self {
+        Self {
+            card,
+            drivers,
+            bound: [None; SDIO_MAX_FUNCTIONS],
+        }
+    }
+
+    /// Tear down all drivers first to leave the functions disabled, see [`Self::teardown`]
+    pub fn into_inner(self) -> [D; N] {
+        self.drivers
+    }
+
+    pub async fn id(&self, func: u8) -> Option<SdioFunctionId> {
+        function_id(&*self.card.lock().await, func)
+    }
+
+    /// Driver bound to `func`
+    pub fn driver(&mut self, func: u8) -> Option<&mut D> {
+        let index = (*self.bound.get(func.checked_sub(1)? as usize)?)?;
+        self.drivers.get_mut(index)
+    }
+
+    /// Enumerate the functions and bring up a driver for each one a driver accepts.
+    /// Each driver serves at most one function. Returns the number of bound functions.
+    pub async fn probe(&mut self) -> Result<usize, Error> {
+        let card = &mut *self.card.lock().await;
+        let Some(info) = card.sdio_info() else {
+            warn!("{TAG} not an SDIO card");
+            return Err(Error::NotSupported);
+        };
+        let num_functions = info.num_functions;
+
+        for func in 1..=num_functions {
+            if self.bound[func as usize - 1].is_some() {
+                continue;
+            }
+            let Some(id) = function_id(card, func) else {
+                continue;
+            };
+            let Some(index) =
+                (0..N).find(|&i| !self.bound.contains(&Some(i)) && self.drivers[i].probe(&id))
+            else {
+                debug!("{TAG} no driver for {id:?}");
+                continue;
+            };
+
+            let function = &mut SdioFunction {
+                card: &mut *card,
+                num: func,
+            };
+            if let Err(err) = self.drivers[index].init(function).await {
+                warn!("{TAG} function {func}: driver init returned {err:?}");
+                continue;
+            }
+            card.io_enable_func_int(func, true).await?;
+            self.bound[func as usize - 1] = Some(index);
+            info!("{TAG} function {func} bound to driver {index}, {id:?}");
+        }
+        Ok(self.bound.iter().flatten().count())
+    }
+
+    /// Wait for a card interrupt and run the handlers of every function with one pending
+    pub async fn service_interrupt(&mut self) -> Result<(), Error> {
+        let slot = self.card.lock().await.slot();
+        wait_for_sdio_interrupt(slot).await?;
+        let card = &mut *self.card.lock().await;
+        let pending = card.io_int_pending().await?;
+        for func in 1..=SDIO_MAX_FUNCTIONS as u8 {
+            if pending & (1 << func) == 0 {
+                continue;
+            }
+            match self.bound[func as usize - 1] {
+                Some(index) => {
+                    let function = &mut SdioFunction {
+                        card: &mut *card,
+                        num: func,
+                    };
+                    self.drivers[index]
+                        .handle_interrupt(function)
+                        .await
+                        .inspect_err(|err| {
+                            warn!("{TAG} function {func}: interrupt handler returned {err:?}")
+                        })?;
+                }
+                None => {
+                    warn!("{TAG} interrupt from unbound function {func}, disabling it");
+                    card.io_enable_func_int(func, false).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Dispatch interrupts until a handler fails
+    pub async fn run(&mut self) -> Error {
+        loop {
+            if let Err(err) = self.service_interrupt().await {
+                return err;
+            }
+        }
+    }
+
+    /// Disable the interrupt of every bound function and tear its driver down
+    pub async fn teardown(&mut self) -> Result<(), Error> {
+        let card = &mut *self.card.lock().await;
+        for func in 1..=SDIO_MAX_FUNCTIONS as u8 {
+            let Some(index) = self.bound[func as usize - 1].take() else {
+                continue;
+            };
+            card.io_enable_func_int(func, false).await?;
+            let function = &mut SdioFunction {
+                card: &mut *card,
+                num: func,
+            };
+            self.drivers[index]
+                .teardown(function)
+                .await
+                .inspect_err(|err| warn!("{TAG} function {func}: teardown returned {err:?}"))?;
+        }
+        Ok(())
+    }
+}
+
+fn function_id(card: &SdmmcCard, func: u8) -> Option<SdioFunctionId> {
+    let info = card.sdio_info()?;
+    let fbr = info.function(func)?;
+    let (vendor, device) = if fbr.cis.manf_id != 0 {
+        (fbr.cis.manf_id, fbr.cis.card_id)
+    } else {
+        info.ids()
+    };
+    Some(SdioFunctionId {
+        func,
+        vendor,
+        device,
+        class: fbr.interface_code,
+        ext_class: fbr.ext_interface_code,
+    })
+}
@@ -0,0 +1,216 @@
+//! Byte addressable storage over a block device.
+//!
+//! [`ByteStorage`] implements the blocking [`embedded_storage`] traits and the async
+//! [`embedded_storage_async::nor_flash`] traits for any block device of this crate. Accesses may
+//! start and end anywhere, partial sectors go through a read-modify-write of one sector buffer.
+//! The traits address the storage with `u32` offsets, so only the first 4GB are reachable.
+
+use aligned::{Aligned, A4};
+use block_device_driver::BlockDevice;
+use embedded_storage::{
+    nor_flash::{ErrorType, NorFlashError, NorFlashErrorKind},
+    ReadStorage, Storage,
+};
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+use log::warn;
+
+use crate::{
+    sdmmc_sd::block::{
+        bytes_as_aligned_blocks, bytes_as_aligned_blocks_mut, AlignedBlock, BLOCK_SIZE,
+    },
+    Error,
+};
+
+const TAG: &'static str = "[SDMMC_STORAGE]";
+
+/// Value of erased bytes, as NOR flash users expect
+const ERASED: u8 = 0xFF;
+
+/// Erased sectors written by one command of [`ByteStorage::erase_bytes`]
+const ERASE_RUN: usize = 8;
+
+static ERASED_SECTORS: [AlignedBlock; ERASE_RUN] = [Aligned([ERASED; BLOCK_SIZE]); ERASE_RUN];
+
+impl NorFlashError for Error {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Error::InvalidArg => NorFlashErrorKind::NotAligned,
+            Error::InvalidSize => NorFlashErrorKind::OutOfBounds,
+            _ => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// Byte addressable view of a block device, see the module documentation
+pub struct ByteStorage<D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>> {
+    device: D,
+    capacity: u64,
+    buf: AlignedBlock,
+}
+
+impl<D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>> ByteStorage<D> {
+    pub async fn new(mut device: D) -> Result<Self, Error> {
+        let capacity = device.size().await?;
+        Ok(Self {
+            device,
+            capacity,
+            buf: Aligned([0; BLOCK_SIZE]),
+        })
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    fn check_range(&self, offset: u32, len: usize) -> Result<(), Error> {
+        if offset as u64 + len as u64 > self.capacity {
+            warn!(
+                "{TAG} {len} bytes at {offset} out of range, capacity {}",
+                self.capacity
+            );
+            Err(Error::InvalidSize)?;
+        }
+        Ok(())
+    }
+
+    /// Read `bytes.len()` bytes starting at byte `offset`
+    pub async fn read_bytes(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+        self.check_range(offset, bytes.len())?;
+        let mut block = offset / BLOCK_SIZE as u32;
+        let mut start = offset as usize % BLOCK_SIZE;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let whole = if start == 0 {
+                bytes.len() / BLOCK_SIZE * BLOCK_SIZE
+            } else {
+                0
+            };
+            // whole sectors go straight into the caller buffer when its alignment allows
+            if let Some(blocks) =
+                bytes_as_aligned_blocks_mut(&mut bytes[..whole]).filter(|b| !b.is_empty())
+            {
+                self.device.read(block, blocks).await?;
+                block += blocks.len() as u32;
+                bytes = &mut bytes[whole..];
+                continue;
+            }
+            let len = (BLOCK_SIZE - start).min(bytes.len());
+            self.device
+                .read(block, core::slice::from_mut(&mut self.buf))
+                .await?;
+            bytes[..len].copy_from_slice(&self.buf[start..start + len]);
+            bytes = &mut bytes[len..];
+            block += 1;
+            start = 0;
+        }
+        Ok(())
+    }
+
+    /// Write `bytes` starting at byte `offset`, preserving the rest of partially written sectors
+    pub async fn write_bytes(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        self.check_range(offset, bytes.len())?;
+        let mut block = offset / BLOCK_SIZE as u32;
+        let mut start = offset as usize % BLOCK_SIZE;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let whole = if start == 0 {
+                bytes.len() / BLOCK_SIZE * BLOCK_SIZE
+            } else {
+                0
+            };
+            if let Some(blocks) = bytes_as_aligned_blocks(&bytes[..whole]).filter(|b| !b.is_empty())
+            {
+                self.device.write(block, blocks).await?;
+                block += blocks.len() as u32;
+                bytes = &bytes[whole..];
+                continue;
+            }
+            let len = (BLOCK_SIZE - start).min(bytes.len());
+            if len < BLOCK_SIZE {
+                self.device
+                    .read(block, core::slice::from_mut(&mut self.buf))
+                    .await?;
+            }
+            self.buf[start..start + len].copy_from_slice(&bytes[..len]);
+            self.device
+                .write(block, core::slice::from_ref(&self.buf))
+                .await?;
+            bytes = &bytes[len..];
+            block += 1;
+            start = 0;
+        }
+        Ok(())
+    }
+
+    /// Fill the sectors in `[from, to)` with the erased value, both ends sector aligned.
+    ///
+    /// The sectors are written [`ERASE_RUN`] at a time with multi-sector commands.
+    pub async fn erase_bytes(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        if from > to || from as usize % BLOCK_SIZE != 0 || to as usize % BLOCK_SIZE != 0 {
+            warn!("{TAG} erase of [{from}, {to}) not sector aligned");
+            Err(Error::InvalidArg)?;
+        }
+        self.check_range(from, (to - from) as usize)?;
+        let mut block = from / BLOCK_SIZE as u32;
+        let end = to / BLOCK_SIZE as u32;
+        while block < end {
+            let count = ((end - block) as usize).min(ERASE_RUN);
+            self.device.write(block, &ERASED_SECTORS[..count]).await?;
+            block += count as u32;
+        }
+        Ok(())
+    }
+
+    /// Capacity in bytes, limited to what `u32` offsets reach
+    pub fn capacity(&self) -> usize {
+        self.capacity.min(u32::MAX as u64) as usize
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>> ReadStorage for ByteStorage<D> {
+    type Error = Error;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        embassy_futures::block_on(self.read_bytes(offset, bytes))
+    }
+
+    fn capacity(&self) -> usize {
+        ByteStorage::capacity(self)
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>> Storage for ByteStorage<D> {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        embassy_futures::block_on(self.write_bytes(offset, bytes))
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>> ErrorType for ByteStorage<D> {
+    type Error = Error;
+}
+
+impl<D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>> ReadNorFlash for ByteStorage<D> {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.read_bytes(offset, bytes).await
+    }
+
+    fn capacity(&self) -> usize {
+        ByteStorage::capacity(self)
+    }
+}
+
+/// Writes do not need a prior erase, erasing only sets the erased value expected by NOR flash users
+impl<D: BlockDevice<BLOCK_SIZE, Error = Error, Align = A4>> NorFlash for ByteStorage<D> {
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = BLOCK_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.erase_bytes(from, to).await
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.write_bytes(offset, bytes).await
+    }
+}